@@ -0,0 +1,193 @@
+use crate::object::Object;
+use std::io::{self, Error, ErrorKind};
+extern crate file_utils;
+use file_utils::read::Read;
+use file_utils::write::Write;
+
+// Static libraries, written by blar. An archive is a list of objects along
+// with an index of the global symbols each of them defines, so a program
+// only pulls in the members it refers to. The layout is
+//
+// u32 magic, u16 version
+// u32 index count, (name, u32 member) for every global symbol
+// u32 member count, (name, u64 size, object) for every member
+pub const MAGIC: u32 = 0xAFE;
+pub const VERSION: u16 = 1;
+
+pub struct Archive {
+  pub name: String,
+  pub index: Vec<(String, usize)>,
+  pub members: Vec<Object>
+}
+
+fn write_str<W: io::Write>(writer: &mut W, s: &str) -> io::Result<()> {
+  writer.write_u32(s.len() as u32)?;
+  writer.write_all(s.as_bytes())
+}
+
+// Like in objects the length is not trusted, only what is there is read
+fn read_bytes<R: io::Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+  let mut buf: Vec<u8> = Vec::new();
+  let mut limited = io::Read::take(io::Read::by_ref(reader), len as u64);
+  io::Read::read_to_end(&mut limited, &mut buf)?;
+  if buf.len() != len {
+    return Err(malformed());
+  }
+  Ok(buf)
+}
+
+fn read_str<R: io::Read>(reader: &mut R) -> io::Result<String> {
+  let len = reader.read_u32()? as usize;
+  String::from_utf8(read_bytes(reader, len)?).map_err(|_| malformed())
+}
+
+fn malformed() -> Error {
+  Error::new(ErrorKind::InvalidData, "Malformed archive")
+}
+
+impl Archive {
+  // Builds the index, a global defined by two members is an error
+  pub fn new(name: &str, members: Vec<Object>) -> Result<Self, Vec<String>> {
+    let mut index: Vec<(String, usize)> = Vec::new();
+    let mut errors: Vec<String> = Vec::new();
+    for (id, obj) in members.iter().enumerate() {
+      for sym in obj.symbols.iter().filter(|s| s.global) {
+        match index.iter().find(|i| i.0 == sym.name) {
+          Some(other) => errors.push(format!("Symbol {} is defined in both {} and {}", sym.name, members[other.1].name, obj.name)),
+          None => index.push((sym.name.clone(), id))
+        }
+      }
+    }
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+    Ok(Self {
+      name: name.to_owned(),
+      index,
+      members
+    })
+  }
+
+  pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    writer.write_u32(MAGIC)?;
+    writer.write_u16(VERSION)?;
+    writer.write_u32(self.index.len() as u32)?;
+    for (name, member) in &self.index {
+      write_str(writer, name)?;
+      writer.write_u32(*member as u32)?;
+    }
+    writer.write_u32(self.members.len() as u32)?;
+    for obj in &self.members {
+      let mut buf: Vec<u8> = Vec::new();
+      obj.write(&mut buf)?;
+      write_str(writer, &obj.name)?;
+      writer.write_u64(buf.len() as u64)?;
+      writer.write_all(&buf)?;
+    }
+    Ok(())
+  }
+
+  pub fn read<R: io::Read>(name: &str, reader: &mut R) -> io::Result<Self> {
+    if reader.read_u32()? != MAGIC {
+      return Err(Error::new(ErrorKind::InvalidData, "Not an archive"));
+    }
+    if reader.read_u16()? != VERSION {
+      return Err(Error::new(ErrorKind::InvalidData, "Unsupported archive version"));
+    }
+    let mut index: Vec<(String, usize)> = Vec::new();
+    for _ in 0..reader.read_u32()? {
+      index.push((read_str(reader)?, reader.read_u32()? as usize));
+    }
+    let mut members: Vec<Object> = Vec::new();
+    for _ in 0..reader.read_u32()? {
+      let member = read_str(reader)?;
+      let len = reader.read_u64()? as usize;
+      let buf = read_bytes(reader, len)?;
+      // Messages name the member as lib.a(print.o)
+      members.push(Object::read(&format!("{name}({member})"), &mut buf.as_slice())?);
+    }
+    if index.iter().any(|i| i.1 >= members.len()) {
+      return Err(malformed());
+    }
+    Ok(Self {
+      name: name.to_owned(),
+      index,
+      members
+    })
+  }
+}
+
+// Symbols the objects refer to which none of them defines, the entry
+// point included
+pub fn undefined(objects: &[Object], entry: &str) -> Vec<String> {
+  let defined = |obj: &Object, name: &str| obj.symbols.iter().any(|s| s.name == name);
+  let global = |name: &str| objects.iter().any(|o| o.symbols.iter().any(|s| s.global && s.name == name));
+  let mut names: Vec<String> = Vec::new();
+  if !global(entry) && !objects.iter().any(|o| defined(o, entry)) {
+    names.push(entry.to_owned());
+  }
+  for obj in objects {
+    let mut refs: Vec<&str> = Vec::new();
+    for reloc in &obj.relocs {
+      reloc.expr.symbols(&mut refs);
+    }
+    for name in refs {
+      if !defined(obj, name) && !global(name) && !names.iter().any(|n| n == name) {
+        names.push(name.to_owned());
+      }
+    }
+  }
+  names
+}
+
+// Adds the archive members which define a symbol the objects need, and the
+// members those need in turn. Archives are searched in the order given
+pub fn select(objects: &mut Vec<Object>, archives: &[Archive], entry: &str) {
+  let mut taken: Vec<(usize, usize)> = Vec::new();
+  loop {
+    let mut added = false;
+    for name in undefined(objects, entry) {
+      let found = archives.iter().enumerate()
+        .find_map(|(id, ar)| ar.index.iter().find(|i| i.0 == name).map(|i| (id, i.1)));
+      if let Some((ar, member)) = found {
+        if !taken.contains(&(ar, member)) {
+          taken.push((ar, member));
+          objects.push(archives[ar].members[member].clone());
+          added = true;
+        }
+      }
+    }
+    if !added {
+      break;
+    }
+  }
+}
+
+// Everything the members taken for names refer to, following the members
+// those need in turn like select. What a library calls back into, such as
+// a firmware member calling main, has to be kept by the program
+pub fn referenced(archives: &[Archive], names: Vec<String>) -> Vec<String> {
+  let mut taken: Vec<(usize, usize)> = Vec::new();
+  let mut refs: Vec<String> = Vec::new();
+  let mut pending = names;
+  while let Some(name) = pending.pop() {
+    let found = archives.iter().enumerate()
+      .find_map(|(id, ar)| ar.index.iter().find(|i| i.0 == name).map(|i| (id, i.1)));
+    let (ar, member) = match found {
+      Some(s) if !taken.contains(&s) => s,
+      _ => continue
+    };
+    taken.push((ar, member));
+    let mut names: Vec<&str> = Vec::new();
+    for reloc in &archives[ar].members[member].relocs {
+      reloc.expr.symbols(&mut names);
+    }
+    for name in names {
+      if !refs.iter().any(|r| r == name) {
+        refs.push(name.to_owned());
+        pending.push(name.to_owned());
+      }
+    }
+  }
+  refs
+}
@@ -0,0 +1,119 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use blitz::archive::Archive;
+use blitz::object::{Kind, Object};
+
+// Exit codes, the same as blc
+const EXIT_ERRORS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
+
+const HELP: &str = "Usage: blar [options] -o <archive> <object>...
+       blar -t <archive>
+
+Packs object files written by blc -c into a library for blc -l and bld
+
+Options:
+  -o <path>          Write the archive to path
+  -t <archive>       List the members of archive and the symbols they define
+  -h, --help         Print this message
+  --version          Print the version of blar
+
+Exit status is 0 on success, 1 if two members define the same symbol, 2
+for bad options and 3 if a file could not be read or written";
+
+fn usage(msg: &str) -> ! {
+  eprintln!("blar: {msg}");
+  eprintln!("Try 'blar --help' for more information");
+  process::exit(EXIT_USAGE);
+}
+
+fn io_error(msg: String) -> ! {
+  eprintln!("blar: {msg}");
+  process::exit(EXIT_IO);
+}
+
+fn list(path: &str) {
+  let bytes = fs::read(path).unwrap_or_else(|e| io_error(format!("Failed to open {path} : {e}")));
+  let archive = Archive::read(path, &mut bytes.as_slice())
+    .unwrap_or_else(|e| io_error(format!("Failed to read {path} : {e}")));
+  for (id, obj) in archive.members.iter().enumerate() {
+    println!("{}:", obj.name);
+    for (name, _) in archive.index.iter().filter(|i| i.1 == id) {
+      let kind = match obj.symbols.iter().find(|s| &s.name == name).map(|s| s.kind) {
+        Some(Kind::FUNC) => "func",
+        _ => "data"
+      };
+      println!("  {kind:5}  {name}");
+    }
+  }
+}
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let mut inputs: Vec<&str> = Vec::new();
+  let mut output: Option<&str> = None;
+  let mut index = 0;
+  while index < args.len() {
+    let arg = args[index].as_str();
+    index += 1;
+    let mut value = || {
+      index += 1;
+      match args.get(index - 1) {
+        Some(s) => s.as_str(),
+        None => usage(&format!("{arg} needs a value"))
+      }
+    };
+    match arg {
+      "-h" | "--help" => {
+        println!("{HELP}");
+        return;
+      }
+      "--version" => {
+        println!("blar {}", env!("CARGO_PKG_VERSION"));
+        return;
+      }
+      "-o" => output = Some(value()),
+      "-t" => {
+        list(value());
+        return;
+      }
+      _ if arg.starts_with('-') && arg.len() > 1 => usage(&format!("Unknown option '{arg}'")),
+      _ => inputs.push(arg)
+    }
+  }
+  let output = match output {
+    Some(s) => s,
+    None => usage("Archive name needed, give it with -o")
+  };
+  if inputs.is_empty() {
+    usage("Input file name needed");
+  }
+  let mut members: Vec<Object> = Vec::new();
+  for input in &inputs {
+    let bytes = fs::read(input).unwrap_or_else(|e| io_error(format!("Failed to open {input} : {e}")));
+    let mut obj = Object::read(input, &mut bytes.as_slice())
+      .unwrap_or_else(|e| io_error(format!("Failed to read {input} : {e}")));
+    // Members are known by their file name, wherever they were built
+    obj.name = Path::new(input).file_name().unwrap().to_string_lossy().into_owned();
+    members.push(obj);
+  }
+  let archive = match Archive::new(output, members) {
+    Ok(s) => s,
+    Err(e) => {
+      for msg in &e {
+        eprintln!("blar: {msg}");
+      }
+      eprintln!("blar: {} error(s) generated", e.len());
+      process::exit(EXIT_ERRORS);
+    }
+  };
+  let mut bytes: Vec<u8> = Vec::new();
+  // Writing to memory cannot fail
+  archive.write(&mut bytes).unwrap();
+  if let Err(e) = fs::write(output, &bytes) {
+    io_error(format!("Failed to write {output} : {e}"));
+  }
+}
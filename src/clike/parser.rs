@@ -17,7 +17,7 @@ pub fn parse(mut lexer: Lexer) {
         match token.2 {
             Ttype::INT => {
                 let ident = lexer.lex();
-                if let Ttype::IDENT(s) = ident.2 {
+                if let Ttype::IDENT(..) = ident.2 {
                     lexer.expect(Ttype::OPAR);
                     lexer.expect(Ttype::CPAR);
                     prog.push(parse_func(token, ident, &mut lexer));
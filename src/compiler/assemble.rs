@@ -0,0 +1,178 @@
+use std::path::PathBuf;
+use crate::archive::{self, Archive};
+use crate::compiler::diag::{Diagnostic, Level};
+use crate::compiler::file::Sources;
+use crate::compiler::gc::{self, Removed};
+use crate::compiler::lint::{self, Config};
+use crate::compiler::parser::Define;
+use crate::compiler::peephole::{self, Report};
+use crate::compiler::r#proc::{Lexeme, Lexer, Token, Unit};
+use crate::compiler::sema::{self, SemUnit};
+use crate::compiler::codegen;
+use crate::link::{self, Image};
+
+// Assembling a program from Rust, e.g
+//
+// let mut sources = Sources::in_memory(vec![
+//   ("main.su".to_owned(), "include \"lib.su\"\n...".to_owned()),
+//   ("lib.su".to_owned(), "...".to_owned())
+// ]);
+// let mut options = Options::new(&["main.su"]);
+// options.firmware = None;
+// let exe = assemble(&mut sources, &options)?;
+//
+// Diagnostics refer to the files in sources and are rendered with it
+
+// What blc does is set by its options, these are the same
+pub struct Options {
+  // Assembled as if each one included the next
+  pub inputs: Vec<String>,
+  pub defines: Vec<Define>,
+  // Searched for included files after the including file's directory
+  pub search: Vec<PathBuf>,
+  // Included after the inputs. Objects are usually made without it and
+  // linked with the firmware's own object
+  pub firmware: Option<String>,
+  pub entry: String,
+  pub warnings: Config,
+  // Leave out what the program can never reach
+  pub gc: bool,
+  // Run the peephole pass of -O
+  pub optimize: bool,
+  // Put the debug section into the executable
+  pub debug: bool,
+  // Stop at a relocatable object instead of linking
+  pub object: bool,
+  // Linked in as far as the program uses them
+  pub libs: Vec<Archive>
+}
+
+impl Options {
+  pub fn new(inputs: &[&str]) -> Self {
+    Self {
+      inputs: inputs.iter().map(|s| s.to_string()).collect(),
+      defines: Vec::new(),
+      search: Vec::new(),
+      firmware: Some("firmware.su".to_owned()),
+      entry: "_start".to_owned(),
+      warnings: Config::new(),
+      gc: true,
+      optimize: false,
+      debug: true,
+      object: false,
+      libs: Vec::new()
+    }
+  }
+
+  // Defines NAME or NAME=value like -D, NAME alone is defined as 1. The
+  // value is lexed like source text so it may be any expression
+  pub fn define(&mut self, sources: &mut Sources, def: &str) -> Result<(), String> {
+    let (name, value) = match def.split_once('=') {
+      Some((name, value)) => (name, value),
+      None => (def, "1")
+    };
+    match Token::new(name) {
+      Ok(Token::IDENT(..)) => {},
+      _ => return Err(format!("Invalid name for define '{name}'"))
+    }
+    let id = sources.add("<command line>", value.to_owned());
+    let mut toks: Vec<Lexeme> = Vec::new();
+    for lexeme in Lexer::new(id, value) {
+      match lexeme {
+        Ok(Lexeme { tok: Token::NEWLINE, .. }) => {},
+        Ok(s) => toks.push(s),
+        Err(..) => return Err(format!("Invalid value for define '{name}'"))
+      }
+    }
+    if toks.is_empty() {
+      return Err(format!("Invalid value for define '{name}'"));
+    }
+    self.defines.push(Define(name.to_owned(), toks));
+    Ok(())
+  }
+}
+
+pub struct Executable {
+  // The executable, or the object when Options::object is set
+  pub bytes: Vec<u8>,
+  pub unit: SemUnit,
+  // The linked program, None for objects
+  pub image: Option<Image>,
+  // Warnings which were not made errors
+  pub warnings: Vec<Diagnostic>,
+  // What was left out and what -O changed
+  pub removed: Vec<Removed>,
+  pub optimized: Option<Report>
+}
+
+// Runs every step of blc on the inputs. On failure the diagnostics hold
+// the warnings found along with the errors
+pub fn assemble(sources: &mut Sources, options: &Options) -> Result<Executable, Vec<Diagnostic>> {
+  let entry = options.entry.as_str();
+  let inputs: Vec<&str> = options.inputs.iter().map(|s| s.as_str()).collect();
+  if inputs.is_empty() {
+    return Err(vec![Diagnostic::bare("Input file name needed")]);
+  }
+  let firmware = options.firmware.as_deref();
+  let mut unit = Unit::new(sources, &inputs, options.defines.clone(), options.search.clone(), firmware)?;
+  // Library members may call back into the program, e.g a firmware in an
+  // archive calling main, so what they use is used and kept
+  let mut roots: Vec<String> = vec![entry.to_owned()];
+  if !options.object {
+    roots.extend(archive::referenced(&options.libs, gc::undefined(&unit, entry)));
+  }
+  let (mut errors, warnings): (Vec<Diagnostic>, Vec<Diagnostic>) = lint::lint(&unit, &options.warnings, &roots, !options.object)
+    .into_iter()
+    .partition(|d| d.level == Level::ERROR);
+  let fail = |errors: Vec<Diagnostic>| {
+    let mut diags = warnings.clone();
+    diags.extend(errors);
+    Err(diags)
+  };
+  let mut removed: Vec<Removed> = Vec::new();
+  if options.gc {
+    // Objects keep whatever other objects may use
+    if options.object {
+      let names = unit.funcs.iter().map(|f| Some(&f.name)).chain(unit.data.iter().map(|d| d.1.as_ref()));
+      for name in names.flatten() {
+        if !unit.locals.iter().any(|l| &l.0 == name) {
+          roots.push(name.clone());
+        }
+      }
+    }
+    removed = gc::collect(&mut unit, roots);
+  }
+  let optimized = match options.optimize {
+    true => Some(peephole::optimize(&mut unit)),
+    false => None
+  };
+  let s = match sema::sem_analyse(unit) {
+    Ok(s) if errors.is_empty() => s,
+    Ok(..) => return fail(errors),
+    Err(e) => {
+      errors.extend(e);
+      return fail(errors);
+    }
+  };
+  let object = codegen::code_gen(&s, sources, inputs[0]);
+  if options.object {
+    let mut bytes: Vec<u8> = Vec::new();
+    // Writing to memory cannot fail
+    object.write(&mut bytes).unwrap();
+    return Ok(Executable { bytes, unit: s, image: None, warnings, removed, optimized });
+  }
+  let mut objects = vec![object];
+  archive::select(&mut objects, &options.libs, entry);
+  // Whatever the libraries do not define has to be defined here
+  let missing = archive::undefined(&objects, entry);
+  for (name, loc) in s.externs.iter().filter(|e| missing.contains(&e.0)) {
+    errors.push(Diagnostic::new(*loc, &format!("Function or label {name} not found")));
+  }
+  if !errors.is_empty() {
+    return fail(errors);
+  }
+  match link::link(&objects, entry, s.data_begin, options.debug) {
+    Ok(image) => Ok(Executable { bytes: image.bytes.clone(), unit: s, image: Some(image), warnings, removed, optimized }),
+    Err(e) => fail(e.iter().map(|msg| Diagnostic::bare(msg)).collect())
+  }
+}
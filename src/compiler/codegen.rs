@@ -1,59 +1,79 @@
-use crate::sema::SemUnit;
-use std::fs::File;
+use crate::compiler::file::Sources;
+use crate::compiler::sema::SemUnit;
+use crate::object::{self, Object};
 use std::io::Error;
 extern crate file_utils;
-use crate::parser::*;
-use crate::r#proc::Token;
 use file_utils::write::Write;
 
-fn write_bytes(file: &mut File, buf: &[u8]) -> Result<(), Error> {
+fn write_bytes<W: std::io::Write>(file: &mut W, buf: &[u8]) -> Result<(), Error> {
   for i in buf {
     file.write_u8(*i)?;
   }
   Ok(())
 }
 
-pub fn code_gen(unit: SemUnit) -> Result<(), Error> {
-  let mut writer = File::create(unit.name.clone() + ".out")?;
-  writer.write_u32(unit.header.magic)?;
-  writer.write_u16(unit.header.major)?;
-  writer.write_u16(unit.header.minor)?;
-  writer.write_usize(unit.header.start)?;
-  writer.write_usize(unit.header.data)?;
-  for func in unit.funcs {
-    for ins in func.ins {
+fn write_code<W: std::io::Write>(unit: &SemUnit, writer: &mut W) -> Result<(), Error> {
+  for func in &unit.funcs {
+    for ins in &func.ins {
       writer.write_u32(ins.opcode)?;
-      if ins.size == 4 {
-        continue;
-      }
-      for arg in ins.args {
-       if arg.0.is_some() {
-         writer.write_i64(arg.0.unwrap())?;
-       }
-       else {
-         writer.write_f64(arg.1.unwrap())?;
-       }
-      } // arg
+      write_bytes(writer, &ins.args)?;
     } // ins
   } // func
 
- for data in unit.data {
-    match data.2 {
-      Token::INT(s) => {
-        match data.0 {
-          Type::BYTE => writer.write_u8(s as u8)?,
-          Type::SHORT => writer.write_u16(s as u16)?,
-          Type::INT => writer.write_u32(s as u32)?,
-          Type::LONG => writer.write_u64(s as u64)?,
-          _ => unreachable!()
-        }
-      }
-      Token::STRING(mut s) => {
-        s += "\0";
-        write_bytes(&mut writer, &s.as_bytes())?
+  Ok(())
+}
+
+// Source line of every instruction, a line is only added where it changes
+fn line_table(unit: &SemUnit, sources: &Sources) -> (Vec<String>, Vec<object::Line>) {
+  let mut ids: Vec<usize> = Vec::new();
+  let mut lines: Vec<object::Line> = Vec::new();
+  let mut offset = 0;
+  for ins in unit.funcs.iter().flat_map(|f| &f.ins) {
+    let file = match ids.iter().position(|id| *id == ins.site.file) {
+      Some(s) => s,
+      None => {
+        ids.push(ins.site.file);
+        ids.len() - 1
       }
-      _ => unreachable!()
+    };
+    if lines.last().map(|l| (l.file, l.line)) != Some((file, ins.site.line)) {
+      lines.push(object::Line { offset, file, line: ins.site.line });
     }
+    offset += ins.size;
+  }
+  let files = ids.iter().map(|id| sources.get(*id).name.clone()).collect();
+  (files, lines)
+}
+
+// Turns the unit into an object named name, the executable is made by
+// linking it
+pub fn code_gen(unit: &SemUnit, sources: &Sources, name: &str) -> Object {
+  let mut code: Vec<u8> = Vec::new();
+  let mut data: Vec<u8> = Vec::new();
+  // Writing to memory cannot fail
+  write_code(unit, &mut code).unwrap();
+  for blob in &unit.data {
+    write_bytes(&mut data, &blob.bytes).unwrap();
+  }
+  let symbols = unit.symbols.iter().map(|sym| object::Symbol {
+    name: sym.name.clone(),
+    kind: sym.kind,
+    section: sym.section,
+    offset: sym.offset,
+    size: sym.size,
+    global: sym.global,
+    file: sources.get(sym.loc.file).name.clone(),
+    line: sym.loc.line
+  }).collect();
+  let (files, lines) = line_table(unit, sources);
+  Object {
+    name: name.to_owned(),
+    code,
+    data,
+    data_align: unit.data_align,
+    symbols,
+    relocs: unit.relocs.clone(),
+    files,
+    lines
   }
-  Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,115 @@
+use crate::compiler::file::Sources;
+
+// A position in the sources, columns and lengths are counted in characters
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Loc {
+  pub file: usize,
+  pub line: usize,
+  pub col: usize,
+  pub len: usize
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Level {
+  ERROR,
+  WARNING
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+  pub level: Level,
+  pub loc: Option<Loc>,
+  pub msg: String,
+  // Extra locations which explain the error e.g the macro call it came from
+  pub notes: Vec<(Loc, String)>,
+  // Name of the warning which produced it, for -W options
+  pub flag: Option<&'static str>
+}
+
+impl Diagnostic {
+  pub fn new(loc: Loc, msg: &str) -> Self {
+    Self {
+      level: Level::ERROR,
+      loc: Some(loc),
+      msg: msg.to_owned(),
+      notes: Vec::new(),
+      flag: None
+    }
+  }
+
+  // For errors which cannot be traced back to a line of source
+  pub fn bare(msg: &str) -> Self {
+    Self {
+      level: Level::ERROR,
+      loc: None,
+      msg: msg.to_owned(),
+      notes: Vec::new(),
+      flag: None
+    }
+  }
+
+  pub fn warning(loc: Loc, msg: &str, flag: &'static str) -> Self {
+    Self {
+      level: Level::WARNING,
+      flag: Some(flag),
+      ..Self::new(loc, msg)
+    }
+  }
+
+  // A warning turned into an error by -Werror
+  pub fn promote(self) -> Self {
+    Self {
+      level: Level::ERROR,
+      ..self
+    }
+  }
+
+  pub fn with_note(mut self, loc: Loc, msg: &str) -> Self {
+    self.notes.push((loc, msg.to_owned()));
+    self
+  }
+
+  // Renders the diagnostic along with the source line it points to, e.g.
+  //
+  // error: Invalid instruction
+  //  --> hello.su:5:3
+  //   |
+  // 5 |   mvo r0, 1
+  //   |   ^^^
+  //
+  // Warnings end with the option controlling them, e.g [-Wunused]
+  pub fn render(&self, sources: &Sources) -> String {
+    let mut out = match (self.level, self.flag) {
+      (Level::ERROR, None) => format!("error: {}\n", self.msg),
+      (Level::ERROR, Some(flag)) => format!("error: {} [-Werror={flag}]\n", self.msg),
+      (Level::WARNING, Some(flag)) => format!("warning: {} [-W{flag}]\n", self.msg),
+      (Level::WARNING, None) => format!("warning: {}\n", self.msg)
+    };
+    if let Some(loc) = self.loc {
+      out += &excerpt(loc, sources);
+    }
+    for (loc, msg) in &self.notes {
+      out += &format!("note: {msg}\n");
+      out += &excerpt(*loc, sources);
+    }
+    out
+  }
+}
+
+fn excerpt(loc: Loc, sources: &Sources) -> String {
+  let file = sources.get(loc.file);
+  let text = file.line(loc.line);
+  let number = loc.line.to_string();
+  let pad = " ".repeat(number.len());
+  let mut out = format!("{pad}--> {}:{}:{}\n", file.name, loc.line, loc.col);
+  out += &format!("{pad} |\n");
+  out += &format!("{number} | {text}\n");
+  // Keep tabs in the gutter so the carets line up with the source above
+  let mut marker = String::new();
+  for c in text.chars().take(loc.col.saturating_sub(1)) {
+    marker.push(if c == '\t' { '\t' } else { ' ' });
+  }
+  marker += &"^".repeat(loc.len.max(1));
+  out += &format!("{pad} | {marker}\n");
+  out
+}
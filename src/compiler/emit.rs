@@ -0,0 +1,82 @@
+use crate::compiler::file::Sources;
+use crate::compiler::sema::SemUnit;
+use crate::isa;
+use crate::link::Image;
+
+// Textual outputs of blc besides the executable itself
+
+// Room for a prologue and two immediates, longer encodings push the
+// source text to the right
+const ENCODING_WIDTH: usize = 42;
+
+// Every line of every source file next to the address and encoding of
+// the code or data it produced, e.g.
+//
+// hello.su:
+//     5  0x000058  005e5600 0007e000    mov r0, hello_world
+//
+// followed by the layout of the data section. The unit has to be the
+// first object of the image
+pub fn listing(unit: &SemUnit, image: &Image, sources: &Sources) -> String {
+  // Rows of address and encoding for every (file, line)
+  let mut rows: Vec<(usize, usize, usize, String)> = Vec::new();
+  let mut address = image.code[0];
+  for func in &unit.funcs {
+    for ins in &func.ins {
+      rows.push((ins.site.file, ins.site.line, address, isa::hex(image.at(address, ins.size, unit.data_begin))));
+      address += ins.size;
+    }
+  }
+  for data in &unit.data {
+    let address = image.data[0] + data.offset;
+    let bytes = image.at(address, data.bytes.len(), unit.data_begin);
+    let mut shown: Vec<String> = bytes.iter().take(8).map(|b| format!("{b:02x}")).collect();
+    if bytes.len() > 8 {
+      shown.push("...".to_owned());
+    }
+    rows.push((data.loc.file, data.loc.line, address, shown.join(" ")));
+  }
+  let mut out = String::new();
+  for (id, file) in sources.files().iter().enumerate() {
+    // Text given on the command line, e.g with -D, has no lines to list
+    if file.name.starts_with('<') {
+      continue;
+    }
+    out += &format!("{}:\n", file.name);
+    for (number, text) in file.text.lines().enumerate() {
+      let mut here = rows.iter().filter(|r| r.0 == id && r.1 == number + 1);
+      match here.next() {
+        Some(row) => {
+          out += format!("{:5}  {:#08x}  {:<ENCODING_WIDTH$}  {text}", number + 1, row.2, row.3).trim_end();
+        }
+        None => out += format!("{:5}  {:8}  {:ENCODING_WIDTH$}  {text}", number + 1, "", "").trim_end()
+      }
+      out += "\n";
+      // The rest of the code produced by a macro call
+      for row in here {
+        out += &format!("{:5}  {:#08x}  {}\n", "", row.2, row.3);
+      }
+    }
+    out += "\n";
+  }
+  out += "Data section:\n";
+  for data in &unit.data {
+    let name = data.name.as_deref().unwrap_or("");
+    out += format!("  {:#08x}  {:5}  {name}", image.data[0] + data.offset, data.bytes.len()).trim_end();
+    out += "\n";
+  }
+  out
+}
+
+// Hex dump of the executable, 16 bytes to a line
+pub fn hex(bytes: &[u8]) -> String {
+  let mut out = String::new();
+  for (line, chunk) in bytes.chunks(16).enumerate() {
+    out += &format!("{:08x}:", line * 16);
+    for byte in chunk {
+      out += &format!(" {byte:02x}");
+    }
+    out += "\n";
+  }
+  out
+}
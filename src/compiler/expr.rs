@@ -0,0 +1,178 @@
+use crate::compiler::diag::{Diagnostic, Loc};
+use crate::compiler::parser::{span, Define};
+use crate::compiler::r#proc::{Lexeme, Token};
+use crate::object::Op;
+use crate::isa::register;
+
+// Defines referring to other defines are followed up to this depth
+pub const MAX_DEPTH: usize = 64;
+
+// Operators and their precedence, the same as in C
+fn operator(tok: &Token) -> Option<(Op, u8)> {
+  match tok {
+    Token::LOR => Some((Op::LOR, 1)),
+    Token::LAND => Some((Op::LAND, 2)),
+    Token::PIPE => Some((Op::OR, 3)),
+    Token::AMP => Some((Op::AND, 4)),
+    Token::EQ => Some((Op::EQ, 5)),
+    Token::NE => Some((Op::NE, 5)),
+    Token::LT => Some((Op::LT, 6)),
+    Token::LE => Some((Op::LE, 6)),
+    Token::GT => Some((Op::GT, 6)),
+    Token::GE => Some((Op::GE, 6)),
+    Token::SHL => Some((Op::SHL, 7)),
+    Token::SHR => Some((Op::SHR, 7)),
+    Token::PLUS => Some((Op::ADD, 8)),
+    Token::MINUS => Some((Op::SUB, 8)),
+    Token::STAR => Some((Op::MUL, 9)),
+    Token::SLASH => Some((Op::DIV, 9)),
+    Token::PERCENT => Some((Op::MOD, 9)),
+    _ => None
+  }
+}
+
+// Integer expression evaluated at assembly time. Defines are substituted
+// while parsing so the only symbols left are functions, labels and data
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+  NUM(i64),
+  SYM(String, Loc),
+  NEG(Box<Expr>, Loc),
+  NOT(Box<Expr>),
+  BINARY(Op, Box<Expr>, Box<Expr>, Loc)
+}
+
+struct ExprParser<'a> {
+  toks: &'a [Lexeme],
+  pos: usize,
+  defines: &'a [Define],
+  depth: usize
+}
+
+impl<'a> ExprParser<'a> {
+  fn peek(&self) -> Option<&'a Lexeme> {
+    self.toks.get(self.pos)
+  }
+
+  fn binary(&mut self, min: u8) -> Result<Expr, Diagnostic> {
+    let mut lhs = self.unary()?;
+    while let Some(lexeme) = self.peek() {
+      let (op, prec) = match operator(&lexeme.tok) {
+        Some(s) => s,
+        // Left for the enclosing parenthesis to match
+        None if lexeme.tok == Token::RPAREN => break,
+        None => return Err(Diagnostic::new(lexeme.loc, "Expected operator here"))
+      };
+      if prec < min {
+        break;
+      }
+      self.pos += 1;
+      let rhs = self.binary(prec + 1)?;
+      lhs = Expr::BINARY(op, Box::new(lhs), Box::new(rhs), lexeme.loc);
+    }
+    Ok(lhs)
+  }
+
+  fn unary(&mut self) -> Result<Expr, Diagnostic> {
+    let lexeme = match self.peek() {
+      Some(s) => s,
+      None => {
+        let last = &self.toks[self.toks.len() - 1];
+        return Err(Diagnostic::new(last.loc, "Expression is incomplete"));
+      }
+    };
+    self.pos += 1;
+    match &lexeme.tok {
+      Token::MINUS => Ok(Expr::NEG(Box::new(self.unary()?), lexeme.loc)),
+      Token::PLUS => self.unary(),
+      Token::TILDE => Ok(Expr::NOT(Box::new(self.unary()?))),
+      Token::LPAREN => {
+        let expr = self.binary(0)?;
+        match self.peek() {
+          Some(Lexeme { tok: Token::RPAREN, .. }) => {
+            self.pos += 1;
+            Ok(expr)
+          }
+          _ => Err(Diagnostic::new(lexeme.loc, "Unclosed parenthesis"))
+        }
+      }
+      Token::INT(i) => Ok(Expr::NUM(*i)),
+      Token::IDENT(s) => {
+        if register(s).is_some() {
+          return Err(Diagnostic::new(lexeme.loc, "Registers cannot be used in expressions"));
+        }
+        match self.defines.iter().rev().find(|d| &d.0 == s) {
+          Some(def) => {
+            if self.depth >= MAX_DEPTH {
+              return Err(Diagnostic::new(lexeme.loc, &format!("Define {s} refers to itself")));
+            }
+            parse_depth(&def.1, self.defines, self.depth + 1)
+          }
+          None => Ok(Expr::SYM(s.to_string(), lexeme.loc))
+        }
+      }
+      Token::DECIMAL(..) => Err(Diagnostic::new(lexeme.loc, "Floating point numbers cannot be used in expressions")),
+      _ => Err(Diagnostic::new(lexeme.loc, "Expected number or symbol here"))
+    }
+  }
+}
+
+pub fn parse(toks: &[Lexeme], defines: &[Define]) -> Result<Expr, Diagnostic> {
+  parse_depth(toks, defines, 0)
+}
+
+fn parse_depth(toks: &[Lexeme], defines: &[Define], depth: usize) -> Result<Expr, Diagnostic> {
+  if toks.is_empty() {
+    unreachable!()
+  }
+  let mut parser = ExprParser {
+    toks,
+    pos: 0,
+    defines,
+    depth
+  };
+  let expr = parser.binary(0)?;
+  match parser.peek() {
+    Some(..) => Err(Diagnostic::new(span(&toks[parser.pos..]), "Unexpected tokens after expression")),
+    None => Ok(expr)
+  }
+}
+
+impl Expr {
+  pub fn has_symbols(&self) -> bool {
+    match self {
+      Expr::NUM(..) => false,
+      Expr::SYM(..) => true,
+      Expr::NEG(e, _) | Expr::NOT(e) => e.has_symbols(),
+      Expr::BINARY(_, l, r, _) => l.has_symbols() || r.has_symbols()
+    }
+  }
+
+  // Symbols are looked up through resolve, all arithmetic is checked
+  pub fn eval(&self, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<i64, Diagnostic> {
+    match self {
+      Expr::NUM(i) => Ok(*i),
+      Expr::SYM(s, loc) => match resolve(s) {
+        Some(s) => Ok(s),
+        None => Err(Diagnostic::new(*loc, &format!("Function or label {s} not found")))
+      },
+      Expr::NEG(e, loc) => match e.eval(resolve)?.checked_neg() {
+        Some(s) => Ok(s),
+        None => Err(Diagnostic::new(*loc, "Arithmetic overflow in constant expression"))
+      },
+      Expr::NOT(e) => Ok(!e.eval(resolve)?),
+      Expr::BINARY(op, l, r, loc) => op.apply(l.eval(resolve)?, r.eval(resolve)?).map_err(|e| Diagnostic::new(*loc, e))
+    }
+  }
+
+  // Value of an expression which may only use numbers and defines
+  pub fn constant(&self) -> Result<i64, Diagnostic> {
+    self.eval(&|_| None)
+  }
+}
+
+// Value of an integer define, used for things like DATA_BEGIN
+pub fn define_value(name: &str, defines: &[Define]) -> Option<Result<i64, Diagnostic>> {
+  let def = defines.iter().rev().find(|d| d.0 == name)?;
+  Some(parse(&def.1, defines).and_then(|e| e.constant()))
+}
@@ -1,40 +1,148 @@
-use std::fs::File;
-use std::io::{BufReader, BufRead};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
-pub struct Handle {
-  pub file: String,
-  line: usize,
-  contents: BufReader<File>
+pub struct SourceFile {
+  pub name: String,
+  pub text: String
 }
 
-impl Handle {
-  pub fn new(name: &str) -> Self {
-    let h = match File::open(name) {
-      Ok(s) => s,
-      Err(..) => panic!("Could not open file {}", name)
-    };
+impl SourceFile {
+  // Lines are numbered from 1 just like in diagnostics
+  pub fn line(&self, line: usize) -> &str {
+    match line {
+      0 => "",
+      _ => self.text.lines().nth(line - 1).unwrap_or("")
+    }
+  }
+}
+
+// Where the files to assemble and include are read from
+pub enum FileSystem {
+  DISK,
+  // Files held in memory, by their path with . and .. taken out
+  MEMORY(Vec<(PathBuf, String)>),
+  // The disk, except for the files held in memory by their canonical
+  // path e.g unsaved files open in an editor
+  OVERLAY(Vec<(PathBuf, String)>)
+}
+
+// Takes . and .. out of a path without looking at the disk
+fn normalize(path: &Path) -> PathBuf {
+  let mut out = PathBuf::new();
+  for part in path.components() {
+    match part {
+      Component::CurDir => {},
+      Component::ParentDir if out.file_name().is_some() => {
+        out.pop();
+      }
+      _ => out.push(part)
+    }
+  }
+  out
+}
+
+// Every file read by the assembler is kept here so that diagnostics
+// can quote the offending source line long after parsing has finished
+pub struct Sources {
+  files: Vec<SourceFile>,
+  fs: FileSystem
+}
+
+impl Sources {
+  pub fn new() -> Self {
+    Self {
+      files: Vec::new(),
+      fs: FileSystem::DISK
+    }
+  }
+
+  // Reads nothing from the disk, every file assembled or included has to
+  // be among files as (path, text)
+  pub fn in_memory(files: Vec<(String, String)>) -> Self {
     Self {
-      file: name.to_owned(),
-      line : 0,
-      contents: BufReader::new(h)
+      files: Vec::new(),
+      fs: FileSystem::MEMORY(files.into_iter().map(|f| (normalize(Path::new(&f.0)), f.1)).collect())
     }
   }
-  
-  pub fn read_line(&mut self) -> String {
-    let mut ret = String::new();
-    match self.contents.read_line(&mut ret) {
-      Ok(s) => { 
-        if s == 0 { 
-          return "EOF".to_owned();
+
+  // Reads the disk like new(), but files is read in place of what is
+  // there. Files do not have to exist on the disk
+  pub fn overlay(files: Vec<(String, String)>) -> Self {
+    let mut sources = Self { files: Vec::new(), fs: FileSystem::OVERLAY(Vec::new()) };
+    let files = files.into_iter().map(|f| (sources.canonical(Path::new(&f.0)), f.1)).collect();
+    sources.fs = FileSystem::OVERLAY(files);
+    sources
+  }
+
+  fn read(&self, name: &str) -> Result<String, String> {
+    match &self.fs {
+      FileSystem::DISK => fs::read_to_string(name).map_err(|e| format!("Could not open file {name} : {e}")),
+      FileSystem::OVERLAY(files) => {
+        let path = self.canonical(Path::new(name));
+        match files.iter().find(|f| f.0 == path) {
+          Some(f) => Ok(f.1.clone()),
+          None => fs::read_to_string(name).map_err(|e| format!("Could not open file {name} : {e}"))
         }
-        self.line += 1;
-        ret.trim().to_string()
       }
-      Err(..) => panic!("Failed to read from file {} ", self.file)
+      FileSystem::MEMORY(files) => {
+        let path = normalize(Path::new(name));
+        match files.iter().find(|f| f.0 == path) {
+          Some(f) => Ok(f.1.clone()),
+          None => Err(format!("Could not open file {name} : No such file"))
+        }
+      }
+    }
+  }
+
+  pub fn load(&mut self, name: &str) -> Result<usize, String> {
+    let text = self.read(name)?;
+    Ok(self.add(name, text))
+  }
+
+  pub fn exists(&self, path: &Path) -> bool {
+    match &self.fs {
+      FileSystem::DISK => path.is_file(),
+      FileSystem::MEMORY(files) => {
+        let path = normalize(path);
+        files.iter().any(|f| f.0 == path)
+      }
+      FileSystem::OVERLAY(files) => {
+        let path = self.canonical(path);
+        path.is_file() || files.iter().any(|f| f.0 == path)
+      }
+    }
+  }
+
+  // One name for every path of a file, so it is only included once
+  pub fn canonical(&self, path: &Path) -> PathBuf {
+    match &self.fs {
+      FileSystem::DISK => fs::canonicalize(path).unwrap_or(path.to_path_buf()),
+      // Files only in memory have no canonical path on the disk
+      FileSystem::OVERLAY(..) => fs::canonicalize(path).unwrap_or(normalize(path)),
+      FileSystem::MEMORY(..) => normalize(path)
     }
   }
-  
-  pub fn error(&self, msg: &str) -> ! {
-    panic!("In File {} at line {} : {}", self.file, self.line, msg)
+
+  // Registers text which did not come from a file e.g a -D value
+  pub fn add(&mut self, name: &str, text: String) -> usize {
+    self.files.push(SourceFile {
+      name: name.to_owned(),
+      text
+    });
+    self.files.len() - 1
+  }
+
+  pub fn get(&self, id: usize) -> &SourceFile {
+    &self.files[id]
+  }
+
+  pub fn files(&self) -> &[SourceFile] {
+    &self.files
+  }
+}
+
+impl Default for Sources {
+  fn default() -> Self {
+    Self::new()
   }
 }
@@ -0,0 +1,140 @@
+use crate::compiler::diag::Loc;
+use crate::compiler::expr::Expr;
+use crate::compiler::parser::{Args, Type, Value};
+use crate::compiler::r#proc::Unit;
+use crate::compiler::sema::label;
+
+// Drops the functions and data the program can never reach, starting from
+// the roots and following every reference in code and data. setex and
+// call targets are references like any other, so exception handlers and
+// everything they call are kept
+
+pub struct Removed {
+  pub kind: &'static str,
+  pub name: String,
+  pub loc: Loc
+}
+
+fn symbols(e: &Expr, out: &mut Vec<String>) {
+  match e {
+    Expr::NUM(..) => {},
+    Expr::SYM(s, _) => out.push(s.clone()),
+    Expr::NEG(e, _) | Expr::NOT(e) => symbols(e, out),
+    Expr::BINARY(_, l, r, _) => {
+      symbols(l, out);
+      symbols(r, out);
+    }
+  }
+}
+
+// Unnamed data continues the named item before it, e.g the second line of
+//
+// table: .long 1, 2
+//        .long 3, 4
+//
+// .align only pads whatever follows, so it is never removed
+fn owners(unit: &Unit) -> Vec<Option<String>> {
+  let mut owner: Option<String> = None;
+  let mut out: Vec<Option<String>> = Vec::new();
+  for data in &unit.data {
+    if data.1.is_some() {
+      owner = data.1.clone();
+    }
+    match data.0 {
+      Type::ALIGN => out.push(None),
+      _ => out.push(owner.clone())
+    }
+  }
+  out
+}
+
+pub fn collect(unit: &mut Unit, roots: Vec<String>) -> Vec<Removed> {
+  let owners = owners(unit);
+  let mut live: Vec<String> = Vec::new();
+  let mut pending = roots;
+  while let Some(name) = pending.pop() {
+    if live.contains(&name) {
+      continue;
+    }
+    let mut refs: Vec<String> = Vec::new();
+    // Labels of the function resolve to the function itself, so they
+    // only add names which are never defined
+    for func in unit.funcs.iter().filter(|f| f.name == name) {
+      for ins in &func.ins {
+        for arg in ins.args.iter().flatten() {
+          if let Args::EXPR(e) | Args::OFFSET(_, e) = arg {
+            symbols(e, &mut refs);
+          }
+        }
+      }
+    }
+    for (data, owner) in unit.data.iter().zip(&owners) {
+      if owner.as_ref() != Some(&name) {
+        continue;
+      }
+      for value in &data.2 {
+        if let Value::EXPR(e, _) = value {
+          symbols(e, &mut refs);
+        }
+      }
+    }
+    live.push(name);
+    pending.extend(refs);
+  }
+
+  let mut removed: Vec<Removed> = Vec::new();
+  unit.funcs.retain(|f| {
+    let keep = live.contains(&f.name);
+    if !keep {
+      removed.push(Removed { kind: "function", name: f.name.clone(), loc: f.loc });
+    }
+    keep
+  });
+  let mut owners = owners.into_iter();
+  unit.data.retain(|d| {
+    let keep = match owners.next().unwrap() {
+      Some(owner) => live.contains(&owner),
+      None => true
+    };
+    if let (false, Some(name)) = (keep, &d.1) {
+      removed.push(Removed { kind: "data", name: name.clone(), loc: d.3 });
+    }
+    keep
+  });
+  unit.locals.retain(|l| live.contains(&l.0));
+  removed
+}
+
+// Names the unit refers to without defining them, the entry point first.
+// These are what library members are taken for when linking
+pub fn undefined(unit: &Unit, entry: &str) -> Vec<String> {
+  let defined = |name: &str| {
+    unit.funcs.iter().any(|f| f.name == name) || unit.data.iter().any(|d| d.1.as_deref() == Some(name))
+  };
+  let mut refs: Vec<String> = vec![entry.to_owned()];
+  for func in &unit.funcs {
+    for (index, ins) in func.ins.iter().enumerate() {
+      let mut names: Vec<String> = Vec::new();
+      for arg in ins.args.iter().flatten() {
+        if let Args::EXPR(e) | Args::OFFSET(_, e) = arg {
+          symbols(e, &mut names);
+        }
+      }
+      refs.extend(names.into_iter().filter(|n| label(n, func, index).is_none()));
+    }
+  }
+  for data in &unit.data {
+    for value in &data.2 {
+      if let Value::EXPR(e, _) = value {
+        symbols(e, &mut refs);
+      }
+    }
+  }
+  let mut names: Vec<String> = Vec::new();
+  for name in refs {
+    if !defined(&name) && !names.contains(&name) {
+      names.push(name);
+    }
+  }
+  names
+}
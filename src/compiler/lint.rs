@@ -0,0 +1,355 @@
+use crate::compiler::diag::{Diagnostic, Loc};
+use crate::compiler::expr::Expr;
+use crate::compiler::parser::{Args, Attrs, Function, Instr, Label, Value};
+use crate::compiler::r#proc::Unit;
+use crate::compiler::sema::label;
+use crate::isa::{Opcode, SP};
+
+// Checks which do not stop a program from assembling but point at likely
+// mistakes. Each one can be turned off with -Wno-<name> or made an error
+// with -Werror=<name>
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Warning {
+  // Functions, labels and data nothing refers to
+  UNUSED,
+  // Instructions after ret or jmp which no label leads to
+  UNREACHABLE,
+  // r7 to r14 changed without being saved, see the calling convention
+  CALLEESAVED,
+  // Privileged instructions outside of .firmware functions
+  PRIVILEGED,
+  // Writes to the data section, which the firmware maps read-only
+  READONLY
+}
+
+const WARNINGS: [Warning; 5] = [
+  Warning::UNUSED,
+  Warning::UNREACHABLE,
+  Warning::CALLEESAVED,
+  Warning::PRIVILEGED,
+  Warning::READONLY
+];
+
+impl Warning {
+  pub fn new(name: &str) -> Option<Self> {
+    WARNINGS.iter().find(|w| w.name() == name).copied()
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Warning::UNUSED => "unused",
+      Warning::UNREACHABLE => "unreachable",
+      Warning::CALLEESAVED => "callee-saved",
+      Warning::PRIVILEGED => "privileged",
+      Warning::READONLY => "read-only"
+    }
+  }
+}
+
+// What to do with each warning, everything is enabled by default
+#[derive(Debug, Clone)]
+pub struct Config {
+  pub disabled: Vec<Warning>,
+  pub errors: Vec<Warning>
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Config {
+  pub fn new() -> Self {
+    Self {
+      disabled: Vec::new(),
+      errors: Vec::new()
+    }
+  }
+
+  // Takes what follows -W: no-<name>, error=<name> or error. 'all' may be
+  // used as the name
+  pub fn set(&mut self, option: &str) -> Result<(), String> {
+    let (list, name) = match option {
+      "error" => (&mut self.errors, "all"),
+      _ => match (option.strip_prefix("no-"), option.strip_prefix("error=")) {
+        (Some(name), _) => (&mut self.disabled, name),
+        (_, Some(name)) => (&mut self.errors, name),
+        _ => return Err(format!("Unknown warning option '-W{option}'"))
+      }
+    };
+    match (name, Warning::new(name)) {
+      ("all", _) => list.extend_from_slice(&WARNINGS),
+      (_, Some(w)) => list.push(w),
+      _ => return Err(format!("Unknown warning '{name}'"))
+    }
+    Ok(())
+  }
+}
+
+struct Lint<'a> {
+  config: &'a Config,
+  diags: Vec<Diagnostic>
+}
+
+impl Lint<'_> {
+  fn warn(&mut self, warning: Warning, loc: Loc, msg: &str) {
+    if self.config.disabled.contains(&warning) {
+      return;
+    }
+    let diag = Diagnostic::warning(loc, msg, warning.name());
+    match self.config.errors.contains(&warning) {
+      true => self.diags.push(diag.promote()),
+      false => self.diags.push(diag)
+    }
+  }
+
+  // Warnings about an instruction made by a macro also point at the call
+  fn warn_at(&mut self, warning: Warning, ins: &Instr, msg: &str) {
+    let before = self.diags.len();
+    self.warn(warning, ins.loc, msg);
+    if self.diags.len() != before && ins.site != ins.loc {
+      let diag = self.diags.pop().unwrap();
+      self.diags.push(diag.with_note(ins.site, "In expansion of the macro called here"));
+    }
+  }
+}
+
+fn symbols<'a>(e: &'a Expr, out: &mut Vec<&'a str>) {
+  match e {
+    Expr::NUM(..) => {},
+    Expr::SYM(s, _) => out.push(s),
+    Expr::NEG(e, _) | Expr::NOT(e) => symbols(e, out),
+    Expr::BINARY(_, l, r, _) => {
+      symbols(l, out);
+      symbols(r, out);
+    }
+  }
+}
+
+// Names an operand refers to
+fn operand_symbols<'a>(arg: &'a Args, out: &mut Vec<&'a str>) {
+  match arg {
+    Args::EXPR(e) | Args::OFFSET(_, e) => symbols(e, out),
+    _ => {}
+  }
+}
+
+// Registers b, w, d and r of the same number are the same register
+fn register_id(reg: u8) -> Option<u8> {
+  match reg < SP {
+    true => Some(reg % 20),
+    false => None
+  }
+}
+
+// Instructions which store into their first operand
+fn writes_first(opcode: Opcode) -> bool {
+  matches!(opcode, Opcode::MOV | Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::MOD |
+    Opcode::OR | Opcode::AND | Opcode::XOR | Opcode::SHL | Opcode::SHR | Opcode::INC | Opcode::DEC |
+    Opcode::SET | Opcode::CLEAR | Opcode::POP | Opcode::LEA)
+}
+
+fn args(ins: &Instr) -> &[Args] {
+  ins.args.as_deref().unwrap_or(&[])
+}
+
+// Labels made by a macro expansion are named label@N
+fn display(name: &str) -> &str {
+  name.split('@').next().unwrap()
+}
+
+fn unused(lint: &mut Lint, unit: &Unit, roots: &[String], executable: bool) {
+  let mut used: Vec<&str> = roots.iter().map(|s| s.as_str()).collect();
+  for func in &unit.funcs {
+    for ins in &func.ins {
+      for arg in args(ins) {
+        operand_symbols(arg, &mut used);
+      }
+    }
+  }
+  for data in &unit.data {
+    for value in &data.2 {
+      if let Value::EXPR(e, _) = value {
+        symbols(e, &mut used);
+      }
+    }
+  }
+  // Other objects may use whatever is not local, and included files are
+  // libraries a program uses only part of
+  let checked = |name: &str, loc: Loc| {
+    unit.inputs.contains(&loc.file) && (executable || unit.locals.iter().any(|l| l.0 == name))
+  };
+  for func in &unit.funcs {
+    if checked(&func.name, func.loc) && !used.contains(&func.name.as_str()) {
+      lint.warn(Warning::UNUSED, func.loc, &format!("Function {} is never used", func.name));
+    }
+  }
+  for data in &unit.data {
+    if let Some(name) = &data.1 {
+      if checked(name, data.3) && !used.contains(&name.as_str()) {
+        lint.warn(Warning::UNUSED, data.3, &format!("Data {name} is never used"));
+      }
+    }
+  }
+  // A label is used if some reference in its function resolves to it
+  let mut labels: Vec<(&Label, bool)> = Vec::new();
+  for func in &unit.funcs {
+    let mut targets: Vec<&Label> = Vec::new();
+    for (index, ins) in func.ins.iter().enumerate() {
+      let mut names: Vec<&str> = Vec::new();
+      for arg in args(ins) {
+        operand_symbols(arg, &mut names);
+      }
+      for name in names {
+        if let Some(l) = label(name, func, index) {
+          targets.push(l);
+        }
+      }
+    }
+    for l in &func.labels {
+      labels.push((l, targets.iter().any(|t| std::ptr::eq(*t, l))));
+    }
+  }
+  // Every expansion of a macro has its own copy of the labels in the body,
+  // which is unused only if no copy is used and reported once at the body
+  let mut reported: Vec<Loc> = Vec::new();
+  for (l, _) in labels.iter().filter(|(l, _)| unit.inputs.contains(&l.loc.file)) {
+    let mut copies = labels.iter().filter(|(c, _)| c.loc == l.loc && display(&c.name) == display(&l.name));
+    if copies.any(|c| c.1) || reported.contains(&l.loc) {
+      continue;
+    }
+    reported.push(l.loc);
+    lint.warn(Warning::UNUSED, l.loc, &format!("Label {} is never used", display(&l.name)));
+  }
+}
+
+fn unreachable(lint: &mut Lint, func: &Function) {
+  let mut ended = false;
+  let mut reported = false;
+  for (index, ins) in func.ins.iter().enumerate() {
+    if func.labels.iter().any(|l| l.index == index) {
+      ended = false;
+    }
+    if ended {
+      // One warning for the whole run of dead code
+      if !reported {
+        lint.warn_at(Warning::UNREACHABLE, ins, "Instruction can never be reached");
+        reported = true;
+      }
+      continue;
+    }
+    reported = false;
+    ended = matches!(ins.def.opcode, Opcode::RET | Opcode::JMP);
+  }
+}
+
+fn callee_saved(lint: &mut Lint, func: &Function) {
+  let saved: Vec<u8> = func.ins.iter()
+    .filter(|ins| ins.def.opcode == Opcode::PUSH)
+    .filter_map(|ins| match args(ins).first() {
+      Some(Args::REGISTER(r)) => register_id(*r),
+      _ => None
+    })
+    .collect();
+  let mut reported: Vec<u8> = Vec::new();
+  for ins in &func.ins {
+    if !writes_first(ins.def.opcode) || ins.def.opcode == Opcode::POP {
+      continue;
+    }
+    let id = match args(ins).first() {
+      Some(Args::REGISTER(r)) => register_id(*r),
+      _ => None
+    };
+    if let Some(id) = id.filter(|id| (7..=14).contains(id)) {
+      if !saved.contains(&id) && !reported.contains(&id) {
+        reported.push(id);
+        lint.warn_at(Warning::CALLEESAVED, ins, &format!(
+          "r{id} is changed without being pushed first, functions have to preserve r7 to r14"
+        ));
+      }
+    }
+  }
+}
+
+fn privileged(lint: &mut Lint, func: &Function) {
+  let firmware = func.attrs.iter().flatten().any(|a| matches!(a.0, Attrs::FIRMWARE));
+  if firmware {
+    return;
+  }
+  for ins in &func.ins {
+    let privileged = ins.def.privileged || (ins.def.opcode == Opcode::SYSCALL && args(ins).first() == Some(&Args::INT(0)));
+    if privileged {
+      lint.warn_at(Warning::PRIVILEGED, ins, &format!(
+        "{} is privileged and only runs in .firmware functions", ins.def.mnemonic
+      ));
+    }
+  }
+}
+
+// Follows registers loaded with the address of data, within straight
+// line code, and warns when something is stored through them
+fn read_only(lint: &mut Lint, unit: &Unit, func: &Function) {
+  let data = |name: &str| unit.data.iter().any(|d| d.1.as_deref() == Some(name));
+  let data_in = |e: &Expr| {
+    let mut names: Vec<&str> = Vec::new();
+    symbols(e, &mut names);
+    names.into_iter().find(|n| data(n)).map(|n| n.to_owned())
+  };
+  let mut holds: Vec<(u8, String)> = Vec::new();
+  for (index, ins) in func.ins.iter().enumerate() {
+    // Control may arrive here from elsewhere
+    if func.labels.iter().any(|l| l.index == index) || ins.def.opcode == Opcode::CALL {
+      holds.clear();
+    }
+    let args = args(ins);
+    if let Some(Args::OFFSET(base, off)) = args.first() {
+      let target = holds.iter().find(|h| Some(h.0) == register_id(*base)).map(|h| h.1.clone());
+      if let Some(name) = target.or_else(|| data_in(off)) {
+        if writes_first(ins.def.opcode) {
+          lint.warn_at(Warning::READONLY, ins, &format!(
+            "Write to data {name}, which the firmware maps read-only"
+          ));
+        }
+      }
+    }
+    if let Some(Args::REGISTER(r)) = args.first() {
+      let id = match register_id(*r) {
+        Some(s) => s,
+        None => continue
+      };
+      if !writes_first(ins.def.opcode) {
+        continue;
+      }
+      holds.retain(|h| h.0 != id);
+      let loaded = match (ins.def.opcode, args.get(1)) {
+        (Opcode::MOV, Some(Args::EXPR(e))) => data_in(e),
+        (Opcode::LEA, Some(Args::OFFSET(base, off))) => holds.iter()
+          .find(|h| Some(h.0) == register_id(*base))
+          .map(|h| h.1.clone())
+          .or_else(|| data_in(off)),
+        _ => None
+      };
+      if let Some(name) = loaded {
+        holds.push((id, name));
+      }
+    }
+  }
+}
+
+// Runs every check on the parsed program. Roots are used from outside the
+// unit, like the entry point and whatever the libraries linked in use.
+// Unless an executable is being made, only local functions and data can
+// be known to be unused
+pub fn lint(unit: &Unit, config: &Config, roots: &[String], executable: bool) -> Vec<Diagnostic> {
+  let mut lint = Lint { config, diags: Vec::new() };
+  unused(&mut lint, unit, roots, executable);
+  for func in &unit.funcs {
+    unreachable(&mut lint, func);
+    callee_saved(&mut lint, func);
+    privileged(&mut lint, func);
+    read_only(&mut lint, unit, func);
+  }
+  lint.diags.sort_by_key(|d| d.loc.map(|l| (l.file, l.line, l.col)));
+  lint.diags
+}
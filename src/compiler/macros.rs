@@ -0,0 +1,109 @@
+use crate::compiler::diag::{Diagnostic, Loc};
+use crate::compiler::r#proc::{Lexeme, Token};
+
+// Expansions calling further macros are followed up to this depth
+pub const MAX_DEPTH: usize = 64;
+
+// A macro is defined as
+//
+// macro name arg1, arg2
+//   ...
+// endm
+//
+// and is recorded as the statements of its body, still in token form
+pub struct Macro {
+  pub name: String,
+  pub params: Vec<String>,
+  pub body: Vec<Vec<Lexeme>>,
+  pub loc: Loc
+}
+
+fn numeric(name: &str) -> bool {
+  name.chars().all(|c| c.is_ascii_digit())
+}
+
+impl Macro {
+  pub fn new(target: &[Lexeme]) -> Result<Self, Diagnostic> {
+    let (name, loc) = match target.get(1) {
+      Some(Lexeme { tok: Token::IDENT(s), loc }) => (s.to_string(), *loc),
+      Some(s) => return Err(Diagnostic::new(s.loc, "Expected identifier after keyword 'macro'")),
+      None => return Err(Diagnostic::new(target[0].loc, "Expected identifier after keyword 'macro'"))
+    };
+    let mut params: Vec<String> = Vec::new();
+    let rest = &target[2..];
+    if !rest.is_empty() {
+      for param in rest.split(|lexeme| lexeme.tok == Token::COMMA) {
+        match param {
+          [Lexeme { tok: Token::IDENT(s), loc }] => {
+            if params.contains(s) {
+              return Err(Diagnostic::new(*loc, "Duplicate macro parameter"));
+            }
+            params.push(s.to_string());
+          }
+          [] => return Err(Diagnostic::new(rest[0].loc, "Expected parameter name between ','")),
+          _ => return Err(Diagnostic::new(param[0].loc, "Expected parameter name"))
+        }
+      }
+    }
+    Ok(Self {
+      name,
+      params,
+      body: Vec::new(),
+      loc
+    })
+  }
+
+  // Substitutes the arguments into the body. Labels declared inside the
+  // body get the expansion number appended so that a macro can be used
+  // several times in the same function, '@' cannot appear in source so
+  // the new names never clash with the user's
+  pub fn expand(&self, args: &[Lexeme], call: Loc, unique: usize) -> Result<Vec<Vec<Lexeme>>, Diagnostic> {
+    let args: Vec<&[Lexeme]> = match args.len() {
+      0 => Vec::new(),
+      _ => args.split(|lexeme| lexeme.tok == Token::COMMA).collect()
+    };
+    if args.len() != self.params.len() {
+      return Err(Diagnostic::new(call, &format!(
+        "Macro {} expects {} argument(s) but was given {}", self.name, self.params.len(), args.len()
+      )).with_note(self.loc, "Macro defined here"));
+    }
+    if args.iter().any(|arg| arg.is_empty()) {
+      return Err(Diagnostic::new(call, "Expected macro argument between ','"));
+    }
+    let mut labels: Vec<&str> = Vec::new();
+    for stmt in &self.body {
+      for lexeme in stmt {
+        if let Token::LABEL(s) = &lexeme.tok {
+          if !numeric(s) {
+            labels.push(s);
+          }
+        }
+      }
+    }
+    let mut ret: Vec<Vec<Lexeme>> = Vec::new();
+    for stmt in &self.body {
+      let mut out: Vec<Lexeme> = Vec::new();
+      for lexeme in stmt {
+        match &lexeme.tok {
+          Token::IDENT(s) => {
+            if let Some(index) = self.params.iter().position(|p| p == s) {
+              out.extend_from_slice(args[index]);
+              continue;
+            }
+            if labels.contains(&s.as_str()) {
+              out.push(Lexeme { tok: Token::IDENT(format!("{s}@{unique}")), loc: lexeme.loc });
+              continue;
+            }
+            out.push(lexeme.clone());
+          }
+          Token::LABEL(s) if labels.contains(&s.as_str()) => {
+            out.push(Lexeme { tok: Token::LABEL(format!("{s}@{unique}")), loc: lexeme.loc });
+          }
+          _ => out.push(lexeme.clone())
+        }
+      }
+      ret.push(out);
+    }
+    Ok(ret)
+  }
+}
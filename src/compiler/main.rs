@@ -1,30 +1,270 @@
 use std::panic;
 use std::env;
-mod file;
-use file::Handle;
-mod r#proc;
-mod parser;
-mod sema;
-mod codegen;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use std::path::{Path, PathBuf};
+use blitz::compiler::assemble::{assemble, Options};
+use blitz::compiler::diag::{Diagnostic, Level};
+use blitz::compiler::file::Sources;
+use blitz::compiler::emit;
+use blitz::archive::Archive;
+use blitz::link;
 
-use r#proc::Unit;
+// Exit codes, so scripts can tell a bad program from a bad invocation
+const EXIT_ERRORS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
+
+const HELP: &str = "Usage: blc [options] <file>...
+
+Assembles the given files into a single executable for the blitz VM
+
+Options:
+  -o <path>          Write the output to path, - for standard output
+  -c                 Write a relocatable object to be linked with bld
+  -D <name>[=value]  Define name, the value defaults to 1
+  -I <dir>           Search dir for included files
+  -l <lib>           Link the members of the archive lib the program uses
+  --emit=<kind>      Output bin (default), obj, listing, symbols or hex
+  --firmware <file>  Use file as the firmware instead of firmware.su
+  --no-firmware      Do not include any firmware, the default with -c
+  --entry <name>     Start execution at name instead of _start
+  -Wno-<name>        Turn off a warning: unused, unreachable, callee-saved,
+                     privileged, read-only or all
+  -Werror=<name>     Make a warning an error, -Werror makes all of them one
+  --no-map           Do not write the symbol map next to the executable
+  --no-debug         Leave the function names and line table out of the
+                     executable
+  --no-gc            Keep functions and data the program never uses
+  --print-removed    List the functions and data which were left out
+  -O                 Remove no-op instructions, shorten jumps to jumps and
+                     print what was changed
+  -h, --help         Print this message
+  --version          Print the version of blc
+
+Exit status is 0 on success, 1 if the program has errors, 2 for bad
+options and 3 if the output could not be written";
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Copy, Clone)]
+enum Emit {
+  BIN,
+  OBJECT,
+  LISTING,
+  SYMBOLS,
+  HEX
+}
+
+impl Emit {
+  fn new(kind: &str) -> Option<Self> {
+    match kind {
+      "bin" => Some(Emit::BIN),
+      "obj" => Some(Emit::OBJECT),
+      "listing" => Some(Emit::LISTING),
+      "symbols" => Some(Emit::SYMBOLS),
+      "hex" => Some(Emit::HEX),
+      _ => None
+    }
+  }
+
+  // Used to name the output after the first input when -o is not given
+  fn extension(&self) -> &'static str {
+    match self {
+      Emit::BIN => "out",
+      Emit::OBJECT => "o",
+      Emit::LISTING => "lst",
+      Emit::SYMBOLS => "sym",
+      Emit::HEX => "hex"
+    }
+  }
+}
+
+fn usage(msg: &str) -> ! {
+  eprintln!("blc: {msg}");
+  eprintln!("Try 'blc --help' for more information");
+  process::exit(EXIT_USAGE);
+}
+
+// Warnings come first, as they are found before anything else
+fn warnings(sources: &Sources, diags: &[Diagnostic]) {
+  for diag in diags {
+    eprintln!("{}", diag.render(sources));
+  }
+  if !diags.is_empty() {
+    eprintln!("blc: {} warning(s) generated", diags.len());
+  }
+}
+
+fn report(sources: &Sources, diags: Vec<Diagnostic>) -> ! {
+  let (errors, found): (Vec<Diagnostic>, Vec<Diagnostic>) = diags.into_iter().partition(|d| d.level == Level::ERROR);
+  warnings(sources, &found);
+  for diag in &errors {
+    eprintln!("{}", diag.render(sources));
+  }
+  eprintln!("blc: {} error(s) generated", errors.len());
+  process::exit(EXIT_ERRORS);
+}
 
 fn main() {
    panic::set_hook(Box::new(|panic_info| {
     if let Some(s) = panic_info.payload().downcast_ref::<String>(){
-      println!("{s}");
+      eprintln!("{s}");
     } 
     else if let Some(s) = panic_info.payload().downcast_ref::<&str>() {
-      println!("{s}");
+      eprintln!("{s}");
     }
   })); 
-  let args: Vec<String> = env::args().collect();
-  if args.len() < 2 {
-    panic!("Input file name needed");
-  }
-  let s = sema::sem_analyse(Unit::new(Handle::new(&args[1])));
-  match codegen::code_gen(s) {
-    Ok(..) => {},
-    Err(e) => panic!("{e}")
+  let args: Vec<String> = env::args().skip(1).collect();
+  let mut options = Options::new(&[]);
+  let mut output: Option<&str> = None;
+  let mut emit = Emit::BIN;
+  let mut firmware: Option<&str> = None;
+  let mut no_firmware = false;
+  let mut map = true;
+  let mut libs: Vec<&str> = Vec::new();
+  let mut print_removed = false;
+  let mut sources = Sources::new();
+  let mut index = 0;
+  while index < args.len() {
+    let arg = args[index].as_str();
+    index += 1;
+    // Options which take a value accept it either attached (-DNAME) or
+    // as the next argument (-D NAME)
+    let (option, attached) = match arg {
+      _ if arg.starts_with("--emit=") => ("--emit", &arg[7..]),
+      _ if arg.starts_with("--") => (arg, ""),
+      _ if arg.len() > 1 && arg.starts_with('-') => {
+        // The option letter need not be ASCII
+        let split = arg.char_indices().nth(2).map_or(arg.len(), |(i, _)| i);
+        arg.split_at(split)
+      }
+      _ => {
+        options.inputs.push(arg.to_owned());
+        continue;
+      }
+    };
+    let needs_value = matches!(option, "-o" | "-D" | "-I" | "-l" | "-W" | "--firmware" | "--entry");
+    let value = match (needs_value, attached) {
+      (false, _) => "",
+      (true, "") => {
+        index += 1;
+        match args.get(index - 1) {
+          Some(s) => s.as_str(),
+          None => usage(&format!("{option} needs a value"))
+        }
+      }
+      (true, s) => s
+    };
+    match option {
+      "-h" | "--help" => {
+        println!("{HELP}");
+        return;
+      }
+      "--version" => {
+        println!("blc {}", env!("CARGO_PKG_VERSION"));
+        return;
+      }
+      "-o" => output = Some(value),
+      "-D" => if let Err(e) = options.define(&mut sources, value) {
+        usage(&e)
+      },
+      "-I" => options.search.push(PathBuf::from(value)),
+      "-l" => libs.push(value),
+      "-W" => if let Err(e) = options.warnings.set(value) {
+        usage(&e)
+      },
+      "--emit" => match Emit::new(attached) {
+        Some(s) => emit = s,
+        None => usage(&format!("Unknown kind of output '{attached}'"))
+      },
+      "-c" => emit = Emit::OBJECT,
+      "--firmware" => firmware = Some(value),
+      "--no-firmware" => no_firmware = true,
+      "--no-map" => map = false,
+      "--no-debug" => options.debug = false,
+      "--no-gc" => options.gc = false,
+      "--print-removed" => print_removed = true,
+      "-O" => options.optimize = true,
+      "--entry" => options.entry = value.to_owned(),
+      _ => usage(&format!("Unknown option '{arg}'"))
+    }
+    if !needs_value && option != "--emit" && !attached.is_empty() {
+      usage(&format!("Unknown option '{arg}'"));
+    }
+  }
+  if options.inputs.is_empty() {
+    usage("Input file name needed");
+  }
+  if emit == Emit::OBJECT && !libs.is_empty() {
+    usage("Libraries are linked with bld when using -c");
+  }
+  for lib in &libs {
+    let res = fs::read(lib).and_then(|bytes| Archive::read(lib, &mut bytes.as_slice()));
+    match res {
+      Ok(s) => options.libs.push(s),
+      Err(e) => {
+        eprintln!("blc: Failed to read {lib} : {e}");
+        process::exit(EXIT_IO);
+      }
+    }
+  }
+  options.object = emit == Emit::OBJECT;
+  // Objects are linked with the firmware later on
+  options.firmware = match (no_firmware, firmware, emit) {
+    (true, _, _) => None,
+    (false, Some(s), _) => Some(s.to_owned()),
+    (false, None, Emit::OBJECT) => None,
+    (false, None, _) => Some("firmware.su".to_owned())
+  };
+  let exe = match assemble(&mut sources, &options) {
+    Ok(s) => s,
+    Err(e) => report(&sources, e)
+  };
+  warnings(&sources, &exe.warnings);
+  if print_removed {
+    for removed in &exe.removed {
+      let file = &sources.get(removed.loc.file).name;
+      eprintln!("blc: removed unused {} {} ({file}:{})", removed.kind, removed.name, removed.loc.line);
+    }
+  }
+  if let Some(report) = &exe.optimized {
+    let changes = report.lines();
+    match changes.len() {
+      0 => eprintln!("blc: -O found nothing to change"),
+      _ => eprintln!("blc: -O {}", changes.join(", "))
+    }
+  }
+  let mut map_text: Option<String> = None;
+  let out = match (emit, &exe.image) {
+    (Emit::LISTING, Some(image)) => emit::listing(&exe.unit, image, &sources).into_bytes(),
+    (Emit::SYMBOLS, Some(image)) => link::map(image).into_bytes(),
+    (Emit::HEX, Some(image)) => emit::hex(&image.bytes).into_bytes(),
+    (_, Some(image)) => {
+      map_text = Some(link::map(image));
+      exe.bytes
+    }
+    (_, None) => exe.bytes
+  };
+  let inputs = &options.inputs;
+  let path = match output {
+    Some(s) => s.to_owned(),
+    None => Path::new(&inputs[0]).with_extension(emit.extension()).display().to_string()
+  };
+  let res = match path.as_str() {
+    "-" => io::stdout().write_all(&out),
+    _ => fs::write(&path, &out)
+  };
+  if let Err(e) = res {
+    eprintln!("blc: Failed to write {path} : {e}");
+    process::exit(EXIT_IO);
+  }
+  // The symbol map goes next to the executable, e.g hello.out and hello.map
+  if let (Some(text), true, false) = (map_text, map, path == "-") {
+    let map_path = Path::new(&path).with_extension("map").display().to_string();
+    if let Err(e) = fs::write(&map_path, text) {
+      eprintln!("blc: Failed to write {map_path} : {e}");
+      process::exit(EXIT_IO);
+    }
   }
 }
@@ -0,0 +1,14 @@
+// The assembler, blc itself is a thin command line around it
+pub mod assemble;
+pub mod codegen;
+pub mod diag;
+pub mod emit;
+pub mod expr;
+pub mod file;
+pub mod gc;
+pub mod lint;
+pub mod macros;
+pub mod parser;
+pub mod peephole;
+pub mod r#proc;
+pub mod sema;
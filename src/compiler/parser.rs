@@ -1,229 +1,192 @@
-use crate::file::Handle;
-use crate::r#proc::{line_split, Token};
-#[derive(Debug, PartialEq, Copy, Clone)]
-pub enum Instruction {
-    NOP = 0,
-    MOV = 1,
-    ADD = 2,
-    SUB = 3,
-    MUL = 4,
-    DIV = 5,
-    MOD = 6,
-    OR = 7,
-    AND = 8,
-    XOR = 9,
-    SHL = 10,
-    SHR = 11,
-    JMP = 12,
-    JE = 13,
-    JNE = 14,
-    JGE = 15,
-    JGT = 16,
-    JLE = 17,
-    JLT = 18,
-    CALL = 19,
-    FMOV = 20,
-    FADD = 21,
-    FSUB = 22,
-    FMUL = 23,
-    FDIV = 24,
-    FMOD = 25,
-    INC = 26,
-    DEC = 27,
-    FINC = 28,
-    FDEC = 29,
-    SET = 30,
-    CLEAR = 31,
-    FPUSH = 32,
-    FPOP = 33,
-    PUSH = 34,
-    POP = 35,
-    LEA = 36,
-    RET = 37,
-    CMP = 38,
-    FCMP = 39,
-    SETEX = 40,
-    SETFLAGS = 41,
-    AGDT = 42,
-    SYSCALL = 50,
-}
-
-impl Instruction {
-    pub fn new(name: String) -> Result<(Self, usize), &'static str> {
-        match name.as_ref() {
-            "nop" | "NOP" => Ok((Instruction::NOP, 1)),
-            "mov" | "MOV" => Ok((Instruction::MOV, 3)),
-            "add" | "ADD" => Ok((Instruction::ADD, 4)),
-            "sub" | "SUB" => Ok((Instruction::SUB, 4)),
-            "mul" | "MUL" => Ok((Instruction::MUL, 4)),
-            "div" | "DIV" => Ok((Instruction::DIV, 4)),
-            "mod" | "MOD" => Ok((Instruction::MOD, 4)),
-            "or" | "OR" => Ok((Instruction::OR, 4)),
-            "and" | "AND" => Ok((Instruction::AND, 4)),
-            "xor" | "XOR" => Ok((Instruction::XOR, 4)),
-            "shl" | "SHL" => Ok((Instruction::SHL, 4)),
-            "shr" | "SHR" => Ok((Instruction::SHR, 4)),
-            "jmp" | "JMP" => Ok((Instruction::JMP, 2)),
-            "je" | "JE" => Ok((Instruction::JE, 2)),
-            "jne" | "JNE" => Ok((Instruction::JNE, 2)),
-            "jge" | "JGE" => Ok((Instruction::JGE, 2)),
-            "jgt" | "JGT" => Ok((Instruction::JGT, 2)),
-            "jle" | "JLE" => Ok((Instruction::JLE, 2)),
-            "jlt" | "JLT" => Ok((Instruction::JLT, 2)),
-            "call" | "CALL" => Ok((Instruction::CALL, 2)),
-            "fmov" | "FMOV" => Ok((Instruction::FMOV, 3)),
-            "fadd" | "FADD" => Ok((Instruction::FADD, 4)),
-            "fsub" | "FSUB" => Ok((Instruction::FSUB, 4)),
-            "fmul" | "FMUL" => Ok((Instruction::FMUL, 4)),
-            "fdiv" | "FDIV" => Ok((Instruction::FDIV, 4)),
-            "fmod" | "FMOD" => Ok((Instruction::FMOD, 4)),
-            "inc" | "INC" => Ok((Instruction::INC, 2)),
-            "dec" | "DEC" => Ok((Instruction::DEC, 2)),
-            "finc" | "FINC" => Ok((Instruction::FINC, 2)),
-            "fdec" | "FDEC" => Ok((Instruction::FDEC, 2)),
-            "set" | "SET" => Ok((Instruction::SET, 3)),
-            "clear" | "CLEAR" => Ok((Instruction::CLEAR, 3)),
-            "fpush" | "FPUSH" => Ok((Instruction::FPUSH, 2)),
-            "fpop" | "FPOP" => Ok((Instruction::FPOP, 2)),
-            "push" | "PUSH" => Ok((Instruction::PUSH, 2)),
-            "pop" | "POP" => Ok((Instruction::POP, 2)),
-            "lea" | "LEA" => Ok((Instruction::LEA, 3)),
-            "ret" | "RET" => Ok((Instruction::RET, 1)),
-            "cmp" | "CMP" => Ok((Instruction::CMP, 3)),
-            "fcmp" | "FCMP" => Ok((Instruction::FCMP, 3)),
-            "setex" | "SETEX" => Ok((Instruction::SETEX, 2)),
-            "setflags" | "SETFLAGS" => Ok((Instruction::SETFLAGS, 1)),
-            "agdt" | "AGDT" => Ok((Instruction::AGDT, 4)),
-            "syscall" | "SYSCALL" => Ok((Instruction::SYSCALL, 2)),
-            _ => Err("Invalid instruction"),
-        }
-    }
-
-    pub fn is_farg_nreg(&self) -> bool {
-        match self {
-            Instruction::JE
-            | Instruction::JMP
-            | Instruction::JNE
-            | Instruction::JLT
-            | Instruction::JLE
-            | Instruction::JGT
-            | Instruction::JGE
-            | Instruction::CALL
-            | Instruction::PUSH
-            | Instruction::FPUSH
-            | Instruction::MOV
-            | Instruction::INC
-            | Instruction::SETEX
-            | Instruction::SYSCALL
-            | Instruction::AGDT
-            | Instruction::DEC => true,
-            _ => false,
-        }
-    }
-}
-
+use crate::compiler::diag::{Diagnostic, Loc};
+use crate::compiler::expr::{self, Expr};
+use crate::compiler::file::Sources;
+use crate::compiler::macros::{Macro, MAX_DEPTH};
+use crate::compiler::r#proc::{Lexeme, Lexer, Token};
+use crate::isa::{self, register, Instruction, Operand};
+use std::path::{Path, PathBuf};
 #[derive(PartialEq, Debug)]
 pub enum Args {
     INT(i64),
     DECIMAL(f64),
-    STRING(String),
     REGISTER(u8),
-    OFFSET(u8, i64),
+    FREGISTER(u8),
+    OFFSET(u8, Expr),
+    // Immediate which depends on the address of a function, label or data
+    EXPR(Expr),
+}
+
+// Location covering a run of tokens, as long as they are on the same line
+pub fn span(toks: &[Lexeme]) -> Loc {
+    let first = toks[0].loc;
+    let last = toks[toks.len() - 1].loc;
+    if first.file != last.file || first.line != last.line {
+        return first;
+    }
+    Loc {
+        len: last.col + last.len - first.col,
+        ..first
+    }
 }
 
 impl Args {
-    pub fn new(token: &Token, defines: &Vec<Define>) -> Result<Self, &'static str> {
-        match token {
-            Token::IDENT(s) => {
-                if s == "sp" {
-                    return Ok(Args::REGISTER(80));
-                }
-                let id = match u8::from_str_radix(&s[1..], 10) {
-                    Ok(s) => s,
-                    Err(..) => 127,
-                };
-                if id >= 20 && id != 127 {
-                    return Err("Invalid register number");
-                }
-                if id != 127 {
-                    if s.starts_with('r') {
-                        return Ok(Args::REGISTER(id + 60));
-                    } else if s.starts_with('b') || s.starts_with('f') {
-                        return Ok(Args::REGISTER(id));
-                    } else if s.starts_with('w') {
-                        return Ok(Args::REGISTER(id + 20));
-                    } else if s.starts_with('d') {
-                        return Ok(Args::REGISTER(id + 40));
-                    }
+    // Whether the operand is one of the kinds an instruction allows. An
+    // integer may be used as an address and an address as an integer
+    fn fits(&self, allowed: &[Operand]) -> bool {
+        allowed.iter().any(|kind| {
+            matches!(
+                (self, kind),
+                (Args::INT(..) | Args::EXPR(..), Operand::IMM | Operand::ADDR)
+                    | (Args::DECIMAL(..), Operand::DECIMAL)
+                    | (Args::REGISTER(..), Operand::REG)
+                    | (Args::FREGISTER(..), Operand::FREG)
+                    | (Args::OFFSET(..), Operand::OFFSET)
+            )
+        })
+    }
+
+    pub fn new(operand: &[Lexeme], defines: &Vec<Define>) -> Result<Self, Diagnostic> {
+        Args::with_depth(operand, defines, 0)
+    }
+
+    fn with_depth(operand: &[Lexeme], defines: &Vec<Define>, depth: usize) -> Result<Self, Diagnostic> {
+        if operand.is_empty() {
+            unreachable!()
+        }
+        let loc = span(operand);
+        match operand {
+            [Lexeme {
+                tok: Token::LBRACKET,
+                ..
+            }, ..] => return Args::offset(operand, defines),
+            [Lexeme {
+                tok: Token::IDENT(s),
+                ..
+            }] => {
+                if let Some(reg) = register(s) {
+                    let kind = match isa::is_float_register(s) {
+                        true => Args::FREGISTER,
+                        false => Args::REGISTER,
+                    };
+                    return reg.map(kind).map_err(|e| Diagnostic::new(loc, e));
                 }
-                for def in defines {
-                    if s == &def.0 {
-                        return Args::new(&def.1, defines);
+                // A define may stand for any kind of operand, not just numbers
+                if let Some(def) = defines.iter().rev().find(|d| &d.0 == s) {
+                    if depth >= expr::MAX_DEPTH {
+                        return Err(Diagnostic::new(loc, &format!("Define {s} refers to itself")));
                     }
+                    return Args::with_depth(&def.1, defines, depth + 1);
                 }
-                return Ok(Args::STRING(s.to_string()));
             }
-            Token::OFFSET(s) => {
-                let mut arg = s.to_string();
-                arg.push(' ');
-                let split = match line_split(&arg.as_bytes()) {
-                    Ok(s) => s,
-                    Err(..) => unreachable!(),
-                };
-                let reg;
-                match &split[0] {
-                    Token::IDENT(..) => match Args::new(&split[0], defines)? {
-                        Args::REGISTER(r) => reg = r,
-                        _ => return Err("First argument to offset must be register"),
-                    },
-                    _ => return Err("First argument to offset must be register"),
-                }
+            [Lexeme {
+                tok: Token::DECIMAL(d),
+                ..
+            }] => return Ok(Args::DECIMAL(*d)),
+            [Lexeme {
+                tok: Token::MINUS, ..
+            }, Lexeme {
+                tok: Token::DECIMAL(d),
+                ..
+            }] => return Ok(Args::DECIMAL(-d)),
+            [Lexeme {
+                tok: Token::STRING(..),
+                ..
+            }] => return Err(Diagnostic::new(loc, "Strings cannot be used as operands")),
+            // Most likely a missing comma as in mov r0 r1
+            [Lexeme {
+                tok: Token::IDENT(s),
+                ..
+            }, second, ..]
+                if register(s).is_some() =>
+            {
+                return Err(Diagnostic::new(second.loc, "Expected ',' between operands"))
+            }
+            _ => {}
+        }
+        let expr = expr::parse(operand, defines)?;
+        match expr.has_symbols() {
+            true => Ok(Args::EXPR(expr)),
+            false => Ok(Args::INT(expr.constant()?)),
+        }
+    }
 
-                if split.len() == 1 {
-                    return Ok(Args::OFFSET(reg, 0));
-                }
+    // Offsets are written as [reg], [reg + expr] or [reg - expr]
+    fn offset(operand: &[Lexeme], defines: &Vec<Define>) -> Result<Self, Diagnostic> {
+        let last = &operand[operand.len() - 1];
+        if operand.len() == 1 || last.tok != Token::RBRACKET {
+            return Err(Diagnostic::new(operand[0].loc, "Unclosed offset"));
+        }
+        let inner = &operand[1..operand.len() - 1];
+        if inner.is_empty() {
+            return Err(Diagnostic::new(span(operand), "Empty offset"));
+        }
+        for lexeme in inner {
+            if lexeme.tok == Token::LBRACKET {
+                return Err(Diagnostic::new(lexeme.loc, "Nested offsets are not allowed"));
+            }
+        }
+        let reg = match Args::new(&inner[..1], defines) {
+            Ok(Args::REGISTER(r)) => r,
+            Ok(Args::FREGISTER(..)) => {
+                return Err(Diagnostic::new(
+                    inner[0].loc,
+                    "Floating point registers cannot be used in offsets",
+                ))
+            }
+            _ => {
+                return Err(Diagnostic::new(
+                    inner[0].loc,
+                    "First argument to offset must be register",
+                ))
+            }
+        };
 
-                if split.len() < 3 {
-                    panic!("Offset argument incomplete");
-                }
+        if inner.len() == 1 {
+            return Ok(Args::OFFSET(reg, Expr::NUM(0)));
+        }
 
-                let number = match &split[2] {
-                    Token::INT(s) => *s,
-                    _ => panic!("Offset must be int"),
-                };
-                match &split[1] {
-                    Token::PLUS => {
-                        return Ok(Args::OFFSET(reg, number));
-                    }
-                    Token::MINUS => {
-                        return Ok(Args::OFFSET(reg, -number));
-                    }
-                    _ => return Err("Only operator + or - allowed in offset"),
+        if inner.len() < 3 {
+            return Err(Diagnostic::new(span(inner), "Offset argument incomplete"));
+        }
+
+        match &inner[1].tok {
+            // The sign is parsed along with the rest so that [r0 - 8 + 4] is r0 - 4
+            Token::PLUS | Token::MINUS => {
+                let expr = expr::parse(&inner[1..], defines)?;
+                match expr.has_symbols() {
+                    true => Ok(Args::OFFSET(reg, expr)),
+                    false => Ok(Args::OFFSET(reg, Expr::NUM(expr.constant()?))),
                 }
             }
-            Token::INT(i) => Ok(Args::INT(*i)),
-            Token::DECIMAL(j) => Ok(Args::DECIMAL(*j)),
-            Token::STRING(str) => Ok(Args::STRING(str.to_owned())),
-            _ => Err("Illegal argument to instruction"),
+            _ => Err(Diagnostic::new(
+                inner[1].loc,
+                "Only operator + or - allowed in offset",
+            )),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct Instr {
-    pub name: Instruction,
-    pub len: usize,
+    pub def: &'static Instruction,
     pub args: Option<Vec<Args>>,
+    pub loc: Loc,
+    pub arg_locs: Vec<Loc>,
+    // Line the instruction came from, the outermost macro call for
+    // instructions produced by a macro
+    pub site: Loc,
 }
 
 impl Instr {
-    pub fn new(name: String) -> Result<Self, &'static str> {
-        let (ins, len) = Instruction::new(name)?;
+    pub fn new(name: String, loc: Loc) -> Result<Self, &'static str> {
+        let def = isa::by_name(&name).ok_or("Invalid instruction")?;
         Ok(Self {
-            name: ins,
-            len,
+            def,
+            loc,
+            arg_locs: Vec::new(),
+            site: loc,
             args: {
-                if len != 1 {
+                if !def.operands.is_empty() {
                     Some(Vec::<Args>::new())
                 } else {
                     None
@@ -232,18 +195,43 @@ impl Instr {
         })
     }
 
-    pub fn add_args(&mut self, args: &[Token], defines: &Vec<Define>) -> Result<(), &'static str> {
-        if args.len() + 1 != self.len {
-            return Err("Instruction has been given more or less arguments than needed");
+    // Operands are separated by commas, each one may span several tokens
+    pub fn add_args(&mut self, args: &[Lexeme], defines: &Vec<Define>) -> Result<(), Diagnostic> {
+        let operands: Vec<&[Lexeme]> = match args.len() {
+            0 => Vec::new(),
+            _ => args.split(|lexeme| lexeme.tok == Token::COMMA).collect(),
+        };
+        for operand in &operands {
+            if operand.is_empty() {
+                return Err(Diagnostic::new(span(args), "Expected operand between ','"));
+            }
+            let ar = Args::new(operand, defines)?;
+            self.args.get_or_insert_with(Vec::new).push(ar);
+            self.arg_locs.push(span(operand));
         }
-        for arg in args {
-            let ar = Args::new(arg, defines)?;
-            self.args.as_mut().unwrap().push(ar);
+        if operands.len() != self.def.operands.len() {
+            let loc = match args.len() {
+                0 => self.loc,
+                _ => span(args),
+            };
+            return Err(Diagnostic::new(
+                loc,
+                "Instruction has been given more or less arguments than needed",
+            ));
         }
-        if !self.name.is_farg_nreg() {
-            match self.args.as_ref().unwrap()[0] {
-                Args::REGISTER(..) => {}
-                _ => return Err("First argument to instruction must be a register"),
+        let args = self.args.iter().flatten();
+        for (index, (arg, allowed)) in args.zip(self.def.operands).enumerate() {
+            if !arg.fits(allowed) {
+                let kinds: Vec<&str> = allowed.iter().map(|o| o.describe()).collect();
+                let expected = match kinds.split_last() {
+                    Some((last, [])) => last.to_string(),
+                    Some((last, rest)) => format!("{} or {last}", rest.join(", ")),
+                    None => unreachable!(),
+                };
+                return Err(Diagnostic::new(
+                    self.arg_locs[index],
+                    &format!("Operand {} of {} must be {expected}", index + 1, self.def.mnemonic),
+                ));
             }
         }
         Ok(())
@@ -266,15 +254,26 @@ impl Attr {
     }
 }
 
+// Labels belong to the function they are declared in, index is the
+// instruction they point to
+#[derive(Debug)]
+pub struct Label {
+    pub name: String,
+    pub index: usize,
+    pub loc: Loc,
+}
+
 #[derive(Debug)]
 pub struct Function {
     pub name: String,
     pub ins: Vec<Instr>,
     pub attrs: Option<Vec<Attr>>,
+    pub labels: Vec<Label>,
+    pub loc: Loc,
 }
 
 impl Function {
-    pub fn new(func: &Token) -> Result<Self, &'static str> {
+    pub fn new(func: &Token, loc: Loc) -> Result<Self, &'static str> {
         let name = match func {
             Token::IDENT(s) => s.to_string(),
             _ => return Err("Expected identifier after keyword 'func'"),
@@ -283,182 +282,664 @@ impl Function {
             name,
             ins: Vec::new(),
             attrs: Some(Vec::new()),
+            labels: Vec::new(),
+            loc,
         })
     }
 
     pub fn add_ins(&mut self, ins: Instr) {
         self.ins.push(ins)
     }
+
+    // Numeric labels such as '1:' may be redefined, they are told apart
+    // with 1f and 1b
+    pub fn add_label(&mut self, name: &str, loc: Loc) -> Result<(), &'static str> {
+        let numeric = name.chars().all(|c| c.is_ascii_digit());
+        if !numeric && self.labels.iter().any(|l| l.name == name) {
+            return Err("Label is already defined in this function");
+        }
+        self.labels.push(Label {
+            name: name.to_owned(),
+            index: self.ins.len(),
+            loc,
+        });
+        Ok(())
+    }
 }
 
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Type {
     BYTE,
     SHORT,
     INT,
     LONG,
+    DOUBLE,
+    // NUL terminated
     STRING,
+    ASCII,
+    ZERO,
+    ALIGN,
 }
 
-pub struct Define(pub String, pub Token);
+impl Type {
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "byte" => Some(Type::BYTE),
+            "short" => Some(Type::SHORT),
+            "int" => Some(Type::INT),
+            "long" => Some(Type::LONG),
+            "double" => Some(Type::DOUBLE),
+            "string" => Some(Type::STRING),
+            "ascii" => Some(Type::ASCII),
+            "zero" | "space" => Some(Type::ZERO),
+            "align" => Some(Type::ALIGN),
+            _ => None,
+        }
+    }
+
+    // Size of one element, strings and padding have no fixed size
+    pub fn width(&self) -> usize {
+        match self {
+            Type::BYTE => 1,
+            Type::SHORT => 2,
+            Type::INT => 4,
+            Type::LONG | Type::DOUBLE => 8,
+            _ => 1,
+        }
+    }
+}
 
-pub struct Data(pub Type, pub String, pub Token);
+#[derive(Debug)]
+pub enum Value {
+    // Integers may refer to functions, labels and other data
+    EXPR(Expr, Loc),
+    DECIMAL(f64),
+    STRING(String),
+    // Byte count of .zero and .align
+    COUNT(usize),
+}
+
+// Size of the data segment set up by firmware.su, no .zero or .align
+// can be larger than that
+pub const MAX_COUNT: i64 = 0x80000;
 
-pub struct Parser {
+// Integers may be given as signed or unsigned e.g .byte -1 or .byte 255
+pub fn fits(value: i64, ty: Type) -> Result<(), &'static str> {
+    let bits = ty.width() * 8;
+    if bits == 64 || (value >= -(1 << (bits - 1)) && value < 1 << bits) {
+        return Ok(());
+    }
+    match ty {
+        Type::BYTE => Err("Value does not fit in a byte"),
+        Type::SHORT => Err("Value does not fit in a short"),
+        _ => Err("Value does not fit in an int"),
+    }
+}
+
+// Defines are kept as tokens, they may hold an expression, a register or
+// any other single operand
+#[derive(Clone)]
+pub struct Define(pub String, pub Vec<Lexeme>);
+
+// A data directive, with or without a label in front e.g
+//
+// table: .long 1, 2, main
+// .align 8
+pub struct Data(pub Type, pub Option<String>, pub Vec<Value>, pub Loc);
+
+// An if, ifdef or ifndef block which has not seen its endif yet
+struct Cond {
+    // Statements of the current branch are assembled
+    active: bool,
+    // One of the branches has been chosen already
+    taken: bool,
+    else_seen: bool,
+    loc: Loc,
+}
+
+pub struct Parser<'a> {
     pub define: Vec<Define>,
     pub funcs: Vec<Function>,
     pub data: Vec<Data>,
+    pub macros: Vec<Macro>,
+    // Symbols named by 'local', they are not visible to other objects
+    pub locals: Vec<(String, Loc)>,
+    // Files entered from the top rather than included
+    pub roots: Vec<usize>,
+    pub diags: Vec<Diagnostic>,
+    sources: &'a mut Sources,
     state: u8,
+    // Macro whose body is being read
+    recording: Option<Macro>,
+    expansions: usize,
+    depth: usize,
+    // Call of the macro being expanded, if any
+    site: Option<Loc>,
+    conds: Vec<Cond>,
+    // Directories given with -I, searched after the including file's own
+    pub search: Vec<PathBuf>,
+    // Every file read so far, each one is only included once
+    included: Vec<PathBuf>,
+    // Files currently being parsed along with the include which opened them
+    chain: Vec<(PathBuf, String, Option<Loc>)>,
 }
 
-impl Parser {
-    pub fn new() -> Self {
+impl<'a> Parser<'a> {
+    pub fn new(sources: &'a mut Sources) -> Self {
         Self {
             define: Vec::new(),
             funcs: Vec::new(),
             data: Vec::new(),
+            macros: Vec::new(),
+            locals: Vec::new(),
+            roots: Vec::new(),
+            diags: Vec::new(),
+            sources,
             state: 0u8,
+            recording: None,
+            expansions: 0,
+            depth: 0,
+            site: None,
+            conds: Vec::new(),
+            search: Vec::new(),
+            included: Vec::new(),
+            chain: Vec::new(),
+        }
+    }
+
+    // Errors do not stop parsing, they are recorded and the parser
+    // carries on from the next statement
+    fn parse_file(&mut self, id: usize) {
+        let lexer = Lexer::new(id, &self.sources.get(id).text);
+        let mut stmt: Vec<Lexeme> = Vec::new();
+        for lexeme in lexer {
+            match lexeme {
+                Ok(lexeme) if lexeme.tok == Token::NEWLINE => {
+                    let target = std::mem::take(&mut stmt);
+                    self.parse_reported(target);
+                }
+                Ok(lexeme) => stmt.push(lexeme),
+                // The statement is still parsed, so that a broken func or
+                // if line opens its block and the end or endif matches up
+                Err(e) => {
+                    if let Some(loc) = e.loc {
+                        stmt.push(Lexeme { tok: Token::ERROR, loc });
+                    }
+                    self.diags.push(e);
+                }
+            }
+        }
+        if let Some(mac) = self.recording.take() {
+            self.diags
+                .push(Diagnostic::new(mac.loc, "Macro is missing a matching 'endm'"));
+        }
+        for cond in std::mem::take(&mut self.conds) {
+            self.diags
+                .push(Diagnostic::new(cond.loc, "Block is missing a matching 'endif'"));
+        }
+        // Otherwise the rest of the includer would end up in this function
+        if self.state == 2 {
+            let func = &self.funcs[self.funcs.len() - 1];
+            self.diags
+                .push(Diagnostic::new(func.loc, "Function is missing a matching 'end'"));
+            self.state = 0;
+        }
+    }
+
+    // Relative names are looked up next to the including file and then
+    // in every -I directory, in the order they were given
+    fn resolve(&self, name: &str, loc: Option<Loc>) -> Option<PathBuf> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return Some(path.to_path_buf());
+        }
+        let base = match loc {
+            Some(l) => Path::new(&self.sources.get(l.file).name)
+                .parent()
+                .unwrap_or(Path::new(""))
+                .to_path_buf(),
+            None => PathBuf::new(),
+        };
+        std::iter::once(&base)
+            .chain(self.search.iter())
+            .map(|dir| dir.join(path))
+            .find(|candidate| self.sources.exists(candidate))
+    }
+
+    // A statement with a token the lexer could not read has been reported
+    // already, whatever else is wrong with it follows from that
+    fn parse_reported(&mut self, target: Vec<Lexeme>) {
+        let broken = target.iter().any(|l| l.tok == Token::ERROR);
+        if let Err(e) = self.parse(target) {
+            if !broken {
+                self.diags.push(e);
+            }
+        }
+    }
+
+    pub fn include(&mut self, name: &str, loc: Option<Loc>) {
+        match self.resolve(name, loc) {
+            Some(path) => self.enter(&path, loc),
+            None => {
+                let e = format!("Could not find include file {name}");
+                self.diags.push(match loc {
+                    Some(l) => Diagnostic::new(l, &e),
+                    None => Diagnostic::bare(&e),
+                });
+            }
+        }
+    }
+
+    // Parses a file in place, so whatever it declares lands in this parser
+    // and the rest of the includer sees its defines and macros
+    pub fn enter(&mut self, path: &Path, loc: Option<Loc>) {
+        let canonical = self.sources.canonical(path);
+        if let Some(pos) = self.chain.iter().position(|c| c.0 == canonical) {
+            let mut names: Vec<String> = self.chain[pos..]
+                .iter()
+                .map(|c| c.1.clone())
+                .collect();
+            names.push(path.display().to_string());
+            let mut diag = Diagnostic::bare(&format!("Include cycle {}", names.join(" -> ")));
+            diag.loc = loc;
+            for (_, _, from) in self.chain[pos + 1..].iter().rev() {
+                if let Some(l) = from {
+                    diag = diag.with_note(*l, "Included from here");
+                }
+            }
+            self.diags.push(diag);
+            return;
+        }
+        if self.included.contains(&canonical) {
+            return;
+        }
+        let name = path.display().to_string();
+        let id = match self.sources.load(&name) {
+            Ok(s) => s,
+            Err(e) => {
+                self.diags.push(match loc {
+                    Some(l) => Diagnostic::new(l, &e),
+                    None => Diagnostic::bare(&e),
+                });
+                return;
+            }
+        };
+        if loc.is_none() {
+            self.roots.push(id);
+        }
+        self.included.push(canonical.clone());
+        self.chain.push((canonical, name, loc));
+        // Conditional blocks cannot span files
+        let conds = std::mem::take(&mut self.conds);
+        self.parse_file(id);
+        self.conds = conds;
+        self.chain.pop();
+    }
+
+    fn conditional(&mut self, target: &[Lexeme]) -> Result<(), Diagnostic> {
+        let first = &target[0];
+        let enclosing = self.conds.iter().all(|c| c.active);
+        match first.tok {
+            Token::IFDEF | Token::IFNDEF => {
+                let cond = match target.get(1) {
+                    Some(Lexeme {
+                        tok: Token::IDENT(name),
+                        ..
+                    }) => Parser::expect_end(target, 2).map(|_| {
+                        let defined = self.define.iter().any(|d| &d.0 == name);
+                        defined == (first.tok == Token::IFDEF)
+                    }),
+                    Some(s) => Err(Diagnostic::new(s.loc, "Expected identifier here")),
+                    None => Err(Diagnostic::new(first.loc, "Expected identifier here")),
+                };
+                self.push_cond(cond, enclosing, first.loc)
+            }
+            Token::IF => {
+                // Conditions inside skipped blocks are not evaluated as they
+                // may use defines which only exist in another configuration
+                let cond = match enclosing {
+                    true => self.condition(&target[1..], first.loc),
+                    false => Ok(false),
+                };
+                self.push_cond(cond, enclosing, first.loc)
+            }
+            Token::ELSE => {
+                let len = self.conds.len();
+                let enclosing = len > 0 && self.conds[..len - 1].iter().all(|c| c.active);
+                let cond = match self.conds.last_mut() {
+                    Some(s) => s,
+                    None => return Err(Diagnostic::new(first.loc, "'else' without a matching 'if'")),
+                };
+                if cond.else_seen {
+                    return Err(Diagnostic::new(first.loc, "Block already has an 'else'"));
+                }
+                cond.else_seen = true;
+                cond.active = enclosing && !cond.taken;
+                cond.taken = true;
+                Parser::expect_end(target, 1)
+            }
+            _ => match self.conds.pop() {
+                Some(..) => Parser::expect_end(target, 1),
+                None => Err(Diagnostic::new(first.loc, "'endif' without a matching 'if'")),
+            },
         }
     }
 
-    pub fn parse(&mut self, target: Vec<Token>) -> Result<(), &str> {
-        let mut inlabel = false;
-        let label;
-        if target.len() == 0 {
+    // A broken condition still opens a block so that its endif matches up
+    fn push_cond(&mut self, cond: Result<bool, Diagnostic>, enclosing: bool, loc: Loc) -> Result<(), Diagnostic> {
+        let value = *cond.as_ref().unwrap_or(&false);
+        self.conds.push(Cond {
+            active: enclosing && value,
+            taken: value || cond.is_err(),
+            else_seen: false,
+            loc,
+        });
+        cond.map(|_| ())
+    }
+
+    fn condition(&self, expr: &[Lexeme], loc: Loc) -> Result<bool, Diagnostic> {
+        if expr.is_empty() {
+            return Err(Diagnostic::new(loc, "Expected condition after 'if'"));
+        }
+        let parsed = expr::parse(expr, &self.define)?;
+        if parsed.has_symbols() {
+            return Err(Diagnostic::new(span(expr), "Condition must be a constant integer"));
+        }
+        Ok(parsed.constant()? != 0)
+    }
+
+    fn invoke(&mut self, index: usize, target: &[Lexeme]) -> Result<(), Diagnostic> {
+        let call = span(target);
+        if self.depth >= MAX_DEPTH {
+            return Err(Diagnostic::new(call, "Macro expansion is nested too deeply"));
+        }
+        self.expansions += 1;
+        let stmts = self.macros[index].expand(&target[1..], call, self.expansions)?;
+        let note = format!("In expansion of macro {}", self.macros[index].name);
+        self.depth += 1;
+        self.site.get_or_insert(call);
+        for stmt in stmts {
+            let broken = stmt.iter().any(|l| l.tok == Token::ERROR);
+            if let Err(e) = self.parse(stmt) {
+                if !broken {
+                    self.diags.push(e.with_note(call, &note));
+                }
+            }
+        }
+        self.depth -= 1;
+        if self.depth == 0 {
+            self.site = None;
+        }
+        Ok(())
+    }
+
+    pub fn parse(&mut self, target: Vec<Lexeme>) -> Result<(), Diagnostic> {
+        if target.is_empty() {
+            return Ok(());
+        }
+        let first = &target[0];
+        // Macro bodies are kept as they are, conditionals inside them are
+        // evaluated each time the macro is expanded
+        if let Some(mac) = self.recording.as_mut() {
+            match first.tok {
+                Token::ENDMACRO => {
+                    let mac = self.recording.take().unwrap();
+                    // The header was reported, only the body had to be skipped
+                    if mac.name.is_empty() {
+                        return Parser::expect_end(&target, 1);
+                    }
+                    if self.macros.iter().any(|m| m.name == mac.name) {
+                        return Err(Diagnostic::new(mac.loc, "Macro is already defined"));
+                    }
+                    self.macros.push(mac);
+                    Parser::expect_end(&target, 1)?;
+                }
+                Token::MACRO => {
+                    return Err(Diagnostic::new(first.loc, "Macros cannot be defined inside macros"))
+                }
+                _ => mac.body.push(target),
+            }
+            return Ok(());
+        }
+        match first.tok {
+            Token::IF | Token::IFDEF | Token::IFNDEF | Token::ELSE | Token::ENDIF => {
+                return self.conditional(&target)
+            }
+            _ => {}
+        }
+        if !self.conds.iter().all(|c| c.active) {
             return Ok(());
         }
+        match &first.tok {
+            Token::MACRO => {
+                // A macro with a broken header still takes everything up to
+                // its endm, which would otherwise be parsed as code
+                return match Macro::new(&target) {
+                    Ok(mac) => {
+                        self.recording = Some(mac);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.recording = Some(Macro { name: String::new(), params: Vec::new(), body: Vec::new(), loc: first.loc });
+                        Err(e)
+                    }
+                };
+            }
+            Token::ENDMACRO => {
+                return Err(Diagnostic::new(first.loc, "'endm' without a matching 'macro'"))
+            }
+            Token::IDENT(s) => {
+                if let Some(index) = self.macros.iter().position(|m| &m.name == s) {
+                    return self.invoke(index, &target);
+                }
+            }
+            _ => {}
+        }
         if self.state == 0 {
-            match &target[0] {
-                Token::LABEL(s) => match &target[1] {
-                    Token::ATTR(at) => match &target[2] {
-                        Token::INT(..) => match at.as_ref() {
-                            "byte" => {
-                                self.data
-                                    .push(Data(Type::BYTE, s.to_string(), target[2].clone()))
-                            }
-                            "short" => {
-                                self.data
-                                    .push(Data(Type::SHORT, s.to_string(), target[2].clone()))
-                            }
-                            "int" => {
-                                self.data
-                                    .push(Data(Type::INT, s.to_string(), target[2].clone()))
-                            }
-                            "long" => {
-                                self.data
-                                    .push(Data(Type::LONG, s.to_string(), target[2].clone()))
-                            }
-                            _ => return Err("Unknown data type modifier"),
-                        },
-                        Token::STRING(..) => match at.as_ref() {
-                            "string" => self.data.push(Data(
-                                Type::STRING,
-                                s.to_string(),
-                                target[2].clone(),
-                            )),
-                            _ => return Err("Only type modifier 'string' is allowed for strings"),
-                        },
-                        _ => return Err("Expected value for data member here")
-                    },
-                    _ => return Err("Expected data declaration here"),
+            match &first.tok {
+                Token::LABEL(s) => match target.get(1) {
+                    Some(Lexeme {
+                        tok: Token::ATTR(..),
+                        ..
+                    }) => {
+                        let data = self.data_directive(&target[1..], Some(s.to_string()), first.loc)?;
+                        self.data.push(data);
+                    }
+                    _ => return Err(Diagnostic::new(first.loc, "Expected data declaration here")),
                 },
+                Token::ATTR(..) => {
+                    let data = self.data_directive(&target, None, first.loc)?;
+                    self.data.push(data);
+                }
                 Token::FUNC => {
                     self.state = 2;
-                    if target.len() < 2 {
-                        return Err("Expected identifier after keyword 'func'");
-                    }
-                    match Function::new(&target[1]) {
+                    let func = match target.get(1) {
+                        Some(name) => Function::new(&name.tok, name.loc).map_err(|e| Diagnostic::new(name.loc, e)),
+                        None => Err(Diagnostic::new(first.loc, "Expected identifier after keyword 'func'")),
+                    };
+                    match func {
                         Ok(s) => self.funcs.push(s),
-                        Err(e) => return Err(e),
+                        Err(e) => {
+                            // Still enter the body so that errors inside it are reported
+                            self.funcs.push(Function::new(&Token::IDENT(String::new()), first.loc).unwrap());
+                            return Err(e);
+                        }
                     }
+                    Parser::expect_end(&target, 2)?;
                 }
                 Token::INCLUDE => {
-                    if target.len() < 2 {
-                        return Err("include keyword has to be followed by a file name");
-                    }
-                    let name = match &target[1] {
-                        Token::STRING(s) => s,
-                        _ => {
-                            return Err(
-                                "Expected string representing filename after define keyword",
-                            )
-                        }
-                    };
-                    let mut src = Handle::new(name);
-                    let mut parser = Parser::new();
-                    loop {
-                        let mut line = src.read_line();
-                        if &line == "EOF" {
-                            break;
+                    let name = match target.get(1) {
+                        Some(Lexeme {
+                            tok: Token::STRING(s),
+                            ..
+                        }) => s,
+                        Some(s) => {
+                            return Err(Diagnostic::new(
+                                s.loc,
+                                "Expected string representing filename after include keyword",
+                            ))
                         }
-                        line.push(' ');
-                        let split = match line_split(line.as_bytes()) {
-                            Ok(s) => s,
-                            Err(e) => src.error(e),
-                        };
-                        match parser.parse(split) {
-                            Ok(..) => {}
-                            Err(e) => src.error(e),
+                        None => {
+                            return Err(Diagnostic::new(
+                                first.loc,
+                                "include keyword has to be followed by a file name",
+                            ))
                         }
-                    }
-                    self.define.append(&mut parser.define);
-                    self.funcs.append(&mut parser.funcs);
+                    };
+                    Parser::expect_end(&target, 2)?;
+                    self.include(&name.clone(), Some(span(&target)));
                 }
                 Token::DEFINE => {
                     if target.len() < 3 {
-                        return Err(
+                        return Err(Diagnostic::new(
+                            span(&target),
                             "define keyword has to be followed by an identifier and a value",
-                        );
+                        ));
                     }
-                    let name = match &target[1] {
+                    let name = match &target[1].tok {
                         Token::IDENT(s) => s.to_string(),
-                        _ => return Err("Expected identifier after define keyword"),
+                        _ => return Err(Diagnostic::new(target[1].loc, "Expected identifier after define keyword")),
                     };
-                    match &target[2] {
-                        Token::INT(..)
-                        | Token::DECIMAL(..)
-                        | Token::STRING(..)
-                        | Token::IDENT(..) => self.define.push(Define(name, target[2].clone())),
-                        _ => return Err("Expected value here"),
+                    self.define.push(Define(name, target[2..].to_vec()));
+                }
+                // local name1, name2
+                Token::IDENT(s) if s == "local" => {
+                    if target.len() == 1 {
+                        return Err(Diagnostic::new(first.loc, "Expected symbol names after local"));
+                    }
+                    for part in target[1..].split(|lexeme| lexeme.tok == Token::COMMA) {
+                        match part {
+                            [Lexeme {
+                                tok: Token::IDENT(name),
+                                loc,
+                            }] => self.locals.push((name.to_string(), *loc)),
+                            [] => return Err(Diagnostic::new(first.loc, "Expected symbol name between ','")),
+                            _ => return Err(Diagnostic::new(part[0].loc, "Expected symbol name here")),
+                        }
                     }
                 }
-                _ => return Err("Only functions are allowed at top level"),
+                _ => return Err(Diagnostic::new(first.loc, "Only functions are allowed at top level")),
             }
         } else if self.state == 2 {
-            match &target[0] {
+            match &first.tok {
                 Token::IDENT(s) => {
                     let mut this_func = self.funcs.pop().unwrap();
-                    let mut ins = Instr::new(s.to_owned())?;
-                    if ins.len != 1 {
-                        ins.add_args(&target[1..], &self.define)?;
+                    let mut ins = Instr::new(s.to_owned(), first.loc);
+                    let res = match ins {
+                        Ok(ref mut ins) => {
+                            ins.site = self.site.unwrap_or(ins.loc);
+                            ins.add_args(&target[1..], &self.define)
+                        }
+                        Err(e) => Err(Diagnostic::new(first.loc, e)),
+                    };
+                    if res.is_ok() {
+                        this_func.add_ins(ins.unwrap());
                     }
-                    this_func.add_ins(ins);
                     self.funcs.push(this_func);
+                    res?;
                 }
                 Token::LABEL(s) => {
-                    if inlabel {
-                        label = Function::new(&Token::IDENT(s.to_string()))?;
-                        self.funcs.push(label);
-                    } else {
-                        inlabel = true;
-                        self.funcs
-                            .push(Function::new(&Token::IDENT(s.to_string()))?);
-                    }
+                    let this_func = self.funcs.last_mut().unwrap();
+                    this_func
+                        .add_label(s, first.loc)
+                        .map_err(|e| Diagnostic::new(first.loc, e))?;
+                    // An instruction may follow the label on the same line
+                    return self.parse(target[1..].to_vec());
                 }
                 Token::ATTR(s) => {
                     let mut this_func = self.funcs.pop().unwrap();
-                    let attr = Attr::new(&s, target.get(1).cloned())?;
-                    this_func.attrs.as_mut().unwrap().push(attr);
+                    let attr = Attr::new(s, target.get(1).map(|l| l.tok.clone()));
+                    match attr {
+                        Ok(attr) => this_func.attrs.as_mut().unwrap().push(attr),
+                        Err(e) => {
+                            self.funcs.push(this_func);
+                            return Err(Diagnostic::new(first.loc, e));
+                        }
+                    }
                     self.funcs.push(this_func);
                 }
                 Token::ENDFUNC => {
                     self.state = 0;
-                    if inlabel {
-                        inlabel = false;
-                    }
+                    Parser::expect_end(&target, 1)?;
                 }
-                _ => return Err("Expected instruction name here"),
+                _ => return Err(Diagnostic::new(first.loc, "Expected instruction name here")),
             }
         }
         Ok(())
     }
+
+    // Parses the directive and its comma separated values, target starts
+    // at the directive
+    fn data_directive(&self, target: &[Lexeme], name: Option<String>, loc: Loc) -> Result<Data, Diagnostic> {
+        let directive = &target[0];
+        let ty = match &directive.tok {
+            Token::ATTR(at) => match Type::new(at) {
+                Some(s) => s,
+                None => return Err(Diagnostic::new(directive.loc, "Unknown data type modifier")),
+            },
+            _ => unreachable!(),
+        };
+        if target.len() == 1 {
+            return Err(Diagnostic::new(directive.loc, "Expected value for data member here"));
+        }
+        let mut values: Vec<Value> = Vec::new();
+        for part in target[1..].split(|lexeme| lexeme.tok == Token::COMMA) {
+            if part.is_empty() {
+                return Err(Diagnostic::new(directive.loc, "Expected value between ','"));
+            }
+            let loc = span(part);
+            let value = match ty {
+                Type::STRING | Type::ASCII => match part {
+                    [Lexeme {
+                        tok: Token::STRING(s),
+                        ..
+                    }] => Value::STRING(s.to_string()),
+                    _ => return Err(Diagnostic::new(loc, "Expected string here")),
+                },
+                Type::DOUBLE => match Args::new(part, &self.define)? {
+                    Args::DECIMAL(d) => Value::DECIMAL(d),
+                    Args::INT(i) => Value::DECIMAL(i as f64),
+                    _ => return Err(Diagnostic::new(loc, "Expected floating point number here")),
+                },
+                Type::ZERO | Type::ALIGN => {
+                    if !values.is_empty() {
+                        return Err(Diagnostic::new(loc, "Expected a single byte count"));
+                    }
+                    match Args::new(part, &self.define)? {
+                        Args::INT(0) if ty == Type::ALIGN => {
+                            return Err(Diagnostic::new(loc, "Alignment must be a power of two"))
+                        }
+                        Args::INT(i) if ty == Type::ALIGN && i.count_ones() != 1 => {
+                            return Err(Diagnostic::new(loc, "Alignment must be a power of two"))
+                        }
+                        Args::INT(i) if i > MAX_COUNT => {
+                            return Err(Diagnostic::new(loc, &format!("Byte count must be at most {MAX_COUNT:#x}")))
+                        }
+                        Args::INT(i) if i >= 0 => Value::COUNT(i as usize),
+                        _ => return Err(Diagnostic::new(loc, "Expected a constant byte count here")),
+                    }
+                }
+                _ => match Args::new(part, &self.define)? {
+                    Args::INT(i) => {
+                        fits(i, ty).map_err(|e| Diagnostic::new(loc, e))?;
+                        Value::EXPR(Expr::NUM(i), loc)
+                    }
+                    Args::EXPR(e) => Value::EXPR(e, loc),
+                    Args::DECIMAL(..) => {
+                        return Err(Diagnostic::new(loc, "Floating point values have to be declared with .double"))
+                    }
+                    _ => return Err(Diagnostic::new(loc, "Expected integer value here")),
+                },
+            };
+            values.push(value);
+        }
+        Ok(Data(ty, name, values, loc))
+    }
+
+    fn expect_end(target: &[Lexeme], len: usize) -> Result<(), Diagnostic> {
+        match target.get(len) {
+            Some(..) => Err(Diagnostic::new(span(&target[len..]), "Unexpected tokens at end of statement")),
+            None => Ok(()),
+        }
+    }
 }
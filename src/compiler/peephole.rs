@@ -0,0 +1,183 @@
+use crate::compiler::expr::Expr;
+use crate::compiler::parser::{Args, Function, Instr, Label};
+use crate::compiler::r#proc::Unit;
+use crate::compiler::sema::label;
+use crate::isa::{self, Opcode};
+
+// Simple rewrites of the parsed program done with -O, before anything is
+// encoded. None of them changes what the program does: labels keep
+// pointing at the same code and jumps only skip over other jumps
+
+// How often each rewrite was done, in the order they are reported
+#[derive(Debug, Default)]
+pub struct Report {
+  pub moves: usize,
+  pub increments: usize,
+  pub pairs: usize,
+  pub jumps: usize,
+  pub nops: usize
+}
+
+impl Report {
+  pub fn lines(&self) -> Vec<String> {
+    let counts = [
+      (self.moves, "no-op movs removed"),
+      (self.increments, "adds and subs of 1 made inc or dec"),
+      (self.pairs, "push and pop pairs removed"),
+      (self.jumps, "jumps to jumps shortened"),
+      (self.nops, "nops removed")
+    ];
+    counts.iter().filter(|c| c.0 != 0).map(|c| format!("{}: {}", c.1, c.0)).collect()
+  }
+}
+
+fn is_jump(opcode: Opcode) -> bool {
+  matches!(opcode, Opcode::JMP | Opcode::JE | Opcode::JNE | Opcode::JGE | Opcode::JGT | Opcode::JLE | Opcode::JLT)
+}
+
+// Label a jump goes to, if it is one of the function's own
+fn jump_target(func: &Function, index: usize) -> Option<&Label> {
+  let ins = &func.ins[index];
+  if !is_jump(ins.def.opcode) {
+    return None;
+  }
+  match ins.args.as_deref() {
+    Some([Args::EXPR(Expr::SYM(name, _))]) => label(name, func, index),
+    _ => None
+  }
+}
+
+fn labelled(func: &Function, index: usize) -> bool {
+  func.labels.iter().any(|l| l.index == index)
+}
+
+// Removes an instruction, labels on it move on to the next one
+fn remove(func: &mut Function, index: usize) {
+  func.ins.remove(index);
+  for l in &mut func.labels {
+    if l.index > index {
+      l.index -= 1;
+    }
+  }
+}
+
+// Register numbers of the operands, None for anything else
+fn registers(ins: &Instr) -> Vec<Option<u8>> {
+  ins.args.iter().flatten().map(|a| match a {
+    Args::REGISTER(r) => Some(*r),
+    _ => None
+  }).collect()
+}
+
+fn simplify(func: &mut Function, report: &mut Report) -> bool {
+  let mut index = 0;
+  let mut changed = false;
+  while index < func.ins.len() {
+    let ins = &func.ins[index];
+    let regs = registers(ins);
+    let opcode = ins.def.opcode;
+    if opcode == Opcode::NOP {
+      remove(func, index);
+      report.nops += 1;
+      changed = true;
+      continue;
+    }
+    if opcode == Opcode::MOV && regs.len() == 2 && regs[0].is_some() && regs[0] == regs[1] {
+      remove(func, index);
+      report.moves += 1;
+      changed = true;
+      continue;
+    }
+    // add r1, r1, 1 or add r1, 1, r1
+    let one = |i: usize| matches!(ins.args.as_deref().and_then(|a| a.get(i)), Some(Args::INT(1)));
+    let step = match opcode {
+      Opcode::ADD if regs[0].is_some() && ((regs[1] == regs[0] && one(2)) || (one(1) && regs[2] == regs[0])) => Some("inc"),
+      Opcode::SUB if regs[0].is_some() && regs[1] == regs[0] && one(2) => Some("dec"),
+      _ => None
+    };
+    if let Some(mnemonic) = step {
+      let ins = &mut func.ins[index];
+      ins.def = isa::by_name(mnemonic).unwrap();
+      ins.args.as_mut().unwrap().truncate(1);
+      ins.arg_locs.truncate(1);
+      report.increments += 1;
+      changed = true;
+    }
+    if opcode == Opcode::PUSH && regs[0].is_some() && !labelled(func, index + 1) {
+      if let Some(next) = func.ins.get(index + 1) {
+        if next.def.opcode == Opcode::POP && registers(next)[0] == regs[0] {
+          remove(func, index);
+          remove(func, index);
+          report.pairs += 1;
+          changed = true;
+          continue;
+        }
+      }
+    }
+    index += 1;
+  }
+  changed
+}
+
+// Points jumps which land on 'jmp label' straight at where that goes
+fn thread(func: &mut Function, report: &mut Report) -> bool {
+  let mut rewrites: Vec<(usize, Option<String>, usize)> = Vec::new();
+  for index in 0..func.ins.len() {
+    let first = match jump_target(func, index) {
+      Some(l) => l,
+      None => continue
+    };
+    let mut target = first;
+    let mut seen: Vec<usize> = vec![index];
+    while let Some(next) = func.ins.get(target.index).and_then(|ins| match ins.def.opcode {
+      Opcode::JMP => jump_target(func, target.index),
+      _ => None
+    }) {
+      if seen.contains(&target.index) {
+        break;
+      }
+      seen.push(target.index);
+      target = next;
+    }
+    if target.index != first.index {
+      // Numeric labels cannot be named from everywhere
+      let name = Some(target.name.clone()).filter(|n| !n.chars().all(|c| c.is_ascii_digit()));
+      rewrites.push((index, name, target.index));
+    }
+  }
+  let changed = !rewrites.is_empty();
+  for (index, name, target) in rewrites {
+    // so the jump gets a label of its own, '@' never starts a source name
+    // and jumps to the same place share it
+    let name = name.unwrap_or_else(|| {
+      let generated = func.labels.iter().filter(|l| l.name.starts_with('@'));
+      if let Some(l) = generated.clone().find(|l| l.index == target) {
+        return l.name.clone();
+      }
+      let name = format!("@jump{}", generated.count());
+      let loc = func.ins[target].loc;
+      func.labels.push(Label { name: name.clone(), index: target, loc });
+      name
+    });
+    let ins = &mut func.ins[index];
+    let loc = ins.arg_locs[0];
+    ins.args = Some(vec![Args::EXPR(Expr::SYM(name, loc))]);
+    report.jumps += 1;
+  }
+  changed
+}
+
+pub fn optimize(unit: &mut Unit) -> Report {
+  let mut report = Report::default();
+  for func in &mut unit.funcs {
+    // Every change may make room for another
+    loop {
+      let simplified = simplify(func, &mut report);
+      let threaded = thread(func, &mut report);
+      if !simplified && !threaded {
+        break;
+      }
+    }
+  }
+  report
+}
@@ -1,8 +1,8 @@
-use crate::file::Handle;
-use crate::parser::*;
+use crate::compiler::diag::{Diagnostic, Loc};
+use crate::compiler::file::Sources;
+use crate::compiler::parser::*;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-// This lexer and parser have one inherent limitation
-// They cannot process instructions extended over more than one line
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
@@ -10,6 +10,13 @@ pub enum Token {
   DEFINE,
   INCLUDE,
   ENDFUNC,
+  MACRO,
+  ENDMACRO,
+  IF,
+  IFDEF,
+  IFNDEF,
+  ELSE,
+  ENDIF,
   INT(i64),
   DECIMAL(f64),
   STRING(String),
@@ -18,211 +25,402 @@ pub enum Token {
   ATTR(String),
   PLUS,
   MINUS,
-  OFFSET(String)
+  STAR,
+  SLASH,
+  PERCENT,
+  SHL,
+  SHR,
+  AMP,
+  PIPE,
+  TILDE,
+  EQ,
+  NE,
+  LT,
+  LE,
+  GT,
+  GE,
+  LAND,
+  LOR,
+  LPAREN,
+  RPAREN,
+  COMMA,
+  LBRACKET,
+  RBRACKET,
+  NEWLINE,
+  // Stands in for text the lexer could not read, which has been reported
+  ERROR
 }
 
 impl Token {
-  pub fn new(token: &str) -> Self {
-    let first = token.chars().nth(0);
-    if token.starts_with("0x") {
-      match i64::from_str_radix(&token[2..], 16) {
-        Ok(s) => return Token::INT(s),
-        Err(..) => panic!("Malformed hexadecimal integer")
+  // Classifies a word i.e a run of identifier characters
+  pub fn new(token: &str) -> Result<Self, &'static str> {
+    // References to numeric labels such as 1f or 2b
+    if let Some(number) = token.strip_suffix(|c| c == 'f' || c == 'b') {
+      if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(Token::IDENT(token.to_owned()));
       }
     }
-    if token.starts_with("0b") {
-      match i64::from_str_radix(&token[2..], 2) {
-        Ok(s) => return Token::INT(s),
-        Err(..) => panic!("Malformed binary integer")
+    if let Some(digits) = token.strip_prefix("0x") {
+      match i64::from_str_radix(digits, 16) {
+        Ok(s) => return Ok(Token::INT(s)),
+        Err(..) => return Err("Malformed hexadecimal integer")
       }
     }
-    else if first == Some('\'') || first == Some('\"') {
-      return Token::STRING(token[1..].to_owned());
+    if let Some(digits) = token.strip_prefix("0b") {
+      match i64::from_str_radix(digits, 2) {
+        Ok(s) => return Ok(Token::INT(s)),
+        Err(..) => return Err("Malformed binary integer")
+      }
+    }
+    match token {
+      "func" => return Ok(Token::FUNC),
+      "end" => return Ok(Token::ENDFUNC),
+      "define" => return Ok(Token::DEFINE),
+      "include" => return Ok(Token::INCLUDE),
+      "macro" => return Ok(Token::MACRO),
+      "endm" => return Ok(Token::ENDMACRO),
+      "if" => return Ok(Token::IF),
+      "ifdef" => return Ok(Token::IFDEF),
+      "ifndef" => return Ok(Token::IFNDEF),
+      "else" => return Ok(Token::ELSE),
+      "endif" => return Ok(Token::ENDIF),
+       _ => {}
     }
-    else if first == Some('.') {
-      return Token::ATTR(token[1..].to_owned());
+    if let Ok(s) = token.parse::<i64>() {
+      return Ok(Token::INT(s));
     }
-    else if first == Some('[') {
-      return Token::OFFSET(token[1..].to_owned());
+    if token.chars().all(|c| c.is_ascii_digit()) {
+      return Err("Integer does not fit in 64 bits");
     }
-    if token.chars().nth(token.len() - 1) == Some(':') {
-      return Token::LABEL(token[0..token.len()-1].to_string());
+    // Only numbers with a point or an exponent are decimals, so that words
+    // such as inf and nan stay identifiers
+    let decimal = token.contains(|c: char| c.is_ascii_digit()) && token.contains(['.', 'e', 'E']);
+    if let (true, Ok(f)) = (decimal, f64::from_str(token)) {
+      return Ok(Token::DECIMAL(f));
     }
-    match token {
-      "func" => return Token::FUNC,
-      "end" => return Token::ENDFUNC,
-      "define" => return Token::DEFINE,
-      "include" => return Token::INCLUDE,
-       _ => {}
+    if token.starts_with(|c: char| c.is_ascii_digit()) {
+      return Err("Malformed number");
+    }
+    Ok(Token::IDENT(token.to_owned()))
+  }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Lexeme {
+  pub tok: Token,
+  pub loc: Loc
+}
+
+// Splits a source file into tokens. Statements are terminated by a
+// NEWLINE token which is produced for every line break or ';', except
+// those escaped with a trailing '\' or hidden inside a /* */ comment
+pub struct Lexer {
+  text: Vec<char>,
+  file: usize,
+  pos: usize,
+  line: usize,
+  col: usize,
+  ended: bool
+}
+
+fn is_word(c: char) -> bool {
+  c.is_alphanumeric() || c == '_'
+}
+
+// Operators written with two characters
+fn pair(first: char, second: char) -> Option<Token> {
+  match (first, second) {
+    ('<', '<') => Some(Token::SHL),
+    ('>', '>') => Some(Token::SHR),
+    ('<', '=') => Some(Token::LE),
+    ('>', '=') => Some(Token::GE),
+    ('=', '=') => Some(Token::EQ),
+    ('!', '=') => Some(Token::NE),
+    ('&', '&') => Some(Token::LAND),
+    ('|', '|') => Some(Token::LOR),
+    _ => None
+  }
+}
+
+impl Lexer {
+  pub fn new(file: usize, text: &str) -> Self {
+    Self {
+      text: text.chars().collect(),
+      file,
+      pos: 0,
+      line: 1,
+      col: 1,
+      ended: false
     }
-    match i64::from_str_radix(token, 10) {
-      Ok(s) => return Token::INT(s),
-      Err(..) => {
-        match f64::from_str(token) {
-          Ok(f) => return Token::DECIMAL(f),
-          Err(..) => return Token::IDENT(token.to_owned())
+  }
+
+  fn peek(&self, ahead: usize) -> Option<char> {
+    self.text.get(self.pos + ahead).copied()
+  }
+
+  fn bump(&mut self) -> Option<char> {
+    let c = self.peek(0)?;
+    self.pos += 1;
+    if c == '\n' {
+      self.line += 1;
+      self.col = 1;
+    } else {
+      self.col += 1;
+    }
+    Some(c)
+  }
+
+  fn loc(&self, line: usize, col: usize, len: usize) -> Loc {
+    Loc {
+      file: self.file,
+      line,
+      col,
+      len
+    }
+  }
+
+  fn word(&mut self) -> String {
+    let mut buf = String::new();
+    while let Some(c) = self.peek(0) {
+      // '.' is only part of a word in numbers like 1.5
+      if is_word(c) || (c == '.' && buf.starts_with(|c: char| c.is_ascii_digit())) {
+        buf.push(c);
+        self.bump();
+      } else {
+        break;
+      }
+    }
+    buf
+  }
+
+  fn escape(&mut self) -> Result<char, &'static str> {
+    match self.bump() {
+      Some('n') => Ok('\n'),
+      Some('t') => Ok('\t'),
+      Some('r') => Ok('\r'),
+      Some('0') => Ok('\0'),
+      Some(c @ ('\\' | '\"' | '\'')) => Ok(c),
+      Some('x') => {
+        let mut digits = String::new();
+        while digits.len() < 2 && self.peek(0).is_some_and(|c| c.is_ascii_hexdigit()) {
+          digits.push(self.bump().unwrap());
+        }
+        match u8::from_str_radix(&digits, 16) {
+          Ok(b) if digits.len() == 2 && b < 0x80 => Ok(b as char),
+          Ok(..) if digits.len() == 2 => Err("Escape \\x only allows values up to 7f"),
+          _ => Err("Expected two hexadecimal digits after \\x")
         }
       }
+      _ => Err("Unknown escape sequence")
     }
   }
-}
 
-pub fn line_split(string: &[u8]) -> Result<Vec<Token>, &str> {
-  let mut ret: Vec<Token> = Vec::new();
-  let mut instr = false;
-  let mut comm = false;
-  let mut attr = false;
-  let mut inexpr = false;
-  let mut buf = String::new();
-  for ch in string {
-    let c = *ch as char;
-    match c {
-      ' ' | '\n' => {
-        if c == ' ' && attr {
-          if buf.len() == 0 {
-            return Err("Attribute name expected");
+  // A bad escape is only reported once the whole string has been read so
+  // that lexing carries on after the closing quote
+  fn string(&mut self, quote: char) -> Result<String, &'static str> {
+    let mut buf = String::new();
+    let mut error: Option<&'static str> = None;
+    loop {
+      match self.peek(0) {
+        None | Some('\n') => return Err("Unclosed string literal"),
+        Some('\\') => {
+          self.bump();
+          if let Some('\n') | None = self.peek(0) {
+            return Err("Unclosed string literal");
           }
-          attr = false;
-          ret.push(Token::new(&buf));
-          buf.clear();
-          continue;
-        }
-        if buf.len() == 0 {
-          if c == ' ' {
-            continue;
+          match self.escape() {
+            Ok(c) => buf.push(c),
+            Err(e) => {
+              error.get_or_insert(e);
+            }
           }
-          break;
         }
-        if instr || inexpr {
+        Some(c) => {
+          self.bump();
+          if c == quote {
+            return match error {
+              Some(e) => Err(e),
+              None => Ok(buf)
+            };
+          }
           buf.push(c);
-          continue;
         }
-        ret.push(Token::new(&buf));
-        if c == '\n' {
-          break;
-        }
-        buf.clear();
       }
-      '.' => {
-        if buf.len() != 0 {
-          buf.push(c);
-          continue;
+    }
+  }
+
+  fn next_lexeme(&mut self) -> Option<Result<Lexeme, Diagnostic>> {
+    loop {
+      let (line, col) = (self.line, self.col);
+      let c = match self.peek(0) {
+        Some(c) => c,
+        None => {
+          // Make sure the last statement is terminated
+          if self.ended {
+            return None;
+          }
+          self.ended = true;
+          return Some(Ok(Lexeme { tok: Token::NEWLINE, loc: self.loc(line, col, 1) }));
         }
-        buf.push(c);
-        attr = true;
+      };
+      if let Some(tok) = self.peek(1).and_then(|next| pair(c, next)) {
+        self.bump();
+        self.bump();
+        return Some(Ok(Lexeme { tok, loc: self.loc(line, col, 2) }));
       }
-      '[' => {
-        if inexpr {
-          panic!("Nested offsets are not allowed");
+      let single = |tok: Token| Lexeme { tok, loc: Loc { file: self.file, line, col, len: 1 } };
+      let lexeme = match c {
+        ' ' | '\t' | '\r' => {
+          self.bump();
+          continue;
         }
-        if buf.len() != 0 {
-            ret.push(Token::new(&buf));
+        '\n' | ';' => single(Token::NEWLINE),
+        ',' => single(Token::COMMA),
+        '[' => single(Token::LBRACKET),
+        ']' => single(Token::RBRACKET),
+        '+' => single(Token::PLUS),
+        '-' => single(Token::MINUS),
+        '*' => single(Token::STAR),
+        '%' => single(Token::PERCENT),
+        '&' => single(Token::AMP),
+        '|' => single(Token::PIPE),
+        '~' => single(Token::TILDE),
+        '(' => single(Token::LPAREN),
+        ')' => single(Token::RPAREN),
+        '<' => single(Token::LT),
+        '>' => single(Token::GT),
+        '/' if self.peek(1) == Some('/') => {
+          while self.peek(0).is_some() && self.peek(0) != Some('\n') {
+            self.bump();
+          }
+          continue;
         }
-        inexpr = true;
-        buf.push(c);
-      }
-      '+' => {
-        if inexpr {
-          buf.push(c);
+        '/' if self.peek(1) == Some('*') => {
+          self.bump();
+          self.bump();
+          loop {
+            match self.peek(0) {
+              None => return Some(Err(Diagnostic::new(self.loc(line, col, 2), "Unclosed comment"))),
+              Some('*') if self.peek(1) == Some('/') => {
+                self.bump();
+                self.bump();
+                break;
+              }
+              _ => {
+                self.bump();
+              }
+            }
+          }
           continue;
         }
-        if buf.len() != 0 {
-            ret.push(Token::new(&buf));
+        '/' => single(Token::SLASH),
+        '\\' => {
+          // A line continuation may only be followed by blanks or a comment
+          self.bump();
+          while let Some(' ' | '\t' | '\r') = self.peek(0) {
+            self.bump();
+          }
+          if self.peek(0) == Some('/') && self.peek(1) == Some('/') {
+            while self.peek(0).is_some() && self.peek(0) != Some('\n') {
+              self.bump();
+            }
+          }
+          match self.peek(0) {
+            Some('\n') | None => {
+              self.bump();
+              continue;
+            }
+            _ => return Some(Err(Diagnostic::new(self.loc(line, col, 1), "Expected end of line after '\\'")))
+          }
         }
-        ret.push(Token::PLUS);
-      }
-      '-' => {
-        if inexpr {
-          buf.push(c);
-          continue;
+        '\"' | '\'' => {
+          self.bump();
+          return Some(match self.string(c) {
+            Ok(s) => Ok(Lexeme { tok: Token::STRING(s), loc: self.loc(line, col, self.col - col) }),
+            Err(e) => Err(Diagnostic::new(self.loc(line, col, 1), e))
+          });
         }
-        if buf.len() != 0 {
-            ret.push(Token::new(&buf));
+        '.' if self.peek(1) == Some('L') => {
+          // .L prefixed names are local labels rather than attributes
+          self.bump();
+          let word = format!(".{}", self.word());
+          if self.peek(0) == Some(':') {
+            self.bump();
+            return Some(Ok(Lexeme { tok: Token::LABEL(word), loc: self.loc(line, col, self.col - col) }));
+          }
+          return Some(Ok(Lexeme { tok: Token::IDENT(word), loc: self.loc(line, col, self.col - col) }));
         }
-        ret.push(Token::MINUS);
-      }
-      ']' => {
-        inexpr = false;
-        if buf.len() == 0 {
-          panic!("Empty offset");
+        '.' if self.peek(1).is_some_and(is_word) => {
+          self.bump();
+          let name = self.word();
+          return Some(Ok(Lexeme { tok: Token::ATTR(name), loc: self.loc(line, col, self.col - col) }));
         }
-        ret.push(Token::new(&buf));
-        buf.clear();
-        //println!("{ret:?}");
-      }
-      '/' => {
-        if comm {
-          if buf.len() != 0 {
-            ret.push(Token::new(&buf));
+        c if is_word(c) => {
+          let word = self.word();
+          if self.peek(0) == Some(':') {
+            self.bump();
+            return Some(Ok(Lexeme { tok: Token::LABEL(word), loc: self.loc(line, col, self.col - col) }));
           }
-          return Ok(ret);
+          let loc = self.loc(line, col, self.col - col);
+          return Some(match Token::new(&word) {
+            Ok(tok) => Ok(Lexeme { tok, loc }),
+            Err(e) => Err(Diagnostic::new(loc, e))
+          });
         }
-        comm = true;
-      }
-      '\'' | '\"' => {
-        if instr {
-          ret.push(Token::new(&buf));
-          buf.clear();
-          instr = false;
-          continue;
+        _ => {
+          self.bump();
+          return Some(Err(Diagnostic::new(self.loc(line, col, 1), &format!("Unexpected character '{c}'"))));
         }
-        instr = true;
-        buf.push(c);
-      }
-      ';' | ',' => {} // Ignore comma and and semi colon
-      _ => buf.push(c)
+      };
+      self.bump();
+      return Some(Ok(lexeme));
     }
   }
-  if instr {
-    return Err("Unclosed string literal");
-  }
-  if comm {
-    return Err("Unclosed comment");
-  }
-  if inexpr {
-    return Err("Unclosed offset");
+}
+
+impl Iterator for Lexer {
+  type Item = Result<Lexeme, Diagnostic>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.next_lexeme()
   }
-  Ok(ret)
 }
 
 pub struct Unit {
-  pub name: String,
   pub funcs: Vec<Function>,
   pub define: Vec<Define>,
-  pub data: Vec<Data>
+  pub data: Vec<Data>,
+  pub locals: Vec<(String, Loc)>,
+  // Files given on the command line, the firmware and includes are not
+  pub inputs: Vec<usize>
 }
 
 impl Unit {
-  pub fn new(mut src: Handle) -> Unit {
-    let mut parser = Parser::new();
-    loop {
-      let mut line = src.read_line();
-      if &line == "EOF" {
-        break;
-      }
-      line.push(' ');
-      let split = match line_split(line.as_bytes()) {
-        Ok(s) => s,
-        Err(e) => src.error(e)
-      };
-      match parser.parse(split) {
-        Ok(..) => {},
-        Err(e) => src.error(e)
-      }
+  // Parses the file and everything it includes, errors are collected
+  // statement by statement so that all of them can be reported in one go.
+  // Several files are assembled as if each one included the next. The
+  // firmware is included after the program unless it is None
+  pub fn new(sources: &mut Sources, names: &[&str], defines: Vec<Define>, search: Vec<PathBuf>,
+             firmware: Option<&str>) -> Result<Unit, Vec<Diagnostic>> {
+    let mut parser = Parser::new(sources);
+    parser.define = defines;
+    parser.search = search;
+    for name in names {
+      parser.enter(Path::new(name), None);
     }
-    let split = match line_split("include \"firmware.su\" ".as_bytes()) {
-        Ok(s) => s,
-        Err(e) => src.error(e)
-    };
-    match parser.parse(split) {
-      Ok(..) => {},
-      Err(e) => src.error(e)
+    let inputs = parser.roots.clone();
+    if let Some(firmware) = firmware {
+      parser.include(firmware, None);
     }
-    Unit {
-      name: src.file,
+    if !parser.diags.is_empty() {
+      return Err(parser.diags);
+    }
+    Ok(Unit {
       funcs: parser.funcs,
       define: parser.define,
-      data: parser.data
-    }
+      data: parser.data,
+      locals: parser.locals,
+      inputs
+    })
   }
 }
@@ -1,23 +1,17 @@
-use crate::r#proc::{Unit, Token};
-use crate::parser::{Args::*, Attrs, Type, Data};
+use crate::compiler::diag::{Diagnostic, Loc};
+use crate::compiler::expr::{self, Expr};
+use crate::compiler::r#proc::Unit;
+use crate::compiler::parser::{Args::{self, *}, Attrs, Type, Data, Function, Instr, Label, Value};
+use crate::isa;
+use crate::link::DATA_BEGIN;
+use crate::object::{self, Field, Kind, Reloc, Section};
 
-const MAGIC: u32 = 0xAFC;
-const MAJOR: u16 = 0x1;
-const MINOR: u16 = 0x0;
-
-pub struct Header {
-  pub magic: u32,
-  pub major: u16,
-  pub minor: u16,
-  pub start: usize,
-  pub data: usize
-}
-
-type Args = (Option<i64>, Option<f64>);
 pub struct Ins {
   pub opcode: u32,
-  pub args: Vec<Args>,
-  pub size: usize
+  // Immediates of the operands in order, as they follow the prologue
+  pub args: Vec<u8>,
+  pub size: usize,
+  pub site: Loc
 }
 
 pub struct Func {
@@ -25,132 +19,313 @@ pub struct Func {
   pub size: usize,
 }
 
-pub struct SemUnit {
+// Labels are named after their function as func.label since the same
+// label may appear in several functions
+pub struct Symbol {
   pub name: String,
-  pub header: Header,
+  pub kind: Kind,
+  pub section: Section,
+  pub offset: usize,
+  pub size: usize,
+  pub global: bool,
+  pub loc: Loc
+}
+
+// A data item, its contents are final apart from the relocations
+pub struct Blob {
+  pub name: Option<String>,
+  pub offset: usize,
+  pub bytes: Vec<u8>,
+  pub loc: Loc
+}
+
+// Code and data are laid out from offset 0 of their sections, anything
+// which depends on their final address is left to the linker
+pub struct SemUnit {
   pub funcs: Vec<Func>,
-  pub data: Vec<Data>
+  pub data: Vec<Blob>,
+  pub data_align: usize,
+  pub data_begin: usize,
+  pub symbols: Vec<Symbol>,
+  pub relocs: Vec<Reloc>,
+  // References to symbols this unit does not define
+  pub externs: Vec<(String, Loc)>
+}
+
+// Constants take the fewest bytes which hold them while anything left to
+// the linker takes 4, so the size of an instruction never depends on
+// where its operands end up
+fn operand_size(arg: &Args) -> usize {
+  match arg {
+    REGISTER(..) | FREGISTER(..) => 0,
+    INT(i) => isa::width(*i),
+    DECIMAL(..) => 8,
+    OFFSET(_, Expr::NUM(n)) => match isa::width(*n) {
+      8 => 8,
+      w => 1 + w
+    },
+    OFFSET(..) => 5,
+    EXPR(..) => 4
+  }
 }
 
-pub fn sem_analyse(unit: Unit) -> SemUnit {
-  let mut header = Header {
-    magic: MAGIC,
-    major: MAJOR,
-    minor: MINOR,
-    start: 15,
-    data: 0
+fn ins_size(ins: &Instr) -> usize {
+  4 + ins.args.iter().flatten().map(operand_size).sum::<usize>()
+}
+
+// Size of a data item placed at address, only .align depends on it
+pub fn data_size(data: &Data, address: usize) -> usize {
+  match data.0 {
+    Type::STRING | Type::ASCII => data.2.iter().map(|value| match value {
+      Value::STRING(s) => s.len() + (data.0 == Type::STRING) as usize,
+      _ => unreachable!()
+    }).sum(),
+    Type::ZERO => match data.2[0] {
+      Value::COUNT(n) => n,
+      _ => unreachable!()
+    },
+    Type::ALIGN => match data.2[0] {
+      Value::COUNT(n) => (n - address % n) % n,
+      _ => unreachable!()
+    },
+    _ => data.0.width() * data.2.len()
+  }
+}
+
+// Offsets of every instruction from the start of the function, with one
+// extra entry for the end of the function so labels can sit after the
+// last instruction
+fn ins_offsets(func: &Function) -> Vec<usize> {
+  let mut offsets = vec![0];
+  for ins in &func.ins {
+    offsets.push(offsets[offsets.len() - 1] + ins_size(ins));
+  }
+  offsets
+}
+
+// Numeric labels are referred to as 1f (next '1:' after the instruction)
+// or 1b (closest '1:' before or at the instruction)
+fn numeric_ref(name: &str) -> Option<(&str, bool)> {
+  let (number, forward) = match name.strip_suffix('f') {
+    Some(s) => (s, true),
+    None => (name.strip_suffix('b')?, false)
   };
-  let mut offset = 24usize;
-  let mut offset_table: Vec<(usize, String)> = Vec::new();
-  if unit.data.len() != 0 {
-    let mut start = 0;
-    for define in &unit.define {
-      if define.0 == "DATA_BEGIN" {
-        match define.1 {
-          Token::INT(s) => start = s as usize,
-          _ => unreachable!()
+  if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+    return None;
+  }
+  Some((number, forward))
+}
+
+// Labels of the enclosing function
+pub fn label<'a>(name: &str, func: &'a Function, index: usize) -> Option<&'a Label> {
+  match numeric_ref(name) {
+    Some((number, true)) => func.labels.iter().find(|l| l.name == number && l.index > index),
+    Some((number, false)) => func.labels.iter().rev().find(|l| l.name == number && l.index <= index),
+    None => func.labels.iter().find(|l| l.name == name)
+  }
+}
+
+// Where a relocation is taken from, labels become an offset from the
+// start of their function
+struct Scope<'a> {
+  func: Option<(&'a Function, usize, &'a [usize])>,
+  defined: &'a [Symbol]
+}
+
+impl Scope<'_> {
+  fn relocatable(&self, e: &Expr, externs: &mut Vec<(String, Loc)>) -> object::Expr {
+    match e {
+      Expr::NUM(i) => object::Expr::NUM(*i),
+      Expr::SYM(s, loc) => {
+        if let Some((func, index, offsets)) = self.func {
+          if let Some(l) = label(s, func, index) {
+            return object::Expr::BINARY(
+              object::Op::ADD,
+              Box::new(object::Expr::SYM(func.name.clone())),
+              Box::new(object::Expr::NUM(offsets[l.index] as i64))
+            );
+          }
         }
-        break;
+        if !self.defined.iter().any(|d| d.kind != Kind::LABEL && &d.name == s) {
+          externs.push((s.clone(), *loc));
+        }
+        object::Expr::SYM(s.clone())
       }
+      Expr::NEG(e, _) => object::Expr::NEG(Box::new(self.relocatable(e, externs))),
+      Expr::NOT(e) => object::Expr::NOT(Box::new(self.relocatable(e, externs))),
+      Expr::BINARY(op, l, r, _) => object::Expr::BINARY(
+        *op,
+        Box::new(self.relocatable(l, externs)),
+        Box::new(self.relocatable(r, externs))
+      )
     }
-    for var in &unit.data {
-      offset_table.push((start, var.1.clone()));
-      match var.0 {
-        Type::BYTE => start += 1,
-        Type::SHORT => start += 2,
-        Type::INT => start += 4,
-        Type::LONG => start += 8,
-        Type::STRING => {
-          match &var.2 {
-            Token::STRING(s) => start += s.len(),
-            _ => unreachable!()
-          }
+  }
+}
+
+// Contents of a data item, values using addresses are left as zero for
+// the linker to fill in
+fn data_bytes(data: &Data, offset: usize, scope: &Scope, relocs: &mut Vec<Reloc>,
+              externs: &mut Vec<(String, Loc)>) -> Vec<u8> {
+  let mut bytes: Vec<u8> = Vec::new();
+  for value in &data.2 {
+    match value {
+      Value::EXPR(Expr::NUM(i), _) => bytes.extend_from_slice(&i.to_le_bytes()[..data.0.width()]),
+      Value::EXPR(e, _) => {
+        relocs.push(Reloc {
+          section: Section::DATA,
+          offset: offset + bytes.len(),
+          field: Field::BYTES(data.0.width()),
+          expr: scope.relocatable(e, externs)
+        });
+        bytes.resize(bytes.len() + data.0.width(), 0);
+      }
+      Value::DECIMAL(d) => bytes.extend_from_slice(&d.to_le_bytes()),
+      Value::STRING(s) => {
+        bytes.extend_from_slice(s.as_bytes());
+        if data.0 == Type::STRING {
+          bytes.push(0);
         }
       }
+      Value::COUNT(..) => bytes.resize(bytes.len() + data_size(data, offset), 0)
     }
   }
-  let mut funcs: Vec<Func> = Vec::new();
+  bytes
+}
+
+fn define(symbols: &mut Vec<Symbol>, sym: Symbol, diags: &mut Vec<Diagnostic>) {
+  if symbols.iter().any(|s| s.kind != Kind::LABEL && s.name == sym.name) {
+    diags.push(Diagnostic::new(sym.loc, &format!("Symbol {} is already defined", sym.name)));
+  }
+  symbols.push(sym);
+}
+
+pub fn sem_analyse(unit: Unit) -> Result<SemUnit, Vec<Diagnostic>> {
+  let mut diags: Vec<Diagnostic> = Vec::new();
+  let mut symbols: Vec<Symbol> = Vec::new();
+  let mut relocs: Vec<Reloc> = Vec::new();
+  let mut externs: Vec<(String, Loc)> = Vec::new();
+  let data_begin = match expr::define_value("DATA_BEGIN", &unit.define) {
+    Some(Ok(s)) => s as usize,
+    Some(Err(..)) => {
+      diags.push(Diagnostic::bare("DATA_BEGIN must be defined as an integer"));
+      DATA_BEGIN
+    }
+    None => DATA_BEGIN
+  };
+  // .align is relative to the start of the section, the linker keeps the
+  // section aligned to the largest alignment used
+  let mut data_align = 1;
+  let mut offsets: Vec<usize> = Vec::new();
+  let mut offset = 0;
+  for var in &unit.data {
+    if let (Type::ALIGN, Value::COUNT(n)) = (var.0, &var.2[0]) {
+      data_align = data_align.max(*n);
+    }
+    offsets.push(offset);
+    let size = data_size(var, offset);
+    if let Some(name) = &var.1 {
+      let sym = Symbol { name: name.clone(), kind: Kind::DATA, section: Section::DATA, offset, size, global: true, loc: var.3 };
+      define(&mut symbols, sym, &mut diags);
+    }
+    offset += size;
+  }
+  // Lay out every function first so that code can refer to functions
+  // and labels which are defined further down
+  let mut layout: Vec<Vec<usize>> = Vec::new();
+  offset = 0;
   for func in &unit.funcs {
-    offset_table.push((offset, func.name.clone()));
+    let offsets = ins_offsets(func);
+    let size = offsets[offsets.len() - 1];
+    let sym = Symbol { name: func.name.clone(), kind: Kind::FUNC, section: Section::CODE, offset, size, global: true, loc: func.loc };
+    define(&mut symbols, sym, &mut diags);
+    // Labels made by -O start with '@' and stay out of the map
+    for label in func.labels.iter().filter(|l| !l.name.starts_with('@')) {
+      symbols.push(Symbol {
+        name: format!("{}.{}", func.name, label.name),
+        kind: Kind::LABEL,
+        section: Section::CODE,
+        offset: offset + offsets[label.index],
+        size: 0,
+        global: false,
+        loc: label.loc
+      });
+    }
+    offset += size;
+    layout.push(offsets);
+  }
+  for (name, loc) in &unit.locals {
+    match symbols.iter_mut().find(|s| s.kind != Kind::LABEL && &s.name == name) {
+      Some(s) => s.global = false,
+      None => diags.push(Diagnostic::new(*loc, &format!("Symbol {name} is not defined")))
+    }
+  }
+  offset = 0;
+  let mut funcs: Vec<Func> = Vec::new();
+  for (func, offsets) in unit.funcs.iter().zip(&layout) {
     let mut f: Vec<Ins> = Vec::new();
     let mut size = 0;
-    for ins in &func.ins {
-      let mut ins_size = 0;
-      let mut opcode = (ins.name as u32) << 22;
-      if ins.len == 1 {
-        f.push(Ins {
-          opcode,
-          args: Vec::new(),
-          size: 4
-        });
-        ins_size = 4;
-        size += ins_size;
-        continue;
-      }
-      ins_size += 4;
-      let mut chunk = 15;
-      let mut args_vec: Vec<Args> = Vec::new();
-      for args in ins.args.as_ref().unwrap() {
-        match args {
+    for (index, ins) in func.ins.iter().enumerate() {
+      let scope = Scope { func: Some((func, index, offsets)), defined: &symbols };
+      let mut opcode = (ins.def.opcode as u32) << isa::OPCODE_SHIFT;
+      let mut bytes: Vec<u8> = Vec::new();
+      for (arg, args) in ins.args.iter().flatten().enumerate() {
+        // Position of this operand's immediate, if it has one
+        let field = offset + size + 4 + bytes.len();
+        let code = match args {
           INT(i) => {
-            args_vec.push((Some(*i), None));
-            ins_size += 8;
-            opcode |= (81 & 0x7F) << chunk;
-            chunk -= 7;
+            let width = isa::width(*i);
+            bytes.extend_from_slice(&i.to_le_bytes()[..width]);
+            isa::imm_code(width)
           }
           DECIMAL(d) => {
-            args_vec.push((None, Some(*d)));
-            ins_size += 8;
-            opcode |= (82 & 0x7F) << chunk;
-            chunk -= 7;
+            bytes.extend_from_slice(&d.to_le_bytes());
+            isa::DECIMAL
+          }
+          REGISTER(r) | FREGISTER(r) => *r,
+          OFFSET(reg, Expr::NUM(off)) if isa::width(*off) < 8 => {
+            let width = isa::width(*off);
+            bytes.push(*reg);
+            bytes.extend_from_slice(&off.to_le_bytes()[..width]);
+            isa::offset_code(width)
           }
-          REGISTER(r) => {
-            opcode |= ((*r as u32) & 0x7F) << chunk;
-            chunk -= 7;
+          OFFSET(reg, Expr::NUM(off)) => {
+            // The register takes the top 7 bits, the offset is what remains
+            let bits = isa::OFFSET_BITS - 1;
+            if *off < -(1 << bits) || *off >= 1 << bits {
+              diags.push(Diagnostic::new(ins.arg_locs[arg], "Offset does not fit in 57 bits"));
+            }
+            let arg: u64 = ((*reg as u64) << isa::OFFSET_BITS) | (*off as u64 & ((1 << isa::OFFSET_BITS) - 1));
+            bytes.extend_from_slice(&arg.to_le_bytes());
+            isa::OFFSET
           }
           OFFSET(reg, off) => {
-            let arg: u64 = ((*reg as u64) << 57) | (*off as u64);
-            let actual= unsafe { std::mem::transmute::<u64, i64>(arg) };
-            ins_size += 8;
-            opcode |= (83 & 0x7F) << chunk;
-            chunk -= 7;
-            args_vec.push((Some(actual), None));
+            relocs.push(Reloc { section: Section::CODE, offset: field + 1, field: Field::IMM32, expr: scope.relocatable(off, &mut externs) });
+            bytes.push(*reg);
+            bytes.extend_from_slice(&[0; 4]);
+            isa::OFFSET32
           }
-          STRING(s) => {
-            let mut found = false;
-            for i in &offset_table {
-              if &i.1 == s {
-                let off = unsafe { std::mem::transmute::<usize, i64>(i.0) };
-                args_vec.push((Some(off), None));
-                ins_size += 8;
-                opcode |= (81 & 0x7F) << chunk;
-                chunk -= 7;
-                found = true;
-                break;
-              }
-            }
-            
-            if !found {
-              panic!("Function or label {s} not found");
-            }
-          } 
-        }
+          EXPR(e) => {
+            relocs.push(Reloc { section: Section::CODE, offset: field, field: Field::IMM32, expr: scope.relocatable(e, &mut externs) });
+            bytes.extend_from_slice(&[0; 4]);
+            isa::IMM32
+          }
+        };
+        opcode |= ((code as u32) & isa::OPERAND_MASK) << isa::OPERAND_SHIFTS[arg];
       }
+      let ins_size = 4 + bytes.len();
       f.push(Ins {
        opcode,
-       args: args_vec,
-       size: ins_size
+       args: bytes,
+       size: ins_size,
+       site: ins.site
       });
       size += ins_size;
     }
-    if func.name == "_start" {
-      header.start = offset;
-    }
     offset += size;
     for attr in func.attrs.as_ref().unwrap() {
       for ins in &mut f {
         match attr.0 {
-          Attrs::FIRMWARE => ins.opcode |= 1 << 0
+          Attrs::FIRMWARE => ins.opcode |= isa::FIRMWARE_BIT
         }
       }
     }
@@ -159,12 +334,23 @@ pub fn sem_analyse(unit: Unit) -> SemUnit {
       size,
     });
   }
-  header.data = offset;
-  
-  SemUnit {
-    name: unit.name,
-    header,
-    funcs,
-    data: unit.data
+  let scope = Scope { func: None, defined: &symbols };
+  let mut data: Vec<Blob> = Vec::new();
+  for (var, offset) in unit.data.iter().zip(offsets) {
+    let bytes = data_bytes(var, offset, &scope, &mut relocs, &mut externs);
+    data.push(Blob { name: var.1.clone(), offset, bytes, loc: var.3 });
+  }
+  if !diags.is_empty() {
+    return Err(diags);
   }
+
+  Ok(SemUnit {
+    funcs,
+    data,
+    data_align,
+    data_begin,
+    symbols,
+    relocs,
+    externs
+  })
 }
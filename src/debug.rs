@@ -0,0 +1,135 @@
+use std::io::{self, Error, ErrorKind};
+extern crate file_utils;
+use file_utils::read::Read;
+use file_utils::write::Write;
+
+// Debug section of an executable, which tells the runtime what function
+// and source line the code at an address came from. The layout is
+//
+// u32 function count, (u64 address, u64 size, name) for every function
+// u32 file count, name of every file
+// u32 line count, (u64 address, u32 file, u32 line) for every line
+//
+// A line covers the code from its address up to the next line. Nothing
+// needs it to run a program, blitz only uses it for error messages
+
+pub struct Function {
+  pub address: usize,
+  pub size: usize,
+  pub name: String
+}
+
+pub struct Line {
+  pub address: usize,
+  // Index into Debug::files
+  pub file: usize,
+  pub line: usize
+}
+
+pub struct Debug {
+  pub funcs: Vec<Function>,
+  pub files: Vec<String>,
+  // Sorted by address
+  pub lines: Vec<Line>
+}
+
+fn write_str<W: io::Write>(writer: &mut W, s: &str) -> io::Result<()> {
+  writer.write_u32(s.len() as u32)?;
+  writer.write_all(s.as_bytes())
+}
+
+// Lengths are not trusted, a bad one ends the read instead of allocating
+// it all up front
+fn read_str<R: io::Read>(reader: &mut R) -> io::Result<String> {
+  let len = reader.read_u32()? as usize;
+  let mut buf: Vec<u8> = Vec::new();
+  let mut limited = io::Read::take(io::Read::by_ref(reader), len as u64);
+  io::Read::read_to_end(&mut limited, &mut buf)?;
+  if buf.len() != len {
+    return Err(malformed());
+  }
+  String::from_utf8(buf).map_err(|_| malformed())
+}
+
+fn malformed() -> Error {
+  Error::new(ErrorKind::InvalidData, "Malformed debug section")
+}
+
+impl Debug {
+  pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    writer.write_u32(self.funcs.len() as u32)?;
+    for func in &self.funcs {
+      writer.write_u64(func.address as u64)?;
+      writer.write_u64(func.size as u64)?;
+      write_str(writer, &func.name)?;
+    }
+    writer.write_u32(self.files.len() as u32)?;
+    for file in &self.files {
+      write_str(writer, file)?;
+    }
+    writer.write_u32(self.lines.len() as u32)?;
+    for line in &self.lines {
+      writer.write_u64(line.address as u64)?;
+      writer.write_u32(line.file as u32)?;
+      writer.write_u32(line.line as u32)?;
+    }
+    Ok(())
+  }
+
+  pub fn read<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    let mut funcs: Vec<Function> = Vec::new();
+    for _ in 0..reader.read_u32()? {
+      funcs.push(Function {
+        address: reader.read_u64()? as usize,
+        size: reader.read_u64()? as usize,
+        name: read_str(reader)?
+      });
+    }
+    let mut files: Vec<String> = Vec::new();
+    for _ in 0..reader.read_u32()? {
+      files.push(read_str(reader)?);
+    }
+    let mut lines: Vec<Line> = Vec::new();
+    for _ in 0..reader.read_u32()? {
+      let line = Line {
+        address: reader.read_u64()? as usize,
+        file: reader.read_u32()? as usize,
+        line: reader.read_u32()? as usize
+      };
+      if line.file >= files.len() {
+        return Err(malformed());
+      }
+      lines.push(line);
+    }
+    Ok(Self { funcs, files, lines })
+  }
+
+  fn containing(&self, pc: usize) -> Option<&Function> {
+    self.funcs.iter().find(|f| f.address <= pc && pc < f.address + f.size)
+  }
+
+  pub fn function(&self, pc: usize) -> Option<&str> {
+    self.containing(pc).map(|f| f.name.as_str())
+  }
+
+  // File and line of the code at pc. Functions from objects without a
+  // line table have none, so the line has to be in the same function
+  pub fn line(&self, pc: usize) -> Option<(&str, usize)> {
+    let func = self.containing(pc)?;
+    let index = self.lines.partition_point(|l| l.address <= pc).checked_sub(1)?;
+    let line = Some(&self.lines[index]).filter(|l| l.address >= func.address)?;
+    Some((&self.files[line.file], line.line))
+  }
+
+  // Where pc is for messages, e.g "in main at hello.su:7 (pc = 0x2c)"
+  pub fn describe(&self, pc: usize) -> String {
+    let func = match self.function(pc) {
+      Some(s) => format!("in {s} "),
+      None => String::new()
+    };
+    match self.line(pc) {
+      Some((file, line)) => format!("{func}at {file}:{line} (pc = {pc:#x})"),
+      None => format!("{func}at pc = {pc:#x}")
+    }
+  }
+}
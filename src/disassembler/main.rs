@@ -0,0 +1,304 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use blitz::isa::{self, register_name, Instruction, Operand};
+use blitz::link::{Header, DATA_BEGIN};
+
+// Exit codes, the same as blc
+const EXIT_ERRORS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
+
+const HELP: &str = "Usage: bldis [options] <executable>
+
+Checks the header of an executable written by blc or bld and prints its code
+as blitz assembly followed by a hex dump of its data. Names are taken from
+the symbol map next to the executable when there is one, function names
+from its debug section otherwise
+
+Options:
+  --map <file>       Read symbol names from file instead of the default map
+  --no-map           Do not use a symbol map
+  -h, --help         Print this message
+  --version          Print the version of bldis
+
+Exit status is 0 on success, 1 if the file is not a valid executable, 2
+for bad options and 3 if a file could not be read";
+
+fn usage(msg: &str) -> ! {
+  eprintln!("bldis: {msg}");
+  eprintln!("Try 'bldis --help' for more information");
+  process::exit(EXIT_USAGE);
+}
+
+fn invalid(path: &str, msg: &str) -> ! {
+  eprintln!("bldis: {path} : {msg}");
+  process::exit(EXIT_ERRORS);
+}
+
+// A function, label or data item from the symbol map
+struct Symbol {
+  address: usize,
+  kind: String,
+  name: String
+}
+
+// Reads the table written by link::map, lines which do not parse are skipped
+fn read_map(text: &str) -> Vec<Symbol> {
+  let mut symbols: Vec<Symbol> = Vec::new();
+  for line in text.lines().skip(1) {
+    let cols: Vec<&str> = line.split_whitespace().collect();
+    if cols.len() < 4 {
+      continue;
+    }
+    let address = match usize::from_str_radix(cols[0].trim_start_matches("0x"), 16) {
+      Ok(s) => s,
+      Err(..) => continue
+    };
+    symbols.push(Symbol { address, kind: cols[2].to_owned(), name: cols[3].to_owned() });
+  }
+  symbols
+}
+
+struct Disassembler<'a> {
+  code: &'a [u8],
+  // Compact immediates only appear from version 2 on
+  major: u16,
+  symbols: &'a [Symbol]
+}
+
+impl<'a> Disassembler<'a> {
+  fn u32_at(&self, address: usize) -> Option<u32> {
+    let bytes = self.code.get(address..address + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+  }
+
+  // Name of the function, label or data item at address, if any
+  fn name(&self, address: usize) -> Option<&str> {
+    self.symbols.iter().find(|s| s.address == address).map(|s| s.name.as_str())
+  }
+
+  // Only operands which are always addresses are named, a constant which
+  // happens to equal an address is printed as the number it is. Labels of
+  // func are named as in its source, without the func. prefix
+  fn immediate(&self, kind: &[Operand], value: u64, func: &str) -> String {
+    let target = kind.contains(&Operand::ADDR);
+    let named = match target {
+      true => self.name(value as usize),
+      false => None
+    };
+    match named {
+      Some(s) => s.strip_prefix(&format!("{func}.")).unwrap_or(s).to_owned(),
+      None if target || value as usize >= DATA_BEGIN => format!("{value:#x}"),
+      None => format!("{}", value as i64)
+    }
+  }
+
+  // Decodes the instruction at address like Cpu::decode and read_args,
+  // returning its text and size
+  fn instruction(&self, address: usize, func: &str) -> Result<(String, usize), String> {
+    let ins = self.u32_at(address).ok_or("Truncated instruction")?;
+    let opcode = ins >> isa::OPCODE_SHIFT;
+    let def: &Instruction = match isa::by_opcode(opcode) {
+      Some(s) => s,
+      None => return Err(format!("Unknown opcode {opcode}"))
+    };
+    let float = def.is_float();
+    let mut size = 4;
+    let mut args: Vec<String> = Vec::new();
+    for (shift, kind) in isa::OPERAND_SHIFTS.iter().zip(def.operands) {
+      let cluster = ((ins >> shift) & isa::OPERAND_MASK) as u8;
+      let len = match isa::operand_size(cluster) {
+        Some(n) if self.major > 1 || !isa::is_compact(cluster) => n,
+        _ => return Err(format!("Unknown operand encoding {cluster}"))
+      };
+      let imm = self.code.get(address + size..address + size + len).ok_or("Truncated instruction")?;
+      size += len;
+      let arg = match cluster {
+        0..=isa::SP => register_name(cluster, float),
+        isa::DECIMAL => format!("{:?}", f64::from_le_bytes(imm.try_into().unwrap())),
+        isa::OFFSET => {
+          let num = u64::from_le_bytes(imm.try_into().unwrap());
+          // The register above a signed 57 bit offset
+          let unused = 64 - isa::OFFSET_BITS;
+          offset((num >> isa::OFFSET_BITS) as u8, ((num << unused) as i64) >> unused)
+        }
+        isa::OFFSET8 | isa::OFFSET16 | isa::OFFSET32 => offset(imm[0], isa::sign_extend(&imm[1..])),
+        _ => self.immediate(kind, isa::sign_extend(imm) as u64, func)
+      };
+      args.push(arg);
+    }
+    let mut text = match args.len() {
+      0 => def.mnemonic.to_owned(),
+      _ => format!("{} {}", def.mnemonic, args.join(", "))
+    };
+    if ins & isa::FIRMWARE_BIT != 0 {
+      text += "  ; firmware";
+    }
+    Ok((text, size))
+  }
+
+  // Prologue followed by the immediates, as in blc's listing
+  fn encoding(&self, address: usize, size: usize) -> String {
+    isa::hex(&self.code[address..address + size])
+  }
+}
+
+fn offset(reg: u8, off: i64) -> String {
+  let reg = register_name(reg, false);
+  match off {
+    0 => format!("[{reg}]"),
+    _ if off < 0 => format!("[{reg} - {}]", off.unsigned_abs()),
+    _ => format!("[{reg} + {off}]")
+  }
+}
+
+fn hexdump(data: &[u8], symbols: &[Symbol]) -> String {
+  let mut out = String::new();
+  let mut offset = 0;
+  while offset < data.len() {
+    let address = DATA_BEGIN + offset;
+    for sym in symbols.iter().filter(|s| s.address == address && s.kind == "data") {
+      out += &format!("{}:\n", sym.name);
+    }
+    // A row ends at 16 bytes or where the next data item starts
+    let next = symbols.iter()
+      .map(|s| s.address)
+      .filter(|a| *a > address && *a < address + 16)
+      .min()
+      .unwrap_or(address + 16);
+    let end = (next - DATA_BEGIN).min(data.len());
+    let row = &data[offset..end];
+    let hex: Vec<String> = row.iter().map(|b| format!("{b:02x}")).collect();
+    let text: String = row.iter().map(|b| match b.is_ascii_graphic() || *b == b' ' {
+      true => *b as char,
+      false => '.'
+    }).collect();
+    out += &format!("  {address:#08x}  {:47}  {text}\n", hex.join(" "));
+    offset = end;
+  }
+  out
+}
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let mut input: Option<&str> = None;
+  let mut map_path: Option<String> = None;
+  let mut map = true;
+  let mut index = 0;
+  while index < args.len() {
+    let arg = args[index].as_str();
+    index += 1;
+    match arg {
+      "-h" | "--help" => {
+        println!("{HELP}");
+        return;
+      }
+      "--version" => {
+        println!("bldis {}", env!("CARGO_PKG_VERSION"));
+        return;
+      }
+      "--map" => {
+        index += 1;
+        match args.get(index - 1) {
+          Some(s) => map_path = Some(s.clone()),
+          None => usage("--map needs a value")
+        }
+      }
+      "--no-map" => map = false,
+      _ if arg.starts_with('-') && arg.len() > 1 => usage(&format!("Unknown option '{arg}'")),
+      _ if input.is_some() => usage("Only one executable can be disassembled at a time"),
+      _ => input = Some(arg)
+    }
+  }
+  let path = match input {
+    Some(s) => s,
+    None => usage("Input file name needed")
+  };
+  let bytes = match fs::read(path) {
+    Ok(s) => s,
+    Err(e) => {
+      eprintln!("bldis: Failed to open {path} : {e}");
+      process::exit(EXIT_IO);
+    }
+  };
+
+  // Header as written by link::link
+  let header = match Header::read(&bytes) {
+    Ok(s) => s,
+    Err(e) => invalid(path, &e)
+  };
+  let (major, minor, start, data_offset) = (header.major, header.minor, header.start, header.data_offset);
+  let code_begin = header.code_begin;
+  let debug = header.debug(&bytes);
+  if header.debug_offset.is_some() && debug.is_none() {
+    invalid(path, "Malformed debug section");
+  }
+
+  let map_path = match (map, map_path) {
+    (false, _) => None,
+    (true, Some(s)) => Some(s),
+    // The default map is only used if it exists
+    (true, None) => Some(Path::new(path).with_extension("map").display().to_string())
+      .filter(|p| Path::new(p).exists())
+  };
+  let symbols = match map_path {
+    Some(p) => match fs::read_to_string(&p) {
+      Ok(s) => read_map(&s),
+      Err(e) => {
+        eprintln!("bldis: Failed to open {p} : {e}");
+        process::exit(EXIT_IO);
+      }
+    },
+    // Functions are still named after the debug section
+    None => debug.iter().flat_map(|d| &d.funcs).map(|f| Symbol {
+      address: f.address,
+      kind: "func".to_owned(),
+      name: f.name.clone()
+    }).collect()
+  };
+
+  let data = &bytes[data_offset..header.data_end];
+  let dis = Disassembler {
+    code: &bytes[..data_offset],
+    major,
+    symbols: &symbols
+  };
+  let entry = match dis.name(start) {
+    Some(s) => format!("{start:#x} ({s})"),
+    None => format!("{start:#x}")
+  };
+  println!("{path}: blitz executable {major}.{minor}, entry {entry}");
+  println!("code {:#x} bytes at {code_begin:#x}, data {:#x} bytes at {DATA_BEGIN:#x}", data_offset - code_begin, data.len());
+  if let Some(d) = &debug {
+    println!("debug section with {} functions and {} lines", d.funcs.len(), d.lines.len());
+  }
+  println!();
+  println!("Code:");
+  let mut address = code_begin;
+  let mut func = String::new();
+  while address < data_offset {
+    for sym in symbols.iter().filter(|s| s.address == address && s.kind != "data") {
+      match sym.kind.as_str() {
+        // Labels are named func.label in the map, the label itself may
+        // have a '.' as in main..Lloop
+        "label" => println!("{}:", sym.name.strip_prefix(&format!("{func}.")).unwrap_or(&sym.name)),
+        _ => {
+          func = sym.name.clone();
+          println!("\n{}:", sym.name)
+        }
+      }
+    }
+    match dis.instruction(address, &func) {
+      Ok((text, size)) => {
+        println!("  {address:#08x}  {:42}  {text}", dis.encoding(address, size));
+        address += size;
+      }
+      Err(e) => invalid(path, &format!("{e} at {address:#x}"))
+    }
+  }
+  println!();
+  println!("Data:");
+  print!("{}", hexdump(data, &symbols));
+}
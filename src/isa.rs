@@ -0,0 +1,364 @@
+// The blitz instruction set, shared by blc, blitz and bldis. Adding an
+// instruction means adding it to Opcode and INSTRUCTIONS and giving it a
+// meaning in Cpu::exec
+//
+// Every instruction starts with a 32 bit prologue holding the opcode in
+// its top 10 bits, one 7 bit operand code for each of up to three operands
+// and the firmware bit at bit 0. An operand code is either a register
+// number or one of the immediate codes below, whose immediates follow the
+// prologue in the order of the operands
+
+pub const OPCODE_SHIFT: u32 = 22;
+// Where the code of the first, second and third operand goes
+pub const OPERAND_SHIFTS: [u32; 3] = [15, 8, 1];
+pub const OPERAND_MASK: u32 = 0x7F;
+pub const FIRMWARE_BIT: u32 = 1 << 0;
+
+// Operand codes above the registers
+pub const IMM: u8 = 81;
+pub const DECIMAL: u8 = 82;
+// Register in the top 7 bits of the immediate, signed offset in the rest
+pub const OFFSET: u8 = 83;
+pub const OFFSET_BITS: u32 = 57;
+
+// Version 2 executables also have immediates of 1, 2 and 4 bytes, sign
+// extended to 64 bits, and offsets made of a register byte followed by a
+// 1, 2 or 4 byte signed offset
+pub const IMM8: u8 = 84;
+pub const IMM16: u8 = 85;
+pub const IMM32: u8 = 86;
+pub const OFFSET8: u8 = 87;
+pub const OFFSET16: u8 = 88;
+pub const OFFSET32: u8 = 89;
+
+pub fn is_compact(code: u8) -> bool {
+  (IMM8..=OFFSET32).contains(&code)
+}
+
+// Bytes an operand code takes after the prologue, None for unknown codes
+pub fn operand_size(code: u8) -> Option<usize> {
+  match code {
+    0..=SP => Some(0),
+    IMM | DECIMAL | OFFSET => Some(8),
+    IMM8 => Some(1),
+    IMM16 => Some(2),
+    IMM32 => Some(4),
+    OFFSET8 => Some(2),
+    OFFSET16 => Some(3),
+    OFFSET32 => Some(5),
+    _ => None
+  }
+}
+
+// Sizes of the immediates following a prologue, unused operands are b0
+// and take nothing
+pub fn immediate_sizes(prologue: u32) -> Option<Vec<usize>> {
+  OPERAND_SHIFTS.iter()
+    .map(|shift| operand_size(((prologue >> shift) & OPERAND_MASK) as u8))
+    .filter(|size| *size != Some(0))
+    .collect()
+}
+
+// Hex of an instruction as it is stored, the prologue followed by each
+// immediate as a little endian number
+pub fn hex(bytes: &[u8]) -> String {
+  let prologue = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+  let mut out = format!("{prologue:08x}");
+  let mut at = 4;
+  for size in immediate_sizes(prologue).unwrap_or_default() {
+    let imm = match bytes.get(at..at + size) {
+      Some(s) => s,
+      None => break
+    };
+    let digits: String = imm.iter().rev().map(|b| format!("{b:02x}")).collect();
+    out += &format!(" {digits}");
+    at += size;
+  }
+  out
+}
+
+// Smallest of 1, 2, 4 and 8 bytes which gives back value when sign extended
+pub fn width(value: i64) -> usize {
+  [1, 2, 4].into_iter().find(|w| sign_extend(&value.to_le_bytes()[..*w]) == value).unwrap_or(8)
+}
+
+pub fn imm_code(width: usize) -> u8 {
+  match width {
+    1 => IMM8,
+    2 => IMM16,
+    4 => IMM32,
+    _ => IMM
+  }
+}
+
+// Code of a compact offset whose offset takes width bytes
+pub fn offset_code(width: usize) -> u8 {
+  match width {
+    1 => OFFSET8,
+    2 => OFFSET16,
+    _ => OFFSET32
+  }
+}
+
+// Little endian bytes of a compact immediate as a 64 bit number
+pub fn sign_extend(bytes: &[u8]) -> i64 {
+  let mut buf = [0u8; 8];
+  buf[..bytes.len()].copy_from_slice(bytes);
+  let unused = 64 - 8 * bytes.len() as u32;
+  (i64::from_le_bytes(buf) << unused) >> unused
+}
+
+// Registers b0-b19, w0-w19, d0-d19 and r0-r19 are views of the same 20
+// registers 1, 2, 4 and 8 bytes wide. f0-f19 are the floating point
+// registers, they share their numbers with b0-b19 and which is meant
+// depends on the instruction
+pub const SP: u8 = 80;
+pub const FLAGS: u8 = 81;
+
+// Flag bits set by cmp and fcmp
+pub const EQUAL: usize = 1 << 0;
+pub const GREATER: usize = 1 << 1;
+pub const LESS: usize = 1 << 2;
+
+pub fn register(s: &str) -> Option<Result<u8, &'static str>> {
+  if s == "sp" {
+    return Some(Ok(SP));
+  }
+  let base = match s.chars().next()? {
+    'b' | 'f' => 0,
+    'w' => 20,
+    'd' => 40,
+    'r' => 60,
+    _ => return None
+  };
+  let id = match s[1..].parse::<u8>() {
+    Ok(s) => s,
+    Err(..) => return None
+  };
+  if id >= 20 {
+    return Some(Err("Invalid register number"));
+  }
+  Some(Ok(base + id))
+}
+
+pub fn is_float_register(s: &str) -> bool {
+  s.starts_with('f')
+}
+
+pub fn register_name(reg: u8, float: bool) -> String {
+  match reg {
+    0..=19 if float => format!("f{reg}"),
+    0..=19 => format!("b{reg}"),
+    20..=39 => format!("w{}", reg - 20),
+    40..=59 => format!("d{}", reg - 40),
+    60..=79 => format!("r{}", reg - 60),
+    SP => "sp".to_owned(),
+    _ => format!("<register {reg}>")
+  }
+}
+
+// Width in bytes of the register, and of memory reached through it
+pub fn register_size(reg: u8) -> usize {
+  match reg {
+    0..=19 => 1,
+    20..=39 => 2,
+    40..=59 => 4,
+    _ => 8
+  }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Opcode {
+  NOP = 0,
+  MOV = 1,
+  ADD = 2,
+  SUB = 3,
+  MUL = 4,
+  DIV = 5,
+  MOD = 6,
+  OR = 7,
+  AND = 8,
+  XOR = 9,
+  SHL = 10,
+  SHR = 11,
+  JMP = 12,
+  JE = 13,
+  JNE = 14,
+  JGE = 15,
+  JGT = 16,
+  JLE = 17,
+  JLT = 18,
+  CALL = 19,
+  FMOV = 20,
+  FADD = 21,
+  FSUB = 22,
+  FMUL = 23,
+  FDIV = 24,
+  FMOD = 25,
+  INC = 26,
+  DEC = 27,
+  FINC = 28,
+  FDEC = 29,
+  SET = 30,
+  CLEAR = 31,
+  FPUSH = 32,
+  FPOP = 33,
+  PUSH = 34,
+  POP = 35,
+  LEA = 36,
+  RET = 37,
+  CMP = 38,
+  FCMP = 39,
+  SETEX = 40,
+  SETFLAGS = 41,
+  AGDT = 42,
+  SYSCALL = 50
+}
+
+impl Opcode {
+  pub fn new(code: u32) -> Option<Self> {
+    by_opcode(code).map(|i| i.opcode)
+  }
+}
+
+// What an operand may be
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Operand {
+  REG,
+  FREG,
+  // Integer immediate
+  IMM,
+  // Integer immediate which is a code address
+  ADDR,
+  DECIMAL,
+  // Memory through a register, [reg + offset]
+  OFFSET
+}
+
+impl Operand {
+  // For messages such as "Operand 1 of fadd must be a floating point register"
+  pub fn describe(&self) -> &'static str {
+    match self {
+      Operand::REG => "an integer register",
+      Operand::FREG => "a floating point register",
+      Operand::IMM => "an integer",
+      Operand::ADDR => "a function or label",
+      Operand::DECIMAL => "a floating point number",
+      Operand::OFFSET => "a memory operand like [r0 + 8]"
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Flags {
+  NONE,
+  // Reads the flags left by cmp or fcmp
+  READ,
+  WRITE
+}
+
+#[derive(Debug)]
+pub struct Instruction {
+  pub mnemonic: &'static str,
+  pub opcode: Opcode,
+  // Allowed kinds of every operand
+  pub operands: &'static [&'static [Operand]],
+  // Only runs from firmware, syscall 0 is privileged too
+  pub privileged: bool,
+  pub flags: Flags
+}
+
+impl Instruction {
+  // Registers below 20 are f0-f19 for these
+  pub fn is_float(&self) -> bool {
+    self.operands.iter().any(|o| o.contains(&Operand::FREG))
+  }
+}
+
+const INT: &[Operand] = &[Operand::REG, Operand::IMM];
+const FLOAT: &[Operand] = &[Operand::FREG, Operand::DECIMAL];
+const MEMORY: &[Operand] = &[Operand::REG, Operand::OFFSET];
+
+const fn ins(mnemonic: &'static str, opcode: Opcode, operands: &'static [&'static [Operand]]) -> Instruction {
+  Instruction { mnemonic, opcode, operands, privileged: false, flags: Flags::NONE }
+}
+
+const fn flags(ins: Instruction, flags: Flags) -> Instruction {
+  Instruction { flags, ..ins }
+}
+
+const fn privileged(ins: Instruction) -> Instruction {
+  Instruction { privileged: true, ..ins }
+}
+
+pub const INSTRUCTIONS: &[Instruction] = &[
+  ins("nop", Opcode::NOP, &[]),
+  ins("mov", Opcode::MOV, &[MEMORY, &[Operand::REG, Operand::IMM, Operand::OFFSET]]),
+  ins("add", Opcode::ADD, &[&[Operand::REG], INT, INT]),
+  ins("sub", Opcode::SUB, &[&[Operand::REG], INT, INT]),
+  ins("mul", Opcode::MUL, &[&[Operand::REG], INT, INT]),
+  ins("div", Opcode::DIV, &[&[Operand::REG], INT, INT]),
+  ins("mod", Opcode::MOD, &[&[Operand::REG], INT, INT]),
+  ins("or", Opcode::OR, &[&[Operand::REG], INT, INT]),
+  ins("and", Opcode::AND, &[&[Operand::REG], INT, INT]),
+  ins("xor", Opcode::XOR, &[&[Operand::REG], INT, INT]),
+  ins("shl", Opcode::SHL, &[&[Operand::REG], INT, INT]),
+  ins("shr", Opcode::SHR, &[&[Operand::REG], INT, INT]),
+  ins("jmp", Opcode::JMP, &[&[Operand::ADDR]]),
+  flags(ins("je", Opcode::JE, &[&[Operand::ADDR]]), Flags::READ),
+  flags(ins("jne", Opcode::JNE, &[&[Operand::ADDR]]), Flags::READ),
+  flags(ins("jge", Opcode::JGE, &[&[Operand::ADDR]]), Flags::READ),
+  flags(ins("jgt", Opcode::JGT, &[&[Operand::ADDR]]), Flags::READ),
+  flags(ins("jle", Opcode::JLE, &[&[Operand::ADDR]]), Flags::READ),
+  flags(ins("jlt", Opcode::JLT, &[&[Operand::ADDR]]), Flags::READ),
+  ins("call", Opcode::CALL, &[&[Operand::ADDR]]),
+  ins("fmov", Opcode::FMOV, &[&[Operand::FREG], &[Operand::DECIMAL]]),
+  ins("fadd", Opcode::FADD, &[&[Operand::FREG], FLOAT, FLOAT]),
+  ins("fsub", Opcode::FSUB, &[&[Operand::FREG], FLOAT, FLOAT]),
+  ins("fmul", Opcode::FMUL, &[&[Operand::FREG], FLOAT, FLOAT]),
+  ins("fdiv", Opcode::FDIV, &[&[Operand::FREG], FLOAT, FLOAT]),
+  ins("fmod", Opcode::FMOD, &[&[Operand::FREG], FLOAT, FLOAT]),
+  ins("inc", Opcode::INC, &[MEMORY]),
+  ins("dec", Opcode::DEC, &[MEMORY]),
+  ins("finc", Opcode::FINC, &[&[Operand::FREG]]),
+  ins("fdec", Opcode::FDEC, &[&[Operand::FREG]]),
+  ins("set", Opcode::SET, &[&[Operand::REG], &[Operand::IMM]]),
+  ins("clear", Opcode::CLEAR, &[&[Operand::REG], &[Operand::IMM]]),
+  ins("fpush", Opcode::FPUSH, &[FLOAT]),
+  ins("fpop", Opcode::FPOP, &[&[Operand::FREG]]),
+  ins("push", Opcode::PUSH, &[INT]),
+  ins("pop", Opcode::POP, &[&[Operand::REG]]),
+  ins("lea", Opcode::LEA, &[&[Operand::REG], &[Operand::OFFSET]]),
+  ins("ret", Opcode::RET, &[]),
+  flags(ins("cmp", Opcode::CMP, &[INT, INT]), Flags::WRITE),
+  flags(ins("fcmp", Opcode::FCMP, &[FLOAT, FLOAT]), Flags::WRITE),
+  privileged(ins("setex", Opcode::SETEX, &[&[Operand::ADDR]])),
+  privileged(flags(ins("setflags", Opcode::SETFLAGS, &[]), Flags::WRITE)),
+  privileged(ins("agdt", Opcode::AGDT, &[&[Operand::IMM], &[Operand::IMM], &[Operand::IMM]])),
+  ins("syscall", Opcode::SYSCALL, &[&[Operand::IMM]])
+];
+
+// Mnemonics are all lower or all upper case
+pub fn by_name(name: &str) -> Option<&'static Instruction> {
+  INSTRUCTIONS.iter().find(|i| i.mnemonic == name || i.mnemonic.to_uppercase() == name)
+}
+
+// Every opcode a prologue can hold
+const OPCODES: usize = 1 << (32 - OPCODE_SHIFT);
+
+const fn opcode_table() -> [Option<&'static Instruction>; OPCODES] {
+  let mut table: [Option<&'static Instruction>; OPCODES] = [None; OPCODES];
+  let mut i = 0;
+  while i < INSTRUCTIONS.len() {
+    table[INSTRUCTIONS[i].opcode as usize] = Some(&INSTRUCTIONS[i]);
+    i += 1;
+  }
+  table
+}
+
+// Indexed by opcode as the runtime decodes every instruction through it
+static BY_OPCODE: [Option<&Instruction>; OPCODES] = opcode_table();
+
+pub fn by_opcode(code: u32) -> Option<&'static Instruction> {
+  *BY_OPCODE.get(code as usize)?
+}
@@ -0,0 +1,7 @@
+// Code shared by the blitz tools
+pub mod compiler;
+pub mod debug;
+pub mod isa;
+pub mod object;
+pub mod link;
+pub mod archive;
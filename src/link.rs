@@ -0,0 +1,304 @@
+use crate::debug::{self, Debug};
+use crate::object::{Field, Kind, Object, Section};
+extern crate file_utils;
+use file_utils::write::Write;
+
+// Executable header, checked by blitz and bldis. Version 2 added the
+// compact immediates and 2.1 the debug section, older executables are
+// still run. The layout is
+//
+// u32 magic, u16 major, u16 minor
+// u64 entry point, u64 offset of the data
+// u64 offset of the debug section or 0, from 2.1 on
+pub const MAGIC: u32 = 0xAFC;
+pub const MAJOR: u16 = 0x2;
+pub const MINOR: u16 = 0x1;
+
+// Code follows the header, data is loaded by blitz at DATA_BEGIN
+pub const CODE_BEGIN: usize = 32;
+pub const DATA_BEGIN: usize = 0x7E000;
+
+pub struct Header {
+  pub major: u16,
+  pub minor: u16,
+  pub start: usize,
+  // Where the code starts, which is after the header of its version
+  pub code_begin: usize,
+  pub data_offset: usize,
+  // The data runs up to the debug section, if there is one
+  pub data_end: usize,
+  pub debug_offset: Option<usize>
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> usize {
+  u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+impl Header {
+  pub fn read(bytes: &[u8]) -> Result<Self, String> {
+    if bytes.len() < 24 {
+      return Err("File is too short to be a blitz executable".to_owned());
+    }
+    if u32::from_le_bytes(bytes[0..4].try_into().unwrap()) != MAGIC {
+      return Err("Not a blitz executable".to_owned());
+    }
+    let major = u16::from_le_bytes(bytes[4..6].try_into().unwrap());
+    let minor = u16::from_le_bytes(bytes[6..8].try_into().unwrap());
+    let code_begin = match (major, minor) {
+      (1, 0) | (2, 0) => 24,
+      (2, 1) if bytes.len() >= CODE_BEGIN => CODE_BEGIN,
+      (2, 1) => return Err("File is too short to be a blitz executable".to_owned()),
+      _ => return Err(format!("Unsupported blitz version {major}.{minor}"))
+    };
+    let start = u64_at(bytes, 8);
+    let data_offset = u64_at(bytes, 16);
+    let debug_offset = match code_begin {
+      CODE_BEGIN => Some(u64_at(bytes, 24)).filter(|o| *o != 0),
+      _ => None
+    };
+    let data_end = debug_offset.unwrap_or(bytes.len());
+    if data_offset < code_begin || data_offset > bytes.len() {
+      return Err(format!("Data offset {data_offset:#x} is outside of the file"));
+    }
+    if data_end < data_offset || data_end > bytes.len() {
+      return Err(format!("Debug section offset {data_end:#x} is outside of the file"));
+    }
+    if start < code_begin || start >= data_offset {
+      return Err(format!("Entry point {start:#x} is outside of the code"));
+    }
+    Ok(Self { major, minor, start, code_begin, data_offset, data_end, debug_offset })
+  }
+
+  // The debug section, if the executable has a well formed one
+  pub fn debug(&self, bytes: &[u8]) -> Option<Debug> {
+    Debug::read(&mut &bytes[self.debug_offset?..]).ok()
+  }
+}
+
+// A symbol once its object has been placed
+pub struct Placed {
+  pub name: String,
+  pub kind: Kind,
+  pub address: usize,
+  pub size: usize,
+  pub global: bool,
+  pub file: String,
+  pub line: usize
+}
+
+pub struct Image {
+  // The executable, header included
+  pub bytes: Vec<u8>,
+  pub symbols: Vec<Placed>,
+  // Where the code and data of every object ended up
+  pub code: Vec<usize>,
+  pub data: Vec<usize>,
+  // Offset of the data section in the executable
+  pub data_offset: usize,
+  pub debug: Debug
+}
+
+impl Image {
+  // Bytes of the executable which are loaded at address
+  pub fn at(&self, address: usize, len: usize, data_begin: usize) -> &[u8] {
+    let start = match address >= data_begin {
+      true => self.data_offset + address - data_begin,
+      false => address
+    };
+    &self.bytes[start..start + len]
+  }
+}
+
+fn patch(buf: &mut [u8], offset: usize, field: Field, value: i64) -> Result<(), String> {
+  let width = field.width();
+  if offset.checked_add(width).is_none_or(|end| end > buf.len()) {
+    return Err("Relocation is outside of its section".to_owned());
+  }
+  let bytes: [u8; 8] = match field {
+    Field::IMM => value.to_le_bytes(),
+    Field::IMM32 => match i32::try_from(value) {
+      Ok(..) => value.to_le_bytes(),
+      Err(..) => return Err("Value does not fit in a 4 byte immediate".to_owned())
+    },
+    Field::OFFSET => {
+      if !(-(1 << 56)..1 << 56).contains(&value) {
+        return Err("Offset does not fit in 57 bits".to_owned());
+      }
+      let mask = (1u64 << 57) - 1;
+      let old = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+      ((old & !mask) | (value as u64 & mask)).to_le_bytes()
+    }
+    Field::BYTES(n) => {
+      let bits = n * 8;
+      if bits < 64 && (value < -(1 << (bits - 1)) || value >= 1 << bits) {
+        return Err(format!("Value does not fit in {n} bytes"));
+      }
+      value.to_le_bytes()
+    }
+  };
+  buf[offset..offset + width].copy_from_slice(&bytes[..width]);
+  Ok(())
+}
+
+// Places the objects one after the other, resolves every relocation and
+// writes the executable. Symbols are looked up in the referring object
+// first and then among the global symbols of all objects
+pub fn link(objects: &[Object], entry: &str, data_begin: usize, with_debug: bool) -> Result<Image, Vec<String>> {
+  let mut errors: Vec<String> = Vec::new();
+  let mut code_base: Vec<usize> = Vec::new();
+  let mut address = CODE_BEGIN;
+  let too_large = || vec!["Objects are too large to be linked".to_owned()];
+  for obj in objects {
+    code_base.push(address);
+    address = address.checked_add(obj.code.len()).ok_or_else(too_large)?;
+  }
+  let data_offset = address;
+  let mut data_base: Vec<usize> = Vec::new();
+  let mut address = data_begin;
+  for obj in objects {
+    let align = obj.data_align.max(1);
+    address = address.checked_add((align - address % align) % align).ok_or_else(too_large)?;
+    data_base.push(address);
+    address = address.checked_add(obj.data.len()).ok_or_else(too_large)?;
+  }
+
+  let mut symbols: Vec<Placed> = Vec::new();
+  // Index into symbols of every global along with its object
+  let mut globals: Vec<(usize, usize)> = Vec::new();
+  for (index, obj) in objects.iter().enumerate() {
+    for sym in &obj.symbols {
+      let base = match sym.section {
+        Section::CODE => code_base[index],
+        Section::DATA => data_base[index]
+      };
+      if sym.global {
+        if let Some(other) = globals.iter().find(|g| symbols[g.0].name == sym.name) {
+          errors.push(format!("Symbol {} is defined in both {} and {}", sym.name, objects[other.1].name, obj.name));
+        }
+        globals.push((symbols.len(), index));
+      }
+      symbols.push(Placed {
+        name: sym.name.clone(),
+        kind: sym.kind,
+        address: base.checked_add(sym.offset).ok_or_else(too_large)?,
+        size: sym.size,
+        global: sym.global,
+        file: sym.file.clone(),
+        line: sym.line
+      });
+    }
+  }
+
+  let mut code: Vec<u8> = Vec::new();
+  let mut data: Vec<u8> = Vec::new();
+  let mut first = 0;
+  for (index, obj) in objects.iter().enumerate() {
+    let own = &symbols[first..first + obj.symbols.len()];
+    first += obj.symbols.len();
+    let resolve = |name: &str| {
+      own.iter().find(|s| s.name == name)
+        .or_else(|| globals.iter().map(|g| &symbols[g.0]).find(|s| s.name == name))
+        .map(|s| s.address as i64)
+    };
+    let mut obj_code = obj.code.clone();
+    let mut obj_data = obj.data.clone();
+    for reloc in &obj.relocs {
+      let res = reloc.expr.eval(&resolve).and_then(|value| match reloc.section {
+        Section::CODE => patch(&mut obj_code, reloc.offset, reloc.field, value),
+        Section::DATA => patch(&mut obj_data, reloc.offset, reloc.field, value)
+      });
+      if let Err(e) = res {
+        errors.push(format!("{} : {e}", obj.name));
+      }
+    }
+    code.extend_from_slice(&obj_code);
+    data.resize(data_base[index] - data_begin, 0);
+    data.extend_from_slice(&obj_data);
+  }
+
+  // Only a global function can be started, never a label inside one
+  let start = match globals.iter().map(|g| &symbols[g.0]).find(|s| s.kind == Kind::FUNC && s.name == entry) {
+    Some(s) => s.address,
+    None => {
+      errors.push(format!("Entry point {entry} is not defined"));
+      0
+    }
+  };
+  if !errors.is_empty() {
+    errors.dedup();
+    return Err(errors);
+  }
+  let debug = debug_section(objects, &symbols, &code_base);
+  let mut bytes: Vec<u8> = Vec::new();
+  // Writing to memory cannot fail
+  bytes.write_u32(MAGIC).unwrap();
+  bytes.write_u16(MAJOR).unwrap();
+  bytes.write_u16(MINOR).unwrap();
+  bytes.write_usize(start).unwrap();
+  bytes.write_usize(data_offset).unwrap();
+  let debug_offset = match with_debug {
+    true => data_offset + data.len(),
+    false => 0
+  };
+  bytes.write_usize(debug_offset).unwrap();
+  bytes.extend_from_slice(&code);
+  bytes.extend_from_slice(&data);
+  if with_debug {
+    debug.write(&mut bytes).unwrap();
+  }
+  Ok(Image {
+    bytes,
+    symbols,
+    code: code_base,
+    data: data_base,
+    data_offset,
+    debug
+  })
+}
+
+// Functions and line tables of all objects at their final addresses,
+// objects without a line table only give their function names
+fn debug_section(objects: &[Object], symbols: &[Placed], code_base: &[usize]) -> Debug {
+  let funcs = symbols.iter().filter(|s| s.kind == Kind::FUNC).map(|s| debug::Function {
+    address: s.address,
+    size: s.size,
+    name: s.name.clone()
+  }).collect();
+  let mut files: Vec<String> = Vec::new();
+  let mut lines: Vec<debug::Line> = Vec::new();
+  for (obj, base) in objects.iter().zip(code_base) {
+    for line in &obj.lines {
+      let name = &obj.files[line.file];
+      let file = match files.iter().position(|f| f == name) {
+        Some(s) => s,
+        None => {
+          files.push(name.clone());
+          files.len() - 1
+        }
+      };
+      lines.push(debug::Line { address: base + line.offset, file, line: line.line });
+    }
+  }
+  Debug { funcs, files, lines }
+}
+
+// Every function, label and data item with its address, lowest first
+//
+// address       size  kind   name          defined at
+// 0x00000018      72  func   print         lib.su:8
+pub fn map(image: &Image) -> String {
+  let mut symbols: Vec<&Placed> = image.symbols.iter().collect();
+  // Stable so a function stays ahead of a label at its first instruction
+  symbols.sort_by_key(|s| s.address);
+  let width = symbols.iter().map(|s| s.name.len()).max().unwrap_or(0).max(4);
+  let mut out = format!("{:10}  {:>6}  {:5}  {:width$}  defined at\n", "address", "size", "kind", "name");
+  for sym in symbols {
+    let kind = match sym.kind {
+      Kind::FUNC => "func",
+      Kind::LABEL => "label",
+      Kind::DATA => "data"
+    };
+    out += &format!("{:#010x}  {:6}  {kind:5}  {:width$}  {}:{}\n", sym.address, sym.size, sym.name, sym.file, sym.line);
+  }
+  out
+}
@@ -0,0 +1,125 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use blitz::archive::{self, Archive};
+use blitz::link::{self, DATA_BEGIN};
+use blitz::object::Object;
+
+// Exit codes, the same as blc
+const EXIT_ERRORS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
+
+const HELP: &str = "Usage: bld [options] <object>...
+
+Links object files written by blc -c into an executable for the blitz VM.
+Archives written by blar may be given among the objects, only the members
+defining a symbol the program needs are linked
+
+Options:
+  -o <path>          Write the executable to path instead of a.out
+  --entry <name>     Start execution at name instead of _start
+  --no-map           Do not write the symbol map next to the executable
+  --no-debug         Leave the function names and line table out
+  -h, --help         Print this message
+  --version          Print the version of bld
+
+Exit status is 0 on success, 1 if the objects could not be linked, 2 for
+bad options and 3 if a file could not be read or written";
+
+fn usage(msg: &str) -> ! {
+  eprintln!("bld: {msg}");
+  eprintln!("Try 'bld --help' for more information");
+  process::exit(EXIT_USAGE);
+}
+
+#[allow(clippy::upper_case_acronyms)]
+enum Input {
+  OBJECT(Object),
+  ARCHIVE(Archive)
+}
+
+// Objects and archives are told apart by their magic number
+fn read(path: &str) -> Result<Input, String> {
+  let bytes = fs::read(path).map_err(|e| format!("Failed to open {path} : {e}"))?;
+  let res = match bytes.get(..4).map(|m| u32::from_le_bytes(m.try_into().unwrap())) {
+    Some(archive::MAGIC) => Archive::read(path, &mut bytes.as_slice()).map(Input::ARCHIVE),
+    _ => Object::read(path, &mut bytes.as_slice()).map(Input::OBJECT)
+  };
+  res.map_err(|e| format!("Failed to read {path} : {e}"))
+}
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let mut inputs: Vec<&str> = Vec::new();
+  let mut output = "a.out";
+  let mut entry = "_start";
+  let mut map = true;
+  let mut debug = true;
+  let mut index = 0;
+  while index < args.len() {
+    let arg = args[index].as_str();
+    index += 1;
+    let mut value = || {
+      index += 1;
+      match args.get(index - 1) {
+        Some(s) => s.as_str(),
+        None => usage(&format!("{arg} needs a value"))
+      }
+    };
+    match arg {
+      "-h" | "--help" => {
+        println!("{HELP}");
+        return;
+      }
+      "--version" => {
+        println!("bld {}", env!("CARGO_PKG_VERSION"));
+        return;
+      }
+      "-o" => output = value(),
+      "--entry" => entry = value(),
+      "--no-map" => map = false,
+      "--no-debug" => debug = false,
+      _ if arg.starts_with('-') && arg.len() > 1 => usage(&format!("Unknown option '{arg}'")),
+      _ => inputs.push(arg)
+    }
+  }
+  if inputs.is_empty() {
+    usage("Input file name needed");
+  }
+  let mut objects: Vec<Object> = Vec::new();
+  let mut archives: Vec<Archive> = Vec::new();
+  for input in &inputs {
+    match read(input) {
+      Ok(Input::OBJECT(s)) => objects.push(s),
+      Ok(Input::ARCHIVE(s)) => archives.push(s),
+      Err(e) => {
+        eprintln!("bld: {e}");
+        process::exit(EXIT_IO);
+      }
+    }
+  }
+  archive::select(&mut objects, &archives, entry);
+  let image = match link::link(&objects, entry, DATA_BEGIN, debug) {
+    Ok(s) => s,
+    Err(e) => {
+      for msg in &e {
+        eprintln!("bld: {msg}");
+      }
+      eprintln!("bld: {} error(s) generated", e.len());
+      process::exit(EXIT_ERRORS);
+    }
+  };
+  if let Err(e) = fs::write(output, &image.bytes) {
+    eprintln!("bld: Failed to write {output} : {e}");
+    process::exit(EXIT_IO);
+  }
+  if map {
+    let map_path = Path::new(output).with_extension("map").display().to_string();
+    if let Err(e) = fs::write(&map_path, link::map(&image)) {
+      eprintln!("bld: Failed to write {map_path} : {e}");
+      process::exit(EXIT_IO);
+    }
+  }
+}
@@ -0,0 +1,221 @@
+use blitz::compiler::diag::Loc;
+use blitz::compiler::file::Sources;
+use blitz::compiler::r#proc::{Lexeme, Lexer, Token};
+use blitz::isa;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Kind {
+  FUNC,
+  LABEL,
+  DATA,
+  DEFINE,
+  MACRO
+}
+
+impl Kind {
+  pub fn describe(&self) -> &'static str {
+    match self {
+      Kind::FUNC => "function",
+      Kind::LABEL => "label",
+      Kind::DATA => "data",
+      Kind::DEFINE => "define",
+      Kind::MACRO => "macro"
+    }
+  }
+}
+
+pub struct Symbol {
+  pub name: String,
+  pub kind: Kind,
+  pub loc: Loc,
+  // Function a label belongs to
+  pub scope: Option<String>
+}
+
+pub struct Reference {
+  pub name: String,
+  pub loc: Loc,
+  pub scope: Option<String>
+}
+
+// Lines from the func to the end of a function
+struct Body {
+  file: usize,
+  first: usize,
+  last: usize,
+  name: String
+}
+
+// Where every function, label, data item, define and macro of the files
+// in sources is declared and used. The files are only lexed, so the index
+// is still there for files which do not parse, and both sides of
+// conditionals are in it
+pub struct Index {
+  pub symbols: Vec<Symbol>,
+  pub refs: Vec<Reference>,
+  bodies: Vec<Body>
+}
+
+fn covers(loc: &Loc, file: usize, line: usize, col: usize) -> bool {
+  loc.file == file && loc.line == line && loc.col <= col && col <= loc.col + loc.len
+}
+
+// Numeric labels are referred to as 1f or 1b, the next or previous 1:
+fn numeric(name: &str) -> Option<(&str, bool)> {
+  let (number, forward) = match name.strip_suffix('f') {
+    Some(s) => (s, true),
+    None => (name.strip_suffix('b')?, false)
+  };
+  match !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) {
+    true => Some((number, forward)),
+    false => None
+  }
+}
+
+fn before(a: &Loc, b: &Loc) -> bool {
+  (a.line, a.col) < (b.line, b.col)
+}
+
+impl Index {
+  pub fn new(sources: &Sources) -> Self {
+    let mut index = Self { symbols: Vec::new(), refs: Vec::new(), bodies: Vec::new() };
+    for (id, file) in sources.files().iter().enumerate() {
+      // -D values and the like are not files
+      if file.name.starts_with('<') {
+        continue;
+      }
+      let lexemes: Vec<Lexeme> = Lexer::new(id, &file.text).filter_map(|l| l.ok()).collect();
+      index.file(&lexemes);
+    }
+    index
+  }
+
+  fn define(&mut self, name: &str, kind: Kind, loc: Loc, scope: &Option<String>) {
+    let scope = match kind {
+      Kind::LABEL => scope.clone(),
+      _ => None
+    };
+    self.symbols.push(Symbol { name: name.to_owned(), kind, loc, scope });
+  }
+
+  fn file(&mut self, lexemes: &[Lexeme]) {
+    let mut func: Option<(String, Loc)> = None;
+    for stmt in lexemes.split(|l| l.tok == Token::NEWLINE) {
+      let scope = func.as_ref().map(|f| f.0.clone());
+      let rest = match (stmt.first().map(|l| &l.tok), stmt.get(1)) {
+        (None, _) => continue,
+        (Some(Token::FUNC), Some(Lexeme { tok: Token::IDENT(name), loc })) => {
+          self.define(name, Kind::FUNC, *loc, &None);
+          func = Some((name.clone(), stmt[0].loc));
+          &stmt[2..]
+        }
+        (Some(Token::ENDFUNC), _) => {
+          if let Some((name, start)) = func.take() {
+            self.bodies.push(Body { file: start.file, first: start.line, last: stmt[0].loc.line, name });
+          }
+          continue;
+        }
+        // Parameters are only names inside the body
+        (Some(Token::MACRO), Some(Lexeme { tok: Token::IDENT(name), loc })) => {
+          self.define(name, Kind::MACRO, *loc, &None);
+          continue;
+        }
+        (Some(Token::DEFINE), Some(Lexeme { tok: Token::IDENT(name), loc })) => {
+          self.define(name, Kind::DEFINE, *loc, &None);
+          &stmt[2..]
+        }
+        (Some(Token::INCLUDE), _) => continue,
+        (Some(Token::LABEL(name)), next) => {
+          // Without the ':'
+          let loc = Loc { len: stmt[0].loc.len - 1, ..stmt[0].loc };
+          match (&scope, next) {
+            (Some(..), _) => self.define(name, Kind::LABEL, loc, &scope),
+            (None, Some(Lexeme { tok: Token::ATTR(..), .. })) => self.define(name, Kind::DATA, loc, &None),
+            _ => {}
+          }
+          &stmt[1..]
+        }
+        _ => stmt
+      };
+      for (i, lexeme) in rest.iter().enumerate() {
+        let name = match &lexeme.tok {
+          Token::IDENT(s) => s,
+          _ => continue
+        };
+        // Mnemonics and registers are not symbols
+        let mnemonic = i == 0 && scope.is_some() && isa::by_name(name).is_some();
+        if mnemonic || isa::register(name).is_some() || name == "local" {
+          continue;
+        }
+        self.refs.push(Reference { name: name.clone(), loc: lexeme.loc, scope: scope.clone() });
+      }
+    }
+    // A function missing its end runs to the end of the file
+    if let Some((name, start)) = func {
+      self.bodies.push(Body { file: start.file, first: start.line, last: usize::MAX, name });
+    }
+  }
+
+  // The function the line is in
+  pub fn scope(&self, file: usize, line: usize) -> Option<&str> {
+    self.bodies.iter()
+      .find(|b| b.file == file && b.first <= line && line <= b.last)
+      .map(|b| b.name.as_str())
+  }
+
+  // What a name used at loc refers to. Labels of the function come before
+  // anything global, and defines in both sides of a conditional are found
+  pub fn resolve(&self, name: &str, scope: Option<&str>, loc: &Loc) -> Vec<&Symbol> {
+    if let Some((number, forward)) = numeric(name) {
+      let labels = self.symbols.iter()
+        .filter(|s| s.kind == Kind::LABEL && s.name == number && s.loc.file == loc.file && s.scope.as_deref() == scope);
+      let found = match forward {
+        true => labels.filter(|s| before(loc, &s.loc)).min_by_key(|s| (s.loc.line, s.loc.col)),
+        false => labels.filter(|s| before(&s.loc, loc)).max_by_key(|s| (s.loc.line, s.loc.col))
+      };
+      return found.into_iter().collect();
+    }
+    let labels: Vec<&Symbol> = self.symbols.iter()
+      .filter(|s| s.kind == Kind::LABEL && s.name == name && scope.is_some() && s.scope.as_deref() == scope)
+      .collect();
+    if !labels.is_empty() {
+      return labels;
+    }
+    self.symbols.iter().filter(|s| s.kind != Kind::LABEL && s.name == name).collect()
+  }
+
+  // The symbols declared or used at the position
+  pub fn lookup(&self, file: usize, line: usize, col: usize) -> Vec<&Symbol> {
+    if let Some(sym) = self.symbols.iter().find(|s| covers(&s.loc, file, line, col)) {
+      return vec![sym];
+    }
+    match self.refs.iter().find(|r| covers(&r.loc, file, line, col)) {
+      Some(r) => self.resolve(&r.name, r.scope.as_deref(), &r.loc),
+      None => Vec::new()
+    }
+  }
+
+  // Every use of sym
+  pub fn references(&self, sym: &Symbol) -> Vec<Loc> {
+    self.refs.iter()
+      .filter(|r| self.resolve(&r.name, r.scope.as_deref(), &r.loc).iter().any(|s| std::ptr::eq(*s, sym)))
+      .map(|r| r.loc)
+      .collect()
+  }
+
+  // Symbols which can be used in the function, or at top level for None
+  pub fn visible(&self, scope: Option<&str>) -> Vec<&Symbol> {
+    let mut found: Vec<&Symbol> = Vec::new();
+    for sym in &self.symbols {
+      let usable = match sym.kind {
+        Kind::LABEL => scope.is_some() && sym.scope.as_deref() == scope,
+        _ => true
+      };
+      if usable && !found.iter().any(|s| s.name == sym.name && s.kind == sym.kind) {
+        found.push(sym);
+      }
+    }
+    found
+  }
+}
@@ -0,0 +1,273 @@
+use std::fmt;
+
+// Just as much JSON as the language server protocol needs. Objects keep
+// their keys in order, which is all that is needed for messages this small
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+  NULL,
+  BOOL(bool),
+  NUMBER(f64),
+  STRING(String),
+  ARRAY(Vec<Json>),
+  OBJECT(Vec<(String, Json)>)
+}
+
+// What get() finds for missing keys
+static NULL: Json = Json::NULL;
+
+impl Json {
+  pub fn parse(text: &str) -> Result<Json, &'static str> {
+    let mut parser = Parser { text: text.chars().collect(), pos: 0 };
+    let value = parser.value()?;
+    parser.blanks();
+    match parser.pos == parser.text.len() {
+      true => Ok(value),
+      false => Err("Unexpected text after JSON value")
+    }
+  }
+
+  pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+    Json::OBJECT(pairs.into_iter().map(|p| (p.0.to_owned(), p.1)).collect())
+  }
+
+  pub fn string(s: &str) -> Json {
+    Json::STRING(s.to_owned())
+  }
+
+  pub fn int(n: usize) -> Json {
+    Json::NUMBER(n as f64)
+  }
+
+  // The value under key, NULL for missing keys and anything but objects
+  pub fn get(&self, key: &str) -> &Json {
+    match self {
+      Json::OBJECT(pairs) => pairs.iter().find(|p| p.0 == key).map_or(&NULL, |p| &p.1),
+      _ => &NULL
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Json::STRING(s) => Some(s),
+      _ => None
+    }
+  }
+
+  pub fn as_usize(&self) -> Option<usize> {
+    match self {
+      Json::NUMBER(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+      _ => None
+    }
+  }
+
+  pub fn as_bool(&self) -> Option<bool> {
+    match self {
+      Json::BOOL(b) => Some(*b),
+      _ => None
+    }
+  }
+
+  pub fn as_array(&self) -> &[Json] {
+    match self {
+      Json::ARRAY(items) => items,
+      _ => &[]
+    }
+  }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+  write!(f, "\"")?;
+  for c in s.chars() {
+    match c {
+      '"' => write!(f, "\\\"")?,
+      '\\' => write!(f, "\\\\")?,
+      '\n' => write!(f, "\\n")?,
+      '\r' => write!(f, "\\r")?,
+      '\t' => write!(f, "\\t")?,
+      c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+      c => write!(f, "{c}")?
+    }
+  }
+  write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Json::NULL => write!(f, "null"),
+      Json::BOOL(b) => write!(f, "{b}"),
+      Json::NUMBER(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+      Json::NUMBER(n) => write!(f, "{n}"),
+      Json::STRING(s) => write_str(f, s),
+      Json::ARRAY(items) => {
+        write!(f, "[")?;
+        for (i, item) in items.iter().enumerate() {
+          if i != 0 {
+            write!(f, ",")?;
+          }
+          write!(f, "{item}")?;
+        }
+        write!(f, "]")
+      }
+      Json::OBJECT(pairs) => {
+        write!(f, "{{")?;
+        for (i, (key, value)) in pairs.iter().enumerate() {
+          if i != 0 {
+            write!(f, ",")?;
+          }
+          write_str(f, key)?;
+          write!(f, ":{value}")?;
+        }
+        write!(f, "}}")
+      }
+    }
+  }
+}
+
+struct Parser {
+  text: Vec<char>,
+  pos: usize
+}
+
+impl Parser {
+  fn peek(&self) -> Option<char> {
+    self.text.get(self.pos).copied()
+  }
+
+  fn blanks(&mut self) {
+    while let Some(' ' | '\t' | '\r' | '\n') = self.peek() {
+      self.pos += 1;
+    }
+  }
+
+  fn expect(&mut self, c: char) -> Result<(), &'static str> {
+    self.blanks();
+    match self.peek() == Some(c) {
+      true => {
+        self.pos += 1;
+        Ok(())
+      }
+      false => Err("Malformed JSON")
+    }
+  }
+
+  fn keyword(&mut self, word: &str, value: Json) -> Result<Json, &'static str> {
+    for c in word.chars() {
+      if self.peek() != Some(c) {
+        return Err("Malformed JSON");
+      }
+      self.pos += 1;
+    }
+    Ok(value)
+  }
+
+  fn value(&mut self) -> Result<Json, &'static str> {
+    self.blanks();
+    match self.peek() {
+      Some('n') => self.keyword("null", Json::NULL),
+      Some('t') => self.keyword("true", Json::BOOL(true)),
+      Some('f') => self.keyword("false", Json::BOOL(false)),
+      Some('"') => Ok(Json::STRING(self.string()?)),
+      Some('[') => {
+        self.pos += 1;
+        let mut items: Vec<Json> = Vec::new();
+        self.blanks();
+        if self.peek() == Some(']') {
+          self.pos += 1;
+          return Ok(Json::ARRAY(items));
+        }
+        loop {
+          items.push(self.value()?);
+          self.blanks();
+          match self.peek() {
+            Some(',') => self.pos += 1,
+            Some(']') => {
+              self.pos += 1;
+              return Ok(Json::ARRAY(items));
+            }
+            _ => return Err("Expected ',' or ']' in JSON array")
+          }
+        }
+      }
+      Some('{') => {
+        self.pos += 1;
+        let mut pairs: Vec<(String, Json)> = Vec::new();
+        self.blanks();
+        if self.peek() == Some('}') {
+          self.pos += 1;
+          return Ok(Json::OBJECT(pairs));
+        }
+        loop {
+          self.blanks();
+          if self.peek() != Some('"') {
+            return Err("Expected a key in JSON object");
+          }
+          let key = self.string()?;
+          self.expect(':')?;
+          pairs.push((key, self.value()?));
+          self.blanks();
+          match self.peek() {
+            Some(',') => self.pos += 1,
+            Some('}') => {
+              self.pos += 1;
+              return Ok(Json::OBJECT(pairs));
+            }
+            _ => return Err("Expected ',' or '}' in JSON object")
+          }
+        }
+      }
+      Some(c) if c == '-' || c.is_ascii_digit() => {
+        let start = self.pos;
+        while let Some('0'..='9' | '-' | '+' | '.' | 'e' | 'E') = self.peek() {
+          self.pos += 1;
+        }
+        let text: String = self.text[start..self.pos].iter().collect();
+        text.parse::<f64>().map(Json::NUMBER).map_err(|_| "Malformed JSON number")
+      }
+      _ => Err("Malformed JSON")
+    }
+  }
+
+  fn hex4(&mut self) -> Result<u32, &'static str> {
+    let digits: String = self.text.get(self.pos..self.pos + 4).ok_or("Malformed JSON escape")?.iter().collect();
+    self.pos += 4;
+    u32::from_str_radix(&digits, 16).map_err(|_| "Malformed JSON escape")
+  }
+
+  // Expects to be at the opening quote
+  fn string(&mut self) -> Result<String, &'static str> {
+    self.pos += 1;
+    let mut buf = String::new();
+    loop {
+      let c = self.peek().ok_or("Unclosed JSON string")?;
+      self.pos += 1;
+      match c {
+        '"' => return Ok(buf),
+        '\\' => {
+          let c = self.peek().ok_or("Unclosed JSON string")?;
+          self.pos += 1;
+          match c {
+            'n' => buf.push('\n'),
+            't' => buf.push('\t'),
+            'r' => buf.push('\r'),
+            'b' => buf.push('\u{8}'),
+            'f' => buf.push('\u{c}'),
+            'u' => {
+              let mut code = self.hex4()?;
+              // Characters outside the BMP come as a surrogate pair
+              if (0xD800..0xDC00).contains(&code) && self.text.get(self.pos..self.pos + 2) == Some(&['\\', 'u']) {
+                self.pos += 2;
+                let low = self.hex4()?;
+                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+              }
+              buf.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+            }
+            c => buf.push(c)
+          }
+        }
+        c => buf.push(c)
+      }
+    }
+  }
+}
@@ -0,0 +1,587 @@
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+use blitz::compiler::assemble::{assemble, Options};
+use blitz::compiler::diag::{Diagnostic, Level, Loc};
+use blitz::compiler::file::Sources;
+use blitz::compiler::lint::Config;
+use blitz::isa::{self, Flags, Instruction, Operand};
+mod index;
+use index::{Index, Kind, Symbol};
+mod json;
+use json::Json;
+
+// Exit codes, the same as blc. 1 is also what the protocol asks for when
+// the client exits without shutting the server down first
+const EXIT_ERRORS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+const HELP: &str = "Usage: blsp [options]
+
+Language server for blitz assembly (.su files), speaking the language server
+protocol on standard input and output. Open files are assembled like blc -c
+would as they change, everything they include is read from the disk unless
+it is open too
+
+Options:
+  -D <name>[=value]  Define name, the value defaults to 1
+  -I <dir>           Search dir for included files
+  -W<option>         Warning options as for blc e.g -Wno-unused
+  -h, --help         Print this message
+  --version          Print the version of blsp";
+
+fn usage(msg: &str) -> ! {
+  eprintln!("blsp: {msg}");
+  eprintln!("Try 'blsp --help' for more information");
+  process::exit(EXIT_USAGE);
+}
+
+// Messages are JSON with a Content-Length header in front, None once the
+// client has closed standard input
+fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Json>> {
+  let mut length: Option<usize> = None;
+  loop {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+      return Ok(None);
+    }
+    let line = line.trim_end();
+    if line.is_empty() {
+      break;
+    }
+    if let Some((name, value)) = line.split_once(':') {
+      if name.eq_ignore_ascii_case("Content-Length") {
+        length = value.trim().parse().ok();
+      }
+    }
+  }
+  let length = length.ok_or(io::Error::new(io::ErrorKind::InvalidData, "Message without a Content-Length"))?;
+  let mut body = vec![0u8; length];
+  reader.read_exact(&mut body)?;
+  let text = String::from_utf8(body).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Message is not UTF-8"))?;
+  Json::parse(&text).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn send(message: Json) {
+  let text = message.to_string();
+  let mut out = io::stdout().lock();
+  // The client is gone if this fails, and it will close standard input
+  let _ = write!(out, "Content-Length: {}\r\n\r\n{text}", text.len());
+  let _ = out.flush();
+}
+
+// Only file URIs are understood, e.g file:///home/me/hello.su
+fn path_of(uri: &str) -> Option<PathBuf> {
+  let encoded = uri.strip_prefix("file://")?.as_bytes();
+  let mut bytes: Vec<u8> = Vec::new();
+  let mut i = 0;
+  while i < encoded.len() {
+    let byte = match (encoded[i], encoded.get(i + 1..i + 3)) {
+      (b'%', Some(hex)) => match u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16) {
+        Ok(b) => {
+          i += 2;
+          b
+        }
+        Err(..) => b'%'
+      },
+      (b, _) => b
+    };
+    bytes.push(byte);
+    i += 1;
+  }
+  String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+fn uri_of(path: &Path) -> String {
+  let mut uri = String::from("file://");
+  for b in path.display().to_string().bytes() {
+    match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(b as char),
+      _ => uri += &format!("%{b:02X}")
+    }
+  }
+  uri
+}
+
+// Positions in the protocol count lines from 0 and characters in UTF-16
+// units, Locs count both from 1 and columns in characters
+fn position(sources: &Sources, file: usize, line: usize, col: usize) -> Json {
+  let text = sources.get(file).line(line);
+  let character: usize = text.chars().take(col.saturating_sub(1)).map(|c| c.len_utf16()).sum();
+  Json::object(vec![("line", Json::int(line.saturating_sub(1))), ("character", Json::int(character))])
+}
+
+fn range(sources: &Sources, loc: &Loc) -> Json {
+  Json::object(vec![
+    ("start", position(sources, loc.file, loc.line, loc.col)),
+    ("end", position(sources, loc.file, loc.line, loc.col + loc.len))
+  ])
+}
+
+fn location(sources: &Sources, loc: &Loc) -> Json {
+  let path = sources.canonical(Path::new(&sources.get(loc.file).name));
+  Json::object(vec![("uri", Json::STRING(uri_of(&path))), ("range", range(sources, loc))])
+}
+
+// Column in characters, from 1, of a protocol character offset
+fn column(text: &str, character: usize) -> usize {
+  let mut units = 0;
+  let mut col = 1;
+  for c in text.chars() {
+    if units >= character {
+      break;
+    }
+    units += c.len_utf16();
+    col += 1;
+  }
+  col
+}
+
+// What an open file assembles to, along with everything it includes
+struct Analysis {
+  sources: Sources,
+  index: Index,
+  // The open file among the sources
+  file: Option<usize>,
+  diags: Vec<Diagnostic>
+}
+
+impl Analysis {
+  // Line and column of a textDocument/position pair
+  fn at(&self, params: &Json) -> Option<(usize, usize, usize)> {
+    let file = self.file?;
+    let line = params.get("position").get("line").as_usize()? + 1;
+    let character = params.get("position").get("character").as_usize()?;
+    Some((file, line, column(self.sources.get(file).line(line), character)))
+  }
+
+  // The word under the cursor, as far as it can be a name
+  fn word(&self, file: usize, line: usize, col: usize) -> Option<String> {
+    let text: Vec<char> = self.sources.get(file).line(line).chars().collect();
+    let is_word = |c: &char| c.is_alphanumeric() || *c == '_' || *c == '.';
+    // The cursor may also be just after the word
+    let pos = (col - 1).min(text.len());
+    let pos = match text.get(pos).is_some_and(is_word) {
+      true => pos,
+      false if pos > 0 && is_word(&text[pos - 1]) => pos - 1,
+      false => return None
+    };
+    let start = text[..pos].iter().rposition(|c| !is_word(c)).map_or(0, |i| i + 1);
+    let end = text[pos..].iter().position(|c| !is_word(c)).map_or(text.len(), |i| pos + i);
+    Some(text[start..end].iter().collect())
+  }
+}
+
+fn signature(ins: &Instruction) -> String {
+  let operands: Vec<String> = ins.operands.iter().map(|kinds| {
+    let names: Vec<&str> = kinds.iter().map(|k| match k {
+      Operand::REG => "reg",
+      Operand::FREG => "freg",
+      Operand::IMM => "imm",
+      Operand::ADDR => "label",
+      Operand::DECIMAL => "float",
+      Operand::OFFSET => "[reg + off]"
+    }).collect();
+    names.join("|")
+  }).collect();
+  match operands.len() {
+    0 => ins.mnemonic.to_owned(),
+    _ => format!("{} {}", ins.mnemonic, operands.join(", "))
+  }
+}
+
+fn describe_instruction(ins: &Instruction) -> String {
+  let mut text = format!("```\n{}\n```\n", signature(ins));
+  for (i, kinds) in ins.operands.iter().enumerate() {
+    let names: Vec<&str> = kinds.iter().map(|k| k.describe()).collect();
+    text += &format!("\n* Operand {} is {}", i + 1, names.join(" or "));
+  }
+  match ins.flags {
+    Flags::READ => text += "\n\nReads the flags set by cmp or fcmp",
+    Flags::WRITE => text += "\n\nSets the flags read by the conditional jumps",
+    Flags::NONE => {}
+  }
+  if ins.privileged {
+    text += "\n\nPrivileged, only runs in `.firmware` functions";
+  }
+  text
+}
+
+fn describe_register(name: &str, reg: u8) -> String {
+  if reg == isa::SP {
+    return "`sp` stack pointer, 8 bytes wide\n\nThe stack grows downwards and has to be 8 byte aligned at all times".to_owned();
+  }
+  let id = reg % 20;
+  if isa::is_float_register(name) {
+    return format!("`{name}` floating point register, 8 bytes wide");
+  }
+  let size = isa::register_size(reg);
+  let mut text = format!("`{name}` integer register, {size} byte{} wide", if size == 1 { "" } else { "s" });
+  text += &format!("\n\nb{id}, w{id}, d{id} and r{id} are the low 1, 2, 4 and all 8 bytes of the same register");
+  if size != 8 {
+    text += &format!(", writing {name} clears the rest of r{id}");
+  }
+  text += match id {
+    0..=6 => "\n\nPasses parameters between function calls",
+    7..=14 => "\n\nSaved across function calls, push it before changing it",
+    _ => "\n\nScratch register"
+  };
+  text
+}
+
+fn describe_symbol(sources: &Sources, sym: &Symbol) -> String {
+  let file = sources.get(sym.loc.file);
+  let name = match &sym.scope {
+    Some(func) => format!("{func}.{}", sym.name),
+    None => sym.name.clone()
+  };
+  let path = Path::new(&file.name).file_name().map_or(file.name.clone(), |s| s.to_string_lossy().to_string());
+  format!("{} `{name}` ({path}:{})\n```\n{}\n```", sym.kind.describe(), sym.loc.line, file.line(sym.loc.line).trim())
+}
+
+struct Server {
+  // Open files as (uri, text)
+  docs: Vec<(String, String)>,
+  analyses: Vec<(String, Analysis)>,
+  defines: Vec<String>,
+  search: Vec<PathBuf>,
+  warnings: Vec<String>,
+  shutdown: bool
+}
+
+impl Server {
+  fn analyse(&self, path: &Path) -> Analysis {
+    let open = self.docs.iter().filter_map(|d| Some((path_of(&d.0)?.display().to_string(), d.1.clone())));
+    let mut sources = Sources::overlay(open.collect());
+    let mut options = Options::new(&[&path.display().to_string()]);
+    options.firmware = None;
+    options.object = true;
+    options.gc = false;
+    options.debug = false;
+    options.search = self.search.clone();
+    // Both were checked when the server started
+    let mut warnings = Config::new();
+    for w in &self.warnings {
+      let _ = warnings.set(w);
+    }
+    options.warnings = warnings;
+    for def in &self.defines {
+      let _ = options.define(&mut sources, def);
+    }
+    // A file which crashes the assembler still gets an index, and the
+    // other open files keep their server
+    let diags = match panic::catch_unwind(AssertUnwindSafe(|| assemble(&mut sources, &options))) {
+      Ok(Ok(exe)) => exe.warnings,
+      Ok(Err(e)) => e,
+      Err(..) => vec![Diagnostic::bare("The assembler crashed on this file")]
+    };
+    let index = Index::new(&sources);
+    let canonical = sources.canonical(path);
+    let file = sources.files().iter().position(|f| !f.name.starts_with('<') && sources.canonical(Path::new(&f.name)) == canonical);
+    Analysis { sources, index, file, diags }
+  }
+
+  // Files include each other, so every open file is assembled again when
+  // any of them changes
+  fn refresh(&mut self) {
+    self.analyses.clear();
+    for (uri, _) in &self.docs {
+      if let Some(path) = path_of(uri) {
+        self.analyses.push((uri.clone(), self.analyse(&path)));
+      }
+    }
+    for (uri, analysis) in &self.analyses {
+      publish(uri, analysis);
+    }
+  }
+
+  fn analysis(&self, params: &Json) -> Result<&Analysis, String> {
+    let uri = params.get("textDocument").get("uri").as_str().ok_or("Missing textDocument")?;
+    match self.analyses.iter().find(|a| a.0 == uri) {
+      Some(a) => Ok(&a.1),
+      None => Err(format!("{uri} is not open"))
+    }
+  }
+
+  fn notify(&mut self, method: &str, params: &Json) {
+    let uri = params.get("textDocument").get("uri").as_str().unwrap_or("").to_owned();
+    match method {
+      "textDocument/didOpen" => {
+        let text = params.get("textDocument").get("text").as_str().unwrap_or("").to_owned();
+        self.docs.retain(|d| d.0 != uri);
+        self.docs.push((uri, text));
+        self.refresh();
+      }
+      "textDocument/didChange" => {
+        // Only whole files are synced
+        let text = match params.get("contentChanges").as_array().last().and_then(|c| c.get("text").as_str()) {
+          Some(s) => s.to_owned(),
+          None => return
+        };
+        if let Some(doc) = self.docs.iter_mut().find(|d| d.0 == uri) {
+          doc.1 = text;
+        }
+        self.refresh();
+      }
+      "textDocument/didClose" => {
+        self.docs.retain(|d| d.0 != uri);
+        send(Json::object(vec![
+          ("jsonrpc", Json::string("2.0")),
+          ("method", Json::string("textDocument/publishDiagnostics")),
+          ("params", Json::object(vec![("uri", Json::STRING(uri)), ("diagnostics", Json::ARRAY(Vec::new()))]))
+        ]));
+        self.refresh();
+      }
+      // Included files which are not open may have changed on the disk
+      "textDocument/didSave" => self.refresh(),
+      "exit" => process::exit(if self.shutdown { 0 } else { EXIT_ERRORS }),
+      _ => {}
+    }
+  }
+
+  fn request(&mut self, method: &str, params: &Json) -> Result<Json, String> {
+    match method {
+      "initialize" => Ok(Json::object(vec![
+        ("capabilities", Json::object(vec![
+          ("textDocumentSync", Json::object(vec![
+            ("openClose", Json::BOOL(true)),
+            ("change", Json::int(1)),
+            ("save", Json::BOOL(true))
+          ])),
+          ("definitionProvider", Json::BOOL(true)),
+          ("referencesProvider", Json::BOOL(true)),
+          ("hoverProvider", Json::BOOL(true)),
+          ("completionProvider", Json::object(Vec::new()))
+        ])),
+        ("serverInfo", Json::object(vec![
+          ("name", Json::string("blsp")),
+          ("version", Json::string(env!("CARGO_PKG_VERSION")))
+        ]))
+      ])),
+      "shutdown" => {
+        self.shutdown = true;
+        Ok(Json::NULL)
+      }
+      "textDocument/definition" => {
+        let a = self.analysis(params)?;
+        let found = match a.at(params) {
+          Some((file, line, col)) => a.index.lookup(file, line, col),
+          None => Vec::new()
+        };
+        Ok(Json::ARRAY(found.iter().map(|s| location(&a.sources, &s.loc)).collect()))
+      }
+      "textDocument/references" => {
+        let a = self.analysis(params)?;
+        let declaration = params.get("context").get("includeDeclaration").as_bool().unwrap_or(true);
+        let mut locs: Vec<Loc> = Vec::new();
+        if let Some((file, line, col)) = a.at(params) {
+          for sym in a.index.lookup(file, line, col) {
+            if declaration {
+              locs.push(sym.loc);
+            }
+            locs.extend(a.index.references(sym));
+          }
+        }
+        locs.dedup();
+        Ok(Json::ARRAY(locs.iter().map(|l| location(&a.sources, l)).collect()))
+      }
+      "textDocument/hover" => {
+        let a = self.analysis(params)?;
+        let (file, line, col) = match a.at(params) {
+          Some(s) => s,
+          None => return Ok(Json::NULL)
+        };
+        let word = match a.word(file, line, col) {
+          Some(s) => s,
+          None => return Ok(Json::NULL)
+        };
+        let symbols = a.index.lookup(file, line, col);
+        let text = match (isa::register(&word), isa::by_name(&word)) {
+          (Some(Ok(reg)), _) => describe_register(&word, reg),
+          // A function may share its name with an instruction
+          (_, Some(ins)) if symbols.is_empty() => describe_instruction(ins),
+          _ if !symbols.is_empty() => {
+            let parts: Vec<String> = symbols.iter().map(|s| describe_symbol(&a.sources, s)).collect();
+            parts.join("\n\n")
+          }
+          _ => return Ok(Json::NULL)
+        };
+        Ok(Json::object(vec![("contents", Json::object(vec![
+          ("kind", Json::string("markdown")),
+          ("value", Json::STRING(text))
+        ]))]))
+      }
+      "textDocument/completion" => {
+        let a = self.analysis(params)?;
+        let scope = match a.at(params) {
+          Some((file, line, _)) => a.index.scope(file, line),
+          None => None
+        };
+        let mut items: Vec<Json> = Vec::new();
+        for ins in isa::INSTRUCTIONS {
+          items.push(Json::object(vec![
+            ("label", Json::string(ins.mnemonic)),
+            // Keyword
+            ("kind", Json::int(14)),
+            ("detail", Json::STRING(signature(ins)))
+          ]));
+        }
+        for sym in a.index.visible(scope) {
+          let kind = match sym.kind {
+            Kind::FUNC => 3,
+            Kind::LABEL => 18,
+            Kind::DATA => 6,
+            Kind::DEFINE => 21,
+            Kind::MACRO => 2
+          };
+          items.push(Json::object(vec![
+            ("label", Json::STRING(sym.name.clone())),
+            ("kind", Json::int(kind)),
+            ("detail", Json::string(sym.kind.describe()))
+          ]));
+        }
+        Ok(Json::ARRAY(items))
+      }
+      _ => Err(format!("Unknown method {method}"))
+    }
+  }
+}
+
+// Diagnostics in other files, such as an include which is not open, are
+// shown at the top of the file along with where they are
+fn publish(uri: &str, a: &Analysis) {
+  let start = Json::object(vec![("line", Json::int(0)), ("character", Json::int(0))]);
+  let top = Json::object(vec![("start", start.clone()), ("end", start)]);
+  let mut diags: Vec<Json> = Vec::new();
+  for diag in &a.diags {
+    let (range, msg) = match diag.loc {
+      Some(loc) if Some(loc.file) == a.file => (range(&a.sources, &loc), diag.msg.clone()),
+      Some(loc) => (top.clone(), format!("{}:{}: {}", a.sources.get(loc.file).name, loc.line, diag.msg)),
+      None => (top.clone(), diag.msg.clone())
+    };
+    let severity = match diag.level {
+      Level::ERROR => 1,
+      Level::WARNING => 2
+    };
+    let notes: Vec<Json> = diag.notes.iter().map(|(loc, note)| Json::object(vec![
+      ("location", location(&a.sources, loc)),
+      ("message", Json::string(note))
+    ])).collect();
+    let mut fields = vec![
+      ("range", range),
+      ("severity", Json::int(severity)),
+      ("source", Json::string("blc")),
+      ("message", Json::STRING(msg)),
+      ("relatedInformation", Json::ARRAY(notes))
+    ];
+    if let Some(flag) = diag.flag {
+      fields.push(("code", Json::string(flag)));
+    }
+    diags.push(Json::object(fields));
+  }
+  send(Json::object(vec![
+    ("jsonrpc", Json::string("2.0")),
+    ("method", Json::string("textDocument/publishDiagnostics")),
+    ("params", Json::object(vec![("uri", Json::string(uri)), ("diagnostics", Json::ARRAY(diags))]))
+  ]));
+}
+
+fn main() {
+  // Standard output belongs to the protocol
+  panic::set_hook(Box::new(|panic_info| {
+    if let Some(s) = panic_info.payload().downcast_ref::<String>() {
+      eprintln!("blsp: {s}");
+    } else if let Some(s) = panic_info.payload().downcast_ref::<&str>() {
+      eprintln!("blsp: {s}");
+    }
+  }));
+  let args: Vec<String> = env::args().skip(1).collect();
+  let mut server = Server {
+    docs: Vec::new(),
+    analyses: Vec::new(),
+    defines: Vec::new(),
+    search: Vec::new(),
+    warnings: Vec::new(),
+    shutdown: false
+  };
+  let mut index = 0;
+  while index < args.len() {
+    let arg = args[index].as_str();
+    index += 1;
+    let (option, attached) = match arg {
+      _ if arg.starts_with("--") => (arg, ""),
+      _ if arg.len() > 1 && arg.starts_with('-') => (&arg[..2], &arg[2..]),
+      _ => usage(&format!("Unexpected argument '{arg}'"))
+    };
+    let value = match (option, attached) {
+      ("-D" | "-I" | "-W", "") => {
+        index += 1;
+        match args.get(index - 1) {
+          Some(s) => s.as_str(),
+          None => usage(&format!("{option} needs a value"))
+        }
+      }
+      (_, s) => s
+    };
+    match option {
+      "-h" | "--help" => {
+        println!("{HELP}");
+        return;
+      }
+      "--version" => {
+        println!("blsp {}", env!("CARGO_PKG_VERSION"));
+        return;
+      }
+      "-D" => {
+        // Checked once here, analyse() repeats it for every file
+        if let Err(e) = Options::new(&[]).define(&mut Sources::new(), value) {
+          usage(&e);
+        }
+        server.defines.push(value.to_owned());
+      }
+      "-I" => server.search.push(PathBuf::from(value)),
+      "-W" => {
+        if let Err(e) = Config::new().set(value) {
+          usage(&e);
+        }
+        server.warnings.push(value.to_owned());
+      }
+      _ => usage(&format!("Unknown option '{arg}'"))
+    }
+  }
+
+  let stdin = io::stdin();
+  let mut reader = stdin.lock();
+  loop {
+    let message = match read_message(&mut reader) {
+      Ok(Some(s)) => s,
+      Ok(None) => process::exit(if server.shutdown { 0 } else { EXIT_ERRORS }),
+      Err(e) => {
+        eprintln!("blsp: {e}");
+        continue;
+      }
+    };
+    let method = message.get("method").as_str().unwrap_or("").to_owned();
+    let params = message.get("params");
+    let id = message.get("id");
+    // Responses to requests of the server's own, there are none
+    if method.is_empty() {
+      continue;
+    }
+    if *id == Json::NULL {
+      server.notify(&method, params);
+      continue;
+    }
+    let reply = match server.request(&method, params) {
+      Ok(result) => ("result", result),
+      Err(e) => {
+        // Method not found or invalid parameters
+        let code = if e.starts_with("Unknown method") { -32601.0 } else { -32602.0 };
+        ("error", Json::object(vec![("code", Json::NUMBER(code)), ("message", Json::STRING(e))]))
+      }
+    };
+    send(Json::object(vec![("jsonrpc", Json::string("2.0")), ("id", id.clone()), reply]));
+  }
+}
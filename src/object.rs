@@ -0,0 +1,430 @@
+use std::io::{self, Error, ErrorKind};
+extern crate file_utils;
+use file_utils::read::Read;
+use file_utils::write::Write;
+
+// Relocatable object files, written by blc -c and combined by bld into an
+// executable. The layout is
+//
+// u32 magic, u16 version
+// u64 code size, code
+// u64 data size, u64 data alignment, data
+// u32 symbol count, symbols
+// u32 relocation count, relocations
+// u32 file count, file names
+// u32 line count, (u64 offset, u32 file, u32 line) for every line
+//
+// Code and data start at offset 0 of their section, every address in them
+// which depends on where a section ends up is described by a relocation
+pub const MAGIC: u32 = 0xAFD;
+pub const VERSION: u16 = 3;
+// Version 1 objects only lack compact immediates and version 2 ones the
+// line table, both can still be linked
+const OLDEST: u16 = 1;
+// .align takes at most the size of the data segment
+const MAX_ALIGN: usize = 0x80000;
+// Expressions are read recursively, a file nesting them deeper than any
+// source would is malformed rather than a stack overflow
+const MAX_DEPTH: usize = 1000;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Section {
+  CODE,
+  DATA
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Kind {
+  FUNC,
+  LABEL,
+  DATA
+}
+
+// Labels are always local and named after their function as func.label
+#[derive(Debug, Clone)]
+pub struct Symbol {
+  pub name: String,
+  pub kind: Kind,
+  pub section: Section,
+  pub offset: usize,
+  pub size: usize,
+  pub global: bool,
+  // Where the symbol was defined, for symbol maps
+  pub file: String,
+  pub line: usize
+}
+
+// What a relocation patches
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Field {
+  // 8 byte immediate of an instruction, only in version 1 objects
+  IMM,
+  // Lower 57 bits of an offset, the register above them is kept. Only in
+  // version 1 objects
+  OFFSET,
+  // 4 byte signed immediate, of IMM32 or after the register of OFFSET32
+  IMM32,
+  // Data item of the given width
+  BYTES(usize)
+}
+
+impl Field {
+  // Bytes of the section the field covers
+  pub fn width(self) -> usize {
+    match self {
+      Field::IMM | Field::OFFSET => 8,
+      Field::IMM32 => 4,
+      Field::BYTES(n) => n
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Op {
+  ADD,
+  SUB,
+  MUL,
+  DIV,
+  MOD,
+  SHL,
+  SHR,
+  AND,
+  OR,
+  // Comparisons and logical operators give 1 for true and 0 for false
+  EQ,
+  NE,
+  LT,
+  LE,
+  GT,
+  GE,
+  LAND,
+  LOR
+}
+
+impl Op {
+  // All arithmetic is checked, bits shifted out of the top are an overflow too
+  pub fn apply(&self, l: i64, r: i64) -> Result<i64, &'static str> {
+    let shift = match (self, u32::try_from(r)) {
+      (Op::SHL | Op::SHR, Ok(s)) if s < 64 => s,
+      (Op::SHL | Op::SHR, _) => return Err("Shift amount out of range"),
+      _ => 0
+    };
+    if (*self == Op::DIV || *self == Op::MOD) && r == 0 {
+      return Err("Division by zero in constant expression");
+    }
+    let res = match self {
+      Op::ADD => l.checked_add(r),
+      Op::SUB => l.checked_sub(r),
+      Op::MUL => l.checked_mul(r),
+      Op::DIV => l.checked_div(r),
+      Op::MOD => l.checked_rem(r),
+      Op::SHL => l.checked_shl(shift).filter(|v| v >> shift == l),
+      Op::SHR => l.checked_shr(shift),
+      Op::AND => Some(l & r),
+      Op::OR => Some(l | r),
+      Op::EQ => Some((l == r) as i64),
+      Op::NE => Some((l != r) as i64),
+      Op::LT => Some((l < r) as i64),
+      Op::LE => Some((l <= r) as i64),
+      Op::GT => Some((l > r) as i64),
+      Op::GE => Some((l >= r) as i64),
+      Op::LAND => Some((l != 0 && r != 0) as i64),
+      Op::LOR => Some((l != 0 || r != 0) as i64)
+    };
+    res.ok_or("Arithmetic overflow in constant expression")
+  }
+
+  fn code(&self) -> u8 {
+    *self as u8
+  }
+
+  fn from_code(code: u8) -> Option<Self> {
+    let ops = [
+      Op::ADD, Op::SUB, Op::MUL, Op::DIV, Op::MOD, Op::SHL, Op::SHR, Op::AND, Op::OR,
+      Op::EQ, Op::NE, Op::LT, Op::LE, Op::GT, Op::GE, Op::LAND, Op::LOR
+    ];
+    ops.get(code as usize).copied()
+  }
+}
+
+// Value of a relocation, computed once every symbol has an address
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+  NUM(i64),
+  SYM(String),
+  NEG(Box<Expr>),
+  NOT(Box<Expr>),
+  BINARY(Op, Box<Expr>, Box<Expr>)
+}
+
+impl Expr {
+  pub fn eval(&self, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+    match self {
+      Expr::NUM(i) => Ok(*i),
+      Expr::SYM(s) => resolve(s).ok_or(format!("Undefined symbol {s}")),
+      Expr::NEG(e) => e.eval(resolve)?.checked_neg().ok_or("Arithmetic overflow in constant expression".to_owned()),
+      Expr::NOT(e) => Ok(!e.eval(resolve)?),
+      Expr::BINARY(op, l, r) => op.apply(l.eval(resolve)?, r.eval(resolve)?).map_err(|e| e.to_owned())
+    }
+  }
+
+  pub fn symbols<'a>(&'a self, out: &mut Vec<&'a str>) {
+    match self {
+      Expr::NUM(..) => {},
+      Expr::SYM(s) => out.push(s),
+      Expr::NEG(e) | Expr::NOT(e) => e.symbols(out),
+      Expr::BINARY(_, l, r) => {
+        l.symbols(out);
+        r.symbols(out);
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Reloc {
+  pub section: Section,
+  pub offset: usize,
+  pub field: Field,
+  pub expr: Expr
+}
+
+// The code from offset up to the next line came from line of a file,
+// an index into Object::files
+#[derive(Debug, Clone)]
+pub struct Line {
+  pub offset: usize,
+  pub file: usize,
+  pub line: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct Object {
+  // File the object was read from or assembled into, used in messages
+  pub name: String,
+  pub code: Vec<u8>,
+  pub data: Vec<u8>,
+  // Largest .align in the data, the linker places the data at a multiple of it
+  pub data_align: usize,
+  pub symbols: Vec<Symbol>,
+  pub relocs: Vec<Reloc>,
+  pub files: Vec<String>,
+  // Sorted by offset
+  pub lines: Vec<Line>
+}
+
+fn write_str<W: io::Write>(writer: &mut W, s: &str) -> io::Result<()> {
+  writer.write_u32(s.len() as u32)?;
+  writer.write_all(s.as_bytes())
+}
+
+// The length comes from the file, so only what is really there is read
+// instead of allocating len bytes up front
+fn read_bytes<R: io::Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+  let mut buf: Vec<u8> = Vec::new();
+  let mut limited = io::Read::take(io::Read::by_ref(reader), len as u64);
+  io::Read::read_to_end(&mut limited, &mut buf)?;
+  if buf.len() != len {
+    return Err(malformed());
+  }
+  Ok(buf)
+}
+
+fn read_str<R: io::Read>(reader: &mut R) -> io::Result<String> {
+  let len = reader.read_u32()? as usize;
+  String::from_utf8(read_bytes(reader, len)?).map_err(|_| malformed())
+}
+
+fn malformed() -> Error {
+  Error::new(ErrorKind::InvalidData, "Malformed object file")
+}
+
+fn write_expr<W: io::Write>(writer: &mut W, expr: &Expr) -> io::Result<()> {
+  match expr {
+    Expr::NUM(i) => {
+      writer.write_u8(0)?;
+      writer.write_i64(*i)
+    }
+    Expr::SYM(s) => {
+      writer.write_u8(1)?;
+      write_str(writer, s)
+    }
+    Expr::NEG(e) => {
+      writer.write_u8(2)?;
+      write_expr(writer, e)
+    }
+    Expr::NOT(e) => {
+      writer.write_u8(3)?;
+      write_expr(writer, e)
+    }
+    Expr::BINARY(op, l, r) => {
+      writer.write_u8(4)?;
+      writer.write_u8(op.code())?;
+      write_expr(writer, l)?;
+      write_expr(writer, r)
+    }
+  }
+}
+
+fn read_expr<R: io::Read>(reader: &mut R, depth: usize) -> io::Result<Expr> {
+  if depth > MAX_DEPTH {
+    return Err(malformed());
+  }
+  match reader.read_u8()? {
+    0 => Ok(Expr::NUM(reader.read_i64()?)),
+    1 => Ok(Expr::SYM(read_str(reader)?)),
+    2 => Ok(Expr::NEG(Box::new(read_expr(reader, depth + 1)?))),
+    3 => Ok(Expr::NOT(Box::new(read_expr(reader, depth + 1)?))),
+    4 => {
+      let op = Op::from_code(reader.read_u8()?).ok_or_else(malformed)?;
+      Ok(Expr::BINARY(op, Box::new(read_expr(reader, depth + 1)?), Box::new(read_expr(reader, depth + 1)?)))
+    }
+    _ => Err(malformed())
+  }
+}
+
+impl Object {
+  pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    writer.write_u32(MAGIC)?;
+    writer.write_u16(VERSION)?;
+    writer.write_u64(self.code.len() as u64)?;
+    writer.write_all(&self.code)?;
+    writer.write_u64(self.data.len() as u64)?;
+    writer.write_u64(self.data_align as u64)?;
+    writer.write_all(&self.data)?;
+    writer.write_u32(self.symbols.len() as u32)?;
+    for sym in &self.symbols {
+      write_str(writer, &sym.name)?;
+      writer.write_u8(sym.kind as u8)?;
+      writer.write_u8(sym.section as u8)?;
+      writer.write_u8(sym.global as u8)?;
+      writer.write_u64(sym.offset as u64)?;
+      writer.write_u64(sym.size as u64)?;
+      write_str(writer, &sym.file)?;
+      writer.write_u32(sym.line as u32)?;
+    }
+    writer.write_u32(self.relocs.len() as u32)?;
+    for reloc in &self.relocs {
+      writer.write_u8(reloc.section as u8)?;
+      writer.write_u64(reloc.offset as u64)?;
+      match reloc.field {
+        Field::IMM => writer.write_u8(0)?,
+        Field::OFFSET => writer.write_u8(1)?,
+        Field::IMM32 => writer.write_u8(10)?,
+        Field::BYTES(n) => writer.write_u8(1 + n as u8)?
+      }
+      write_expr(writer, &reloc.expr)?;
+    }
+    writer.write_u32(self.files.len() as u32)?;
+    for file in &self.files {
+      write_str(writer, file)?;
+    }
+    writer.write_u32(self.lines.len() as u32)?;
+    for line in &self.lines {
+      writer.write_u64(line.offset as u64)?;
+      writer.write_u32(line.file as u32)?;
+      writer.write_u32(line.line as u32)?;
+    }
+    Ok(())
+  }
+
+  pub fn read<R: io::Read>(name: &str, reader: &mut R) -> io::Result<Self> {
+    if reader.read_u32()? != MAGIC {
+      return Err(Error::new(ErrorKind::InvalidData, "Not an object file"));
+    }
+    let version = reader.read_u16()?;
+    if !(OLDEST..=VERSION).contains(&version) {
+      return Err(Error::new(ErrorKind::InvalidData, "Unsupported object file version"));
+    }
+    let len = reader.read_u64()? as usize;
+    let code = read_bytes(reader, len)?;
+    let len = reader.read_u64()? as usize;
+    let data_align = reader.read_u64()? as usize;
+    if !data_align.is_power_of_two() || data_align > MAX_ALIGN {
+      return Err(malformed());
+    }
+    let data = read_bytes(reader, len)?;
+    // Symbols and relocations have to lie within their section
+    let section_len = |section: Section| match section {
+      Section::CODE => code.len(),
+      Section::DATA => data.len()
+    };
+    let mut symbols: Vec<Symbol> = Vec::new();
+    for _ in 0..reader.read_u32()? {
+      let name = read_str(reader)?;
+      let kind = match reader.read_u8()? {
+        0 => Kind::FUNC,
+        1 => Kind::LABEL,
+        2 => Kind::DATA,
+        _ => return Err(malformed())
+      };
+      let section = match reader.read_u8()? {
+        0 => Section::CODE,
+        1 => Section::DATA,
+        _ => return Err(malformed())
+      };
+      let sym = Symbol {
+        name,
+        kind,
+        section,
+        global: reader.read_u8()? != 0,
+        offset: reader.read_u64()? as usize,
+        size: reader.read_u64()? as usize,
+        file: read_str(reader)?,
+        line: reader.read_u32()? as usize
+      };
+      if sym.offset > section_len(section) {
+        return Err(malformed());
+      }
+      symbols.push(sym);
+    }
+    let mut relocs: Vec<Reloc> = Vec::new();
+    for _ in 0..reader.read_u32()? {
+      let section = match reader.read_u8()? {
+        0 => Section::CODE,
+        1 => Section::DATA,
+        _ => return Err(malformed())
+      };
+      let offset = reader.read_u64()? as usize;
+      let field = match reader.read_u8()? {
+        0 => Field::IMM,
+        1 => Field::OFFSET,
+        n @ (2 | 3 | 5 | 9) => Field::BYTES(n as usize - 1),
+        10 => Field::IMM32,
+        _ => return Err(malformed())
+      };
+      if offset.checked_add(field.width()).is_none_or(|end| end > section_len(section)) {
+        return Err(malformed());
+      }
+      relocs.push(Reloc { section, offset, field, expr: read_expr(reader, 0)? });
+    }
+    let mut files: Vec<String> = Vec::new();
+    let mut lines: Vec<Line> = Vec::new();
+    if version >= 3 {
+      for _ in 0..reader.read_u32()? {
+        files.push(read_str(reader)?);
+      }
+      for _ in 0..reader.read_u32()? {
+        let line = Line {
+          offset: reader.read_u64()? as usize,
+          file: reader.read_u32()? as usize,
+          line: reader.read_u32()? as usize
+        };
+        if line.file >= files.len() {
+          return Err(malformed());
+        }
+        lines.push(line);
+      }
+    }
+    Ok(Self {
+      name: name.to_owned(),
+      code,
+      data,
+      data_align,
+      symbols,
+      relocs,
+      files,
+      lines
+    })
+  }
+}
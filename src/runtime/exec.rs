@@ -3,10 +3,9 @@ use std::io::{stdin, Read};
 use crate::memory::{EXEC};
 use mmap_rs::{MmapMut, MmapOptions, MmapFlags};
 use crate::utils;
-
-const MAGIC: u32 = 0xAFC;
-const MAJOR: u16 = 0x1;
-const MINOR: u16 = 0x0;
+use blitz::debug::Debug;
+use blitz::isa::{self, Opcode};
+use blitz::link::{self, Header};
 
 #[derive(Debug)]
 pub struct Regs(pub [usize; 22]);
@@ -38,15 +37,6 @@ impl Regs {
     }
   }
 
-  fn size(idx: usize) -> usize {
-    match idx {
-      0..=19 => 1,
-      20..=39 => 2,
-      40..=59 => 4,
-      60..=81 => 8,
-      _ => unsafe { unreachable_unchecked() }
-    }
-  }
 }
 
 #[derive(Debug)]
@@ -95,22 +85,33 @@ impl Args {
   }
 }
 
-fn read_args(value: u8, code: &[u8], offset: &mut usize) -> Args {
+fn read_args(value: u8, code: &[u8], offset: &mut usize, major: u16) -> Args {
+  if isa::is_compact(value) && major > 1 {
+    let size = isa::operand_size(value).unwrap();
+    let imm = &code[*offset..(*offset + size)];
+    *offset += size;
+    return match value {
+      isa::OFFSET8 | isa::OFFSET16 | isa::OFFSET32 => Args::OFFSET(imm[0], isa::sign_extend(&imm[1..]) as u64),
+      _ => Args::INT(isa::sign_extend(imm) as u64)
+    };
+  }
   match value {
-    0..=80 => Args::REG(value),
-    83 => {
+    0..=isa::SP => Args::REG(value),
+    isa::OFFSET => {
       let num = utils::make_u64(&code[*offset..(*offset + 8)]);
       *offset += 8;
-      let reg = (num >> 57) as u8;
-      let off = num & ((1 << 57) - 1);
+      let reg = (num >> isa::OFFSET_BITS) as u8;
+      // The offset is a signed 57 bit number below the register
+      let unused = 64 - isa::OFFSET_BITS;
+      let off = (((num << unused) as i64) >> unused) as u64;
       Args::OFFSET(reg, off)
     }
-    81 => {
+    isa::IMM => {
       let num = utils::make_u64(&code[*offset..(*offset + 8)]);
       *offset += 8;
       Args::INT(num)
     }
-    82 => {
+    isa::DECIMAL => {
       let num = utils::make_u64(&code[*offset..(*offset + 8)]);
       *offset += 8;
       let arg = unsafe { std::mem::transmute::<u64, f64>(num) };
@@ -127,7 +128,11 @@ pub struct Cpu {
   pub special: [usize; 6],
   pub gdt: Vec<Area>,
   pub pc: usize,
-  pub memory: MmapMut
+  pub memory: MmapMut,
+  // Version of the executable being run
+  pub major: u16,
+  // Names exceptions after functions and lines when the executable has it
+  pub debug: Option<Debug>
 }
 
 impl Cpu {
@@ -141,39 +146,35 @@ impl Cpu {
         Ok(s) => s,
         Err(e) => panic!("Error allocating memory {e}")
       },
-      pc: 0
+      pc: 0,
+      major: link::MAJOR,
+      debug: None
     }
   }
   
   pub fn init(code: Vec<u8>) -> Self {
-    let magic = utils::make_u32(&code[0..4]);
-    if magic != MAGIC {
-      panic!("Not a blitz executable!");
-    }
-    let major = utils::make_u16(&code[4..6]);
-    let minor = utils::make_u16(&code[6..8]);
-    if major != MAJOR || minor != MINOR {
-      panic!("Unsupported blitz version {major}.{minor}");
-    }
+    let header = match Header::read(&code) {
+      Ok(s) => s,
+      Err(e) => panic!("{e}")
+    };
     let mut cpu = Cpu::new(2 * 1048 * 1048);
-    let data = utils::make_u64(&code[16..24]) as usize;
-    cpu.write(0, &code[..data]);
-    cpu.write(0x7E000, &code[data..]);
+    cpu.major = header.major;
+    cpu.debug = header.debug(&code);
+    cpu.write(0, &code[..header.data_offset]);
+    cpu.write(0x7E000, &code[header.data_offset..header.data_end]);
     cpu
   }
   
   fn decode(&mut self, ins: u32, offset: usize) -> (Vec<Args>, usize) {
     let mut pc = offset;
+    let major = self.major;
     let code = self.read(0x00000, 0x7DFFF);
     let mut args_vec: Vec<Args> = Vec::with_capacity(4);
-    let arg1 = ((ins >> 15) & 127) as u8;
-    let arg2 = ((ins >> 8) & 127) as u8;
-    let arg3 = ((ins >> 1) & 127) as u8;
     pc += 4;
-    args_vec.push(read_args(arg1, code, &mut pc));
-    args_vec.push(read_args(arg2, code, &mut pc));
-    args_vec.push(read_args(arg3, code, &mut pc));
-    args_vec.push(Args::FLAG(ins & (1 << 0) != 0));
+    for shift in isa::OPERAND_SHIFTS {
+      args_vec.push(read_args(((ins >> shift) & isa::OPERAND_MASK) as u8, code, &mut pc, major));
+    }
+    args_vec.push(Args::FLAG(ins & isa::FIRMWARE_BIT != 0));
     (args_vec, pc)
   }
   
@@ -182,17 +183,17 @@ impl Cpu {
     let mut depth: Vec<usize> = Vec::new();
     loop {
       let ins = self.read_u32(self.pc);
-      let opcode = ins >> 22;
+      let opcode = ins >> isa::OPCODE_SHIFT;
       let (args, new) = self.decode(ins, self.pc);
-      match opcode {
-        0 => {},
-        1 => {
+      match Opcode::new(opcode) {
+        Some(Opcode::NOP) => {},
+        Some(Opcode::MOV) => {
           let arg = match &args[1] {
             Args::REG(r) => self.regs.get(*r as usize),
             Args::INT(s) => *s as usize,
             Args::OFFSET(reg, off) => {
-              let address = self.regs.get(*reg as usize) + *off as usize;
-              let size = Regs::size(*reg as usize);
+              let address = self.regs.get(*reg as usize).wrapping_add(*off as usize);
+              let size = isa::register_size(*reg);
               match size {
                 1 => self.read_u8(address) as usize,
                 2 => self.read_u16(address) as usize,
@@ -206,15 +207,16 @@ impl Cpu {
           match &args[0] {
             Args::REG(r) => self.regs.set(*r as usize, arg),
             Args::OFFSET(reg, off) => {
-              let address = self.regs.get(*reg as usize) + *off as usize;
-              let size = Regs::size(*reg as usize);
+              let address = self.regs.get(*reg as usize).wrapping_add(*off as usize);
+              let size = isa::register_size(*reg);
               let content = utils::u64_to_u8(arg as u64);
               self.write(address, &content[0..size]);
             }
             _ => unreachable!()
           }
         }
-        2..=11 => {
+        Some(op @ (Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::MOD |
+                   Opcode::OR | Opcode::AND | Opcode::XOR | Opcode::SHL | Opcode::SHR)) => {
           let reg = args[0].get_reg() as usize;
           let arg1 = match &args[1] {
             Args::INT(s) => *s as usize,
@@ -226,33 +228,33 @@ impl Cpu {
             Args::REG(r) => self.regs.get(*r as usize),
             _ => unreachable!()
           };
-          match opcode - 2 {
-            0 => self.regs.set(reg, arg1 + arg2),
-            1 => self.regs.set(reg, arg1 - arg2),
-            2 => self.regs.set(reg, arg1 * arg2),
-            3 => {
+          match op {
+            Opcode::ADD => self.regs.set(reg, arg1 + arg2),
+            Opcode::SUB => self.regs.set(reg, arg1 - arg2),
+            Opcode::MUL => self.regs.set(reg, arg1 * arg2),
+            Opcode::DIV => {
               if arg2 == 0 {
                 self.throw(0);
               }
               self.regs.set(reg, arg1 / arg2)
             }
-            4 => {
+            Opcode::MOD => {
               if arg2 == 0 {
                 self.throw(0);
               }
               self.regs.set(reg, arg1 % arg2)
             }
-            5 => self.regs.set(reg, arg1 | arg2),
-            6 => self.regs.set(reg, arg1 & arg2),
-            7 => self.regs.set(reg, arg1 ^ arg2),
-            8 => self.regs.set(reg, arg1 << arg2),
-            9 => self.regs.set(reg, arg1 >> arg2),
+            Opcode::OR => self.regs.set(reg, arg1 | arg2),
+            Opcode::AND => self.regs.set(reg, arg1 & arg2),
+            Opcode::XOR => self.regs.set(reg, arg1 ^ arg2),
+            Opcode::SHL => self.regs.set(reg, arg1 << arg2),
+            Opcode::SHR => self.regs.set(reg, arg1 >> arg2),
             _ => unsafe {
                 unreachable_unchecked()
             }
           }
         }
-        12 => {
+        Some(Opcode::JMP) => {
           let arg = match &args[0] {
             Args::REG(r) => self.regs.get(*r as usize),
             Args::INT(s) => *s as usize,
@@ -271,16 +273,17 @@ impl Cpu {
             }
           }
         }
-        13..=18 => {
+        Some(op @ (Opcode::JE | Opcode::JNE | Opcode::JGE | Opcode::JGT | Opcode::JLE | Opcode::JLT)) => {
           let offset = args[0].get_int() as usize;
+          let flags = self.regs.get(isa::FLAGS as usize);
           let res;
-          match opcode - 13 {
-            0 => res = (self.regs.get(81) & (1 << 0)) != 0,
-            1 => res = (self.regs.get(81) & (1 << 0)) == 0,
-            2 => res = (self.regs.get(81) & (1 << 1)) != 0 || (self.regs.get(81) & (1 << 0)) != 0,
-            3 => res = (self.regs.get(81) & (1 << 1)) != 0,
-            4 => res = (self.regs.get(81) & (1 << 2)) != 0 || (self.regs.get(81) & (1 << 0)) != 0,
-            5 => res = self.regs.get(81) & (1 << 2) != 0,
+          match op {
+            Opcode::JE => res = (flags & isa::EQUAL) != 0,
+            Opcode::JNE => res = (flags & isa::EQUAL) == 0,
+            Opcode::JGE => res = (flags & isa::GREATER) != 0 || (flags & isa::EQUAL) != 0,
+            Opcode::JGT => res = (flags & isa::GREATER) != 0,
+            Opcode::JLE => res = (flags & isa::LESS) != 0 || (flags & isa::EQUAL) != 0,
+            Opcode::JLT => res = flags & isa::LESS != 0,
             _ => unsafe { unreachable_unchecked() }
           }
           if res {
@@ -288,17 +291,17 @@ impl Cpu {
             continue;
           }
         }
-        19 => {
+        Some(Opcode::CALL) => {
           let offset = args[0].get_int() as usize;
           depth.push(new);
           self.pc = offset;
           continue;
         }
-        20 => {
+        Some(Opcode::FMOV) => {
           let reg = args[0].get_reg() as usize;
           self.fregs[reg] = args[1].get_decimal();
         }
-        21..=25 => {
+        Some(op @ (Opcode::FADD | Opcode::FSUB | Opcode::FMUL | Opcode::FDIV | Opcode::FMOD)) => {
           let reg = args[0].get_reg() as usize;
           let arg1 = match &args[1] {
             Args::DECIMAL(s) => *s,
@@ -310,17 +313,17 @@ impl Cpu {
             Args::REG(r) => self.fregs[*r as usize],
             _ => unreachable!()
           };
-          match opcode - 21 {
-            0 => self.fregs[reg] = arg1 + arg2,
-            1 => self.fregs[reg] = arg1 - arg2,
-            2 => self.fregs[reg] = arg1 * arg2,
-            3 => {
+          match op {
+            Opcode::FADD => self.fregs[reg] = arg1 + arg2,
+            Opcode::FSUB => self.fregs[reg] = arg1 - arg2,
+            Opcode::FMUL => self.fregs[reg] = arg1 * arg2,
+            Opcode::FDIV => {
               if arg2 == 0.0f64 {
                 self.throw(0);
               }
               self.fregs[reg] = arg1 / arg2;
             }
-            4 => {
+            Opcode::FMOD => {
               if arg2 == 0.0f64 {
                 self.throw(0);
               }
@@ -329,11 +332,11 @@ impl Cpu {
             _ => unreachable!()
           }
         }
-        26 => {
+        Some(Opcode::INC) => {
           match &args[0] {
             Args::REG(r) => self.regs.set(*r as usize, self.regs.get(*r as usize) + 1),
             Args::OFFSET(reg, off) => {
-              let address = self.regs.get(*reg as usize) + *off as usize;
+              let address = self.regs.get(*reg as usize).wrapping_add(*off as usize);
               let arg = self.read_u64(address) + 1;
               let content = utils::u64_to_u8(arg);
               self.write(address, &content);
@@ -341,11 +344,11 @@ impl Cpu {
             _ => unreachable!()
           }
         }
-        27 => {
+        Some(Opcode::DEC) => {
           match &args[0] {
             Args::REG(r) => self.regs.set(*r as usize, self.regs.get(*r as usize) - 1),
             Args::OFFSET(reg, off) => {
-              let address = self.regs.get(*reg as usize) + *off as usize;
+              let address = self.regs.get(*reg as usize).wrapping_add(*off as usize);
               let arg = self.read_u64(address) - 1;
               let content = utils::u64_to_u8(arg);
               self.write(address, &content);
@@ -353,29 +356,29 @@ impl Cpu {
             _ => unreachable!()
           }
         }
-        28 => {
+        Some(Opcode::FINC) => {
           match &args[0] {
             Args::REG(r) => self.fregs[*r as usize] += 1.0,
             _ => unreachable!()
           }
         }
-        29 => {
+        Some(Opcode::FDEC) => {
           match &args[0] {
             Args::REG(r) => self.fregs[*r as usize] -= 1.0,
             _ => unreachable!()
           }
         }
-        30 => {
+        Some(Opcode::SET) => {
           let reg = args[0].get_reg() as usize;
           let bit = args[1].get_int() as usize;
           self.regs.set(reg, self.regs.get(reg) | 1 << bit);
         }
-        31 => {
+        Some(Opcode::CLEAR) => {
           let reg = args[0].get_reg() as usize;
           let bit = args[1].get_int() as usize;
           self.regs.set(reg, self.regs.get(reg) & !(1 << bit));
         }
-        32 => {
+        Some(Opcode::FPUSH) => {
           let arg = match &args[0] {
             Args::DECIMAL(s) => *s,
             Args::REG(r) => self.fregs[*r as usize],
@@ -383,44 +386,44 @@ impl Cpu {
           };
           let arg1 = unsafe { std::mem::transmute::<f64, u64>(arg)};
           let num = utils::u64_to_u8(arg1);
-          self.regs.set(80, self.regs.get(80) - 8);
-          self.write(self.regs.get(80), &num);
+          self.regs.set(isa::SP as usize, self.regs.get(isa::SP as usize) - 8);
+          self.write(self.regs.get(isa::SP as usize), &num);
         }
-        33 => {
+        Some(Opcode::FPOP) => {
           let reg = args[0].get_reg() as usize;
-          let word = self.read_u64(self.regs.get(80));
-          self.regs.set(80, self.regs.get(80) + 8);
+          let word = self.read_u64(self.regs.get(isa::SP as usize));
+          self.regs.set(isa::SP as usize, self.regs.get(isa::SP as usize) + 8);
           self.fregs[reg] = unsafe { std::mem::transmute::<u64, f64>(word) };
         } 
-        34 => {
+        Some(Opcode::PUSH) => {
           let arg1 = match &args[0] {
             Args::INT(s) => *s as usize,
             Args::REG(r) => self.regs.get(*r as usize),
             _ => unreachable!()
           };
           let num = utils::u64_to_u8(arg1 as u64);
-          self.regs.set(80, self.regs.get(80) - 8);
-          self.write(self.regs.get(80), &num);
+          self.regs.set(isa::SP as usize, self.regs.get(isa::SP as usize) - 8);
+          self.write(self.regs.get(isa::SP as usize), &num);
         }
-        35 => {
+        Some(Opcode::POP) => {
           let reg = args[0].get_reg() as usize;
-          let word = self.read_u64(self.regs.get(80));
-          self.regs.set(80, self.regs.get(80) + 8);
+          let word = self.read_u64(self.regs.get(isa::SP as usize));
+          self.regs.set(isa::SP as usize, self.regs.get(isa::SP as usize) + 8);
           self.regs.set(reg, word as usize);
         }
-        36 => {
+        Some(Opcode::LEA) => {
           let reg = args[0].get_reg() as usize;
           let (target, offset) = args[1].get_off();
-          self.regs.set(reg, self.regs.get(target as usize) + offset as usize);
+          self.regs.set(reg, self.regs.get(target as usize).wrapping_add(offset as usize));
         }
-        37 => {
+        Some(Opcode::RET) => {
           if depth.len() == 0 {
             return;
           }
           self.pc = depth.pop().unwrap();
           continue;
         }
-        38 => {
+        Some(Opcode::CMP) => {
           let arg1 = match &args[0] {
             Args::INT(s) => *s as usize,
             Args::REG(r) => self.regs.get(*r as usize),
@@ -432,14 +435,14 @@ impl Cpu {
             _ => unreachable!()
           };
           if arg1 == arg2 {
-            self.regs.set(81, self.regs.get(81) | 1 << 0);
+            self.regs.set(isa::FLAGS as usize, self.regs.get(isa::FLAGS as usize) | isa::EQUAL);
           } else if arg1 > arg2 {
-            self.regs.set(81, self.regs.get(81) | 1 << 1);
+            self.regs.set(isa::FLAGS as usize, self.regs.get(isa::FLAGS as usize) | isa::GREATER);
           } else if arg1 < arg2 {
-             self.regs.set(81, self.regs.get(81) | 1 << 2);
+             self.regs.set(isa::FLAGS as usize, self.regs.get(isa::FLAGS as usize) | isa::LESS);
           }
         }
-        39 => {
+        Some(Opcode::FCMP) => {
           let arg1 = match &args[0] {
             Args::DECIMAL(s) => *s,
             Args::REG(r) => self.fregs[*r as usize],
@@ -451,19 +454,19 @@ impl Cpu {
             _ => unreachable!()
           };
           if arg1 == arg2 {
-            self.regs.set(81, self.regs.get(81) | 1 << 0);
+            self.regs.set(isa::FLAGS as usize, self.regs.get(isa::FLAGS as usize) | isa::EQUAL);
           } else if arg1 > arg2 {
-            self.regs.set(81, self.regs.get(81) | 1 << 1);
+            self.regs.set(isa::FLAGS as usize, self.regs.get(isa::FLAGS as usize) | isa::GREATER);
           } else if arg1 < arg2 {
-             self.regs.set(81, self.regs.get(81) | 1 << 2);
+             self.regs.set(isa::FLAGS as usize, self.regs.get(isa::FLAGS as usize) | isa::LESS);
           }
         }
-        40..=42 => {
+        Some(op @ (Opcode::SETEX | Opcode::SETFLAGS | Opcode::AGDT)) => {
           if args[3].get_flag() {
-            match opcode - 40 {
-              0 => self.special[0] = args[0].get_int() as usize,
-              1 => self.regs.set(81, self.regs.get(60)),
-              2 => {
+            match op {
+              Opcode::SETEX => self.special[0] = args[0].get_int() as usize,
+              Opcode::SETFLAGS => self.regs.set(isa::FLAGS as usize, self.regs.get(60)),
+              Opcode::AGDT => {
                 let beg = args[0].get_int() as usize;
                 let end = args[1].get_int() as usize;
                 let perm = args[2].get_int() as u8;
@@ -478,20 +481,21 @@ impl Cpu {
             self.throw(3);
           }
         }
-        50 => {
+        Some(Opcode::SYSCALL) => {
           let ty = args[0].get_int();
           if ty == 0 && !args[3].get_flag() {
-            panic!("Attempt to execute privileged instruction with privilege bit off")
+            panic!("Attempt to execute privileged instruction with privilege bit off {}", self.location(self.pc))
           }
           match ty {
             0 => {
+              let at = self.location(self.special[2]);
               match self.special[1] {
-                0 => panic!("Attempt to divide by zero at pc = {}", self.special[2]),
-                1 => panic!("Attempt to read/execute/write to memory region with insufficient permission = {} at pc = {}", self.special[3], self.special[2]),
-                2 => panic!("Illegal opcode {} at pc = {}", self.special[3], self.special[2]),
-                3 => panic!("Attempt to execute privileged instruction with privilege bit off at pc = {}", self.special[2]),
-                4 => panic!("Attempt to access unknown or illegal device {} at pc = {}", self.special[3], self.special[2]),
-                5 => panic!("Unknown system call number = {} at pc = {}", self.special[3], self.special[2]),
+                0 => panic!("Attempt to divide by zero {at}"),
+                1 => panic!("Attempt to read/execute/write to memory region with insufficient permission = {} {at}", self.special[3]),
+                2 => panic!("Illegal opcode {} {at}", self.special[3]),
+                3 => panic!("Attempt to execute privileged instruction with privilege bit off {at}"),
+                4 => panic!("Attempt to access unknown or illegal device {} {at}", self.special[3]),
+                5 => panic!("Unknown system call number = {} {at}", self.special[3]),
                 _ => panic!("Internal error: Unknown exception type = {}", self.special[1])
               }
             }
@@ -529,7 +533,7 @@ impl Cpu {
             }
           }
         }
-        _ => {
+        None => {
           self.special[3] = (opcode >> 22) as usize;
           self.throw(2);
         }
@@ -539,6 +543,15 @@ impl Cpu {
     }
   }
 
+  // Where an exception happened, e.g "in main at hello.su:7 (pc = 0x2c)"
+  // when the executable has a debug section
+  fn location(&self, pc: usize) -> String {
+    match &self.debug {
+      Some(s) => s.describe(pc),
+      None => format!("at pc = {pc}")
+    }
+  }
+
   pub fn throw(&mut self, extype: usize) {
     self.special[1] = extype;
     self.special[2] = self.pc;
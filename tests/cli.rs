@@ -0,0 +1,113 @@
+mod common;
+
+use common::*;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// A directory of its own holding the firmware and the given files
+fn workspace(name: &str, files: &[(&str, &str)]) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("blitz-cli-{}-{name}", std::process::id()));
+  let _ = std::fs::remove_dir_all(&dir);
+  std::fs::create_dir_all(&dir).unwrap();
+  for (file, text) in [("firmware.su", FIRMWARE), ("constant.su", CONSTANT), ("lib.su", LIB)].iter().chain(files) {
+    std::fs::write(dir.join(file), text).unwrap();
+  }
+  dir
+}
+
+fn blc(dir: &Path, args: &[&str]) -> Output {
+  Command::new(env!("CARGO_BIN_EXE_blc")).args(args).current_dir(dir).output().unwrap()
+}
+
+fn stderr(out: &Output) -> String {
+  String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+const HELLO: &str = "include \"lib.su\"\nhello: .string \"Hi\"\nfunc main\n  mov r0, hello\n  call println\n  ret\nend";
+
+#[test]
+fn output_paths() {
+  let dir = workspace("paths", &[("hello.su", HELLO)]);
+  let out = blc(&dir, &["hello.su"]);
+  assert!(out.status.success(), "{}", stderr(&out));
+  // println writes the terminating NUL too
+  assert_eq!(run(&std::fs::read(dir.join("hello.out")).unwrap()), "Hi\0\n");
+  assert!(dir.join("hello.map").exists());
+  let out = blc(&dir, &["-o", "other.bin", "hello.su"]);
+  assert!(out.status.success());
+  assert_eq!(std::fs::read(dir.join("other.bin")).unwrap(), std::fs::read(dir.join("hello.out")).unwrap());
+  let out = blc(&dir, &["-o", "-", "hello.su"]);
+  assert_eq!(out.stdout, std::fs::read(dir.join("hello.out")).unwrap());
+  let out = blc(&dir, &["--emit=hex", "-o", "-", "hello.su"]);
+  assert!(String::from_utf8(out.stdout).unwrap().starts_with("00000000"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn exit_codes() {
+  let dir = workspace("exit", &[("bad.su", "func main\n  bogus\nend"), ("hello.su", HELLO)]);
+  let out = blc(&dir, &["bad.su"]);
+  assert_eq!(out.status.code(), Some(1));
+  assert!(stderr(&out).contains("bad.su:2"), "{}", stderr(&out));
+  assert_eq!(blc(&dir, &["--bogus", "hello.su"]).status.code(), Some(2));
+  assert_eq!(blc(&dir, &["-é", "hello.su"]).status.code(), Some(2));
+  assert_eq!(blc(&dir, &[]).status.code(), Some(2));
+  assert_eq!(blc(&dir, &["-o", "missing/dir/x.out", "hello.su"]).status.code(), Some(3));
+  assert!(blc(&dir, &["--help"]).status.success());
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn tool(path: &str, dir: &Path, args: &[&str]) -> Output {
+  let out = Command::new(path).args(args).current_dir(dir).output().unwrap();
+  assert!(out.status.success(), "{path} {args:?}: {}", stderr(&out));
+  out
+}
+
+// Objects made with blc -c, put into an archive by blar and linked by
+// bld or blc -l
+#[test]
+fn separate_compilation() {
+  let greet = "include \"lib.su\"\nfunc greet\n  putc 65\n  ret\nend";
+  let main = "func main\n  call greet\n  ret\nend";
+  let dir = workspace("objects", &[("greet.su", greet), ("main.su", main)]);
+  for file in ["greet.su", "main.su", "firmware.su"] {
+    tool(env!("CARGO_BIN_EXE_blc"), &dir, &["-c", file]);
+  }
+  tool(env!("CARGO_BIN_EXE_blar"), &dir, &["-o", "libgreet.a", "greet.o"]);
+  tool(env!("CARGO_BIN_EXE_bld"), &dir, &["-o", "linked.out", "firmware.o", "main.o", "libgreet.a"]);
+  assert_eq!(run(&std::fs::read(dir.join("linked.out")).unwrap()), "A");
+  tool(env!("CARGO_BIN_EXE_blc"), &dir, &["-l", "libgreet.a", "-o", "assembled.out", "main.su"]);
+  assert_eq!(run(&std::fs::read(dir.join("assembled.out")).unwrap()), "A");
+  assert_eq!(blc(&dir, &["-c", "-l", "libgreet.a", "main.su"]).status.code(), Some(2));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn disassemble() {
+  let text = "include \"lib.su\"
+hello: .string \"Hi\"
+func main
+  mov r0, hello
+  mov r2, 3
+.Lagain:
+  dec r2
+  cmp r2, 0
+  jne .Lagain
+done:
+  call println
+  ret
+end";
+  let dir = workspace("bldis", &[("hello.su", text)]);
+  tool(env!("CARGO_BIN_EXE_blc"), &dir, &["-D", "UNPROTECTED", "hello.su"]);
+  let out = tool(env!("CARGO_BIN_EXE_bldis"), &dir, &["hello.out"]);
+  let text = String::from_utf8(out.stdout).unwrap();
+  let lines: Vec<&str> = text.lines().map(|l| l.trim_end()).collect();
+  assert!(lines.contains(&".Lagain:") && lines.contains(&"done:"), "{text}");
+  assert!(lines.iter().any(|l| l.ends_with("jne .Lagain")), "{text}");
+  assert!(lines.iter().any(|l| l.ends_with("jne loop")), "{text}");
+  assert!(lines.iter().any(|l| l.ends_with("call println")), "{text}");
+  // Constants are never taken for the data at that address
+  assert!(lines.iter().any(|l| l.ends_with("mov r0, 0x7e000")), "{text}");
+  assert!(!text.contains("hello,") && !text.contains(", hello"), "{text}");
+  let _ = std::fs::remove_dir_all(&dir);
+}
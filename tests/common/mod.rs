@@ -0,0 +1,119 @@
+// Helpers shared by the tests, which assemble sources held in memory
+// through the library like blc does
+#![allow(dead_code)]
+
+use blitz::compiler::assemble::{assemble, Executable, Options};
+use blitz::compiler::diag::Level;
+use blitz::compiler::file::Sources;
+use blitz::compiler::r#proc::{Lexer, Token};
+use blitz::link::Placed;
+use blitz::object::Object;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const FIRMWARE: &str = include_str!("../../firmware.su");
+pub const CONSTANT: &str = include_str!("../../constant.su");
+pub const LIB: &str = include_str!("../../lib.su");
+
+// The files as (name, text) along with the firmware and the files it includes
+pub fn sources(files: &[(&str, &str)]) -> Sources {
+  let mut all: Vec<(String, String)> = files.iter().map(|f| (f.0.to_owned(), f.1.to_owned())).collect();
+  for (name, text) in [("firmware.su", FIRMWARE), ("constant.su", CONSTANT), ("lib.su", LIB)] {
+    if !files.iter().any(|f| f.0 == name) {
+      all.push((name.to_owned(), text.to_owned()));
+    }
+  }
+  Sources::in_memory(all)
+}
+
+// Assembles main.su with the firmware
+pub fn options() -> Options {
+  Options::new(&["main.su"])
+}
+
+// Assembles main.su on its own, starting at main
+pub fn bare() -> Options {
+  let mut options = options();
+  options.firmware = None;
+  options.entry = "main".to_owned();
+  options
+}
+
+// Messages of the diagnostics on failure
+pub fn build_with(files: &[(&str, &str)], options: &Options) -> Result<Executable, Vec<String>> {
+  let mut sources = sources(files);
+  assemble(&mut sources, options).map_err(|diags| diags.into_iter().map(|d| d.msg).collect())
+}
+
+pub fn build(text: &str, options: &Options) -> Result<Executable, Vec<String>> {
+  build_with(&[("main.su", text)], options)
+}
+
+// Messages of the errors, without the warnings found along with them
+pub fn errors(text: &str, options: &Options) -> Vec<String> {
+  let mut sources = sources(&[("main.su", text)]);
+  match assemble(&mut sources, options) {
+    Ok(..) => panic!("main.su assembled without errors"),
+    Err(diags) => diags.into_iter().filter(|d| d.level == Level::ERROR).map(|d| d.msg).collect()
+  }
+}
+
+pub fn warnings(text: &str, options: &Options) -> Vec<String> {
+  match build(text, options) {
+    Ok(exe) => exe.warnings.into_iter().map(|d| d.msg).collect(),
+    Err(e) => panic!("main.su did not assemble: {e:?}")
+  }
+}
+
+// The relocatable object of main.su
+pub fn object(name: &str, text: &str) -> Object {
+  let mut options = Options::new(&[name]);
+  options.firmware = None;
+  options.object = true;
+  let exe = build_with(&[(name, text)], &options).unwrap_or_else(|e| panic!("{name} did not assemble: {e:?}"));
+  Object::read(name, &mut exe.bytes.as_slice()).unwrap()
+}
+
+pub fn symbol<'a>(exe: &'a Executable, name: &str) -> &'a Placed {
+  let image = exe.image.as_ref().expect("an executable");
+  image.symbols.iter().find(|s| s.name == name).unwrap_or_else(|| panic!("no symbol {name}"))
+}
+
+// Code of a function as it is in the executable
+pub fn code<'a>(exe: &'a Executable, func: &str) -> &'a [u8] {
+  let sym = symbol(exe, func);
+  let image = exe.image.as_ref().unwrap();
+  image.at(sym.address, sym.size, exe.unit.data_begin)
+}
+
+// Every token of text, errors included
+pub fn tokens(text: &str) -> Vec<Result<Token, String>> {
+  Lexer::new(0, text).map(|l| l.map(|l| l.tok).map_err(|d| d.msg)).collect()
+}
+
+// What the program writes to the console when run by blitz
+pub fn run(bytes: &[u8]) -> String {
+  static COUNT: AtomicUsize = AtomicUsize::new(0);
+  let n = COUNT.fetch_add(1, Ordering::SeqCst);
+  let path: PathBuf = std::env::temp_dir().join(format!("blitz-test-{}-{n}.out", std::process::id()));
+  std::fs::write(&path, bytes).unwrap();
+  let output = Command::new(env!("CARGO_BIN_EXE_blitz")).arg(&path).output().unwrap();
+  let _ = std::fs::remove_file(&path);
+  assert!(output.status.success(), "blitz failed: {}", String::from_utf8_lossy(&output.stderr));
+  String::from_utf8(output.stdout).unwrap()
+}
+
+// Like build with each of defines given as -D
+pub fn build_defined(text: &str, defines: &[&str], options: &mut Options) -> Result<Executable, Vec<String>> {
+  let mut sources = sources(&[("main.su", text)]);
+  for def in defines {
+    options.define(&mut sources, def).unwrap();
+  }
+  assemble(&mut sources, options).map_err(|diags| diags.into_iter().map(|d| d.msg).collect())
+}
+
+// Assembles the one input with the firmware
+pub fn options_for(input: &str) -> Options {
+  Options::new(&[input])
+}
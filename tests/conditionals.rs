@@ -0,0 +1,112 @@
+mod common;
+
+use common::*;
+
+// Writes A when the condition holds and B otherwise
+fn program(cond: &str) -> String {
+  format!("include \"lib.su\"
+func main
+  {cond}
+  putc 65
+  else
+  putc 66
+  endif
+  ret
+end
+")
+}
+
+fn output(cond: &str, defines: &[&str]) -> String {
+  let exe = build_defined(&program(cond), defines, &mut options()).unwrap();
+  run(&exe.bytes)
+}
+
+#[test]
+fn ifdef() {
+  assert_eq!(output("ifdef MODE", &["MODE"]), "A");
+  assert_eq!(output("ifdef MODE", &[]), "B");
+  assert_eq!(output("ifndef MODE", &[]), "A");
+  assert_eq!(output("ifndef MODE", &["MODE=0"]), "B");
+}
+
+#[test]
+fn if_expression() {
+  assert_eq!(output("if MODE", &["MODE=1"]), "A");
+  assert_eq!(output("if MODE", &["MODE=0"]), "B");
+  assert_eq!(output("if MODE & 2", &["MODE=3"]), "A");
+  assert_eq!(output("if MODE - 4", &["MODE=4"]), "B");
+}
+
+#[test]
+fn comparisons() {
+  assert_eq!(output("if MODE == 2", &["MODE=2"]), "A");
+  assert_eq!(output("if MODE == 2", &["MODE=3"]), "B");
+  assert_eq!(output("if MODE != 2", &["MODE=3"]), "A");
+  assert_eq!(output("if MODE >= 2 && MODE < 4", &["MODE=3"]), "A");
+  assert_eq!(output("if MODE >= 2 && MODE < 4", &["MODE=4"]), "B");
+  assert_eq!(output("if MODE <= 0 || MODE > 8", &["MODE=9"]), "A");
+  assert_eq!(output("if MODE << 1 == 8", &["MODE=4"]), "A");
+}
+
+// A condition the lexer could not read still opens its block, so the else
+// and endif which follow are not reported too
+#[test]
+fn broken_condition() {
+  let errs = errors("if 1 = 2
+value: .long 1
+else
+value: .long 2
+endif
+func main
+  ret
+end", &bare());
+  assert_eq!(errs, ["Unexpected character '='"]);
+  let errs = errors("if $
+else
+endif
+func main
+  ret
+end", &bare());
+  assert_eq!(errs, ["Unexpected character '$'"]);
+}
+
+#[test]
+fn nested() {
+  let text = "define A 1
+ifdef A
+  ifdef B
+    value: .long 1
+  else
+    value: .long 2
+  endif
+else
+  value: .long 3
+endif
+func main
+  mov r0, value
+  ret
+end";
+  let exe = build(text, &bare()).unwrap();
+  let sym = symbol(&exe, "value");
+  assert_eq!(exe.image.as_ref().unwrap().at(sym.address, 4, exe.unit.data_begin), 2u32.to_le_bytes());
+}
+
+#[test]
+fn unbalanced() {
+  let errs = errors("ifdef A\nfunc main\n  ret\nend", &bare());
+  assert_eq!(errs, ["Block is missing a matching 'endif'"]);
+  let errs = errors("endif\nfunc main\n  ret\nend", &bare());
+  assert_eq!(errs, ["'endif' without a matching 'if'"]);
+  let errs = errors("else\nfunc main\n  ret\nend", &bare());
+  assert_eq!(errs, ["'else' without a matching 'if'"]);
+  let errs = errors("ifdef A\nelse\nelse\nendif\nfunc main\n  ret\nend", &bare());
+  assert_eq!(errs, ["Block already has an 'else'"]);
+}
+
+#[test]
+fn bad_conditions() {
+  let errs = errors("if\nendif\nfunc main\n  ret\nend", &bare());
+  assert_eq!(errs, ["Expected condition after 'if'"]);
+  let errs = errors("if main\nendif\nfunc main\n  ret\nend", &bare());
+  assert_eq!(errs, ["Condition must be a constant integer"]);
+}
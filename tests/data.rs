@@ -0,0 +1,73 @@
+mod common;
+
+use blitz::compiler::assemble::Executable;
+use common::*;
+
+// Bytes of the data item name
+fn bytes<'a>(exe: &'a Executable, name: &str) -> &'a [u8] {
+  let sym = symbol(exe, name);
+  exe.image.as_ref().unwrap().at(sym.address, sym.size, exe.unit.data_begin)
+}
+
+fn data(text: &str) -> Executable {
+  let mut options = bare();
+  options.gc = false;
+  build(&format!("{text}\nfunc main\n  ret\nend"), &options).unwrap()
+}
+
+#[test]
+fn arrays() {
+  let exe = data("bytes: .byte 1, -1, 255\nshorts: .short 0x1234, -2\nints: .int 7\nlongs: .long -1");
+  assert_eq!(bytes(&exe, "bytes"), [1, 0xFF, 0xFF]);
+  assert_eq!(bytes(&exe, "shorts"), [0x34, 0x12, 0xFE, 0xFF]);
+  assert_eq!(bytes(&exe, "ints"), [7, 0, 0, 0]);
+  assert_eq!(bytes(&exe, "longs"), [0xFF; 8]);
+  let exe = data("value: .double 1.5, 2");
+  assert_eq!(bytes(&exe, "value")[..8], 1.5f64.to_le_bytes());
+  assert_eq!(bytes(&exe, "value")[8..], 2.0f64.to_le_bytes());
+}
+
+#[test]
+fn strings() {
+  let exe = data("a: .string \"a\\tb\\x41\\0\"\nb: .ascii 'xy'");
+  assert_eq!(bytes(&exe, "a"), b"a\tbA\0\0");
+  assert_eq!(bytes(&exe, "b"), b"xy");
+}
+
+#[test]
+fn padding() {
+  let exe = data("a: .byte 1\n.align 8\nb: .zero 3\nc: .space 2\n.align 16\nd: .byte 2");
+  let address = |name: &str| symbol(&exe, name).address;
+  assert_eq!(address("b") % 8, 0);
+  assert_eq!(address("c"), address("b") + 3);
+  assert_eq!(address("d") % 16, 0);
+  assert_eq!(bytes(&exe, "b"), [0, 0, 0]);
+  let exe = data("a: .byte 1\nb: .zero 0x80000");
+  assert_eq!(symbol(&exe, "b").size, 0x80000);
+}
+
+#[test]
+fn data_errors() {
+  let errs = |text: &str| errors(&format!("{text}\nfunc main\n  ret\nend"), &bare());
+  assert_eq!(errs("a: .byte 256"), ["Value does not fit in a byte"]);
+  assert_eq!(errs("a: .short 65536"), ["Value does not fit in a short"]);
+  assert_eq!(errs("a: .byte 1,,2"), ["Expected value between ','"]);
+  assert_eq!(errs("a: .align 3"), ["Alignment must be a power of two"]);
+  assert_eq!(errs("a: .zero 1, 2"), ["Expected a single byte count"]);
+  assert_eq!(errs("a: .zero -1"), ["Expected a constant byte count here"]);
+  assert_eq!(errs("a: .zero 0x80001"), ["Byte count must be at most 0x80000"]);
+  assert_eq!(errs("a: .space 0x7FFFFFFFFFFFFFFF"), ["Byte count must be at most 0x80000"]);
+  assert_eq!(errs("a: .align 0x4000000000000000"), ["Byte count must be at most 0x80000"]);
+  assert_eq!(errs("a: .int 1.5"), ["Floating point values have to be declared with .double"]);
+  assert_eq!(errs("a: .string 1"), ["Expected string here"]);
+  assert_eq!(errs("a: .bogus 1"), ["Unknown data type modifier"]);
+}
+
+// The error is at the count, not the directive
+#[test]
+fn count_location() {
+  let mut sources = sources(&[("main.su", "a: .zero 1 << 40\nfunc main\n  ret\nend")]);
+  let diags = blitz::compiler::assemble::assemble(&mut sources, &bare()).err().unwrap();
+  let loc = diags[0].loc.unwrap();
+  assert_eq!((loc.line, loc.col, loc.len), (1, 10, 7));
+}
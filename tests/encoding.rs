@@ -0,0 +1,81 @@
+mod common;
+
+use blitz::isa::{self, Opcode};
+use blitz::link::Header;
+use common::*;
+
+// Operand codes and immediates of the first instruction of main
+fn first(ins: &str) -> (Vec<u8>, Vec<u8>) {
+  let exe = build(&format!("func main\n  {ins}\n  ret\nend"), &bare()).unwrap();
+  let code = code(&exe, "main");
+  let prologue = u32::from_le_bytes(code[..4].try_into().unwrap());
+  let codes: Vec<u8> = isa::OPERAND_SHIFTS.iter().map(|s| ((prologue >> s) & isa::OPERAND_MASK) as u8).collect();
+  let size: usize = isa::immediate_sizes(prologue).unwrap().iter().sum();
+  (codes, code[4..4 + size].to_vec())
+}
+
+fn reg(name: &str) -> u8 {
+  isa::register(name).unwrap().unwrap()
+}
+
+#[test]
+fn immediates() {
+  let r0 = reg("r0");
+  assert_eq!(first("mov r0, 5"), (vec![r0, isa::IMM8, 0], vec![5]));
+  assert_eq!(first("mov r0, -2"), (vec![r0, isa::IMM8, 0], vec![0xFE]));
+  assert_eq!(first("mov r0, 1000"), (vec![r0, isa::IMM16, 0], 1000i16.to_le_bytes().to_vec()));
+  assert_eq!(first("mov r0, 70000"), (vec![r0, isa::IMM32, 0], 70000i32.to_le_bytes().to_vec()));
+  let big = 1i64 << 40;
+  assert_eq!(first(&format!("mov r0, {big}")), (vec![r0, isa::IMM, 0], big.to_le_bytes().to_vec()));
+}
+
+#[test]
+fn offsets() {
+  // The register byte comes before the sign extended offset
+  assert_eq!(first("mov r0, [r1+4]"), (vec![reg("r0"), isa::OFFSET8, 0], vec![reg("r1"), 4]));
+  assert_eq!(first("mov r0, [r2-300]"), (vec![reg("r0"), isa::OFFSET16, 0], vec![reg("r2"), 0xD4, 0xFE]));
+  assert_eq!(first("mov r0, [r3+100000]").0[1], isa::OFFSET32);
+}
+
+// Addresses are left to the linker and always take 4 bytes
+#[test]
+fn addresses() {
+  let (codes, imm) = first("jmp 1f\n1:");
+  assert_eq!(codes[0], isa::IMM32);
+  assert_eq!(imm.len(), 4);
+}
+
+#[test]
+fn sign_extension() {
+  for value in [0, 1, -1, 127, -128, 128, 32767, -32769, i32::MAX as i64, i32::MIN as i64 - 1, i64::MIN] {
+    let width = isa::width(value);
+    assert_eq!(isa::sign_extend(&value.to_le_bytes()[..width]), value);
+  }
+  assert_eq!(isa::width(127), 1);
+  assert_eq!(isa::width(128), 2);
+  assert_eq!(isa::width(1 << 31), 8);
+}
+
+// Compact operands need a version 2 executable
+#[test]
+fn header() {
+  let exe = build("func main\n  ret\nend", &bare()).unwrap();
+  let header = Header::read(&exe.bytes).unwrap();
+  assert_eq!((header.major, header.minor), (2, 1));
+  assert_eq!(header.start, symbol(&exe, "main").address);
+}
+
+#[test]
+fn opcodes() {
+  for ins in isa::INSTRUCTIONS {
+    assert_eq!(isa::by_opcode(ins.opcode as u32).map(|i| i.mnemonic), Some(ins.mnemonic));
+    assert_eq!(Opcode::new(ins.opcode as u32), Some(ins.opcode));
+  }
+  assert!(isa::by_opcode(1023).is_none());
+}
+
+#[test]
+fn runs() {
+  let exe = build("include \"lib.su\"\nfunc main\n  mov r2, 1000\n  sub r2, r2, 935\n  putc r2\n  ret\nend", &options()).unwrap();
+  assert_eq!(run(&exe.bytes), "A");
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::*;
+
+const PUTS: &str = "macro puts value
+  mov r0, 1
+  mov r1, value
+  syscall 2
+endm";
+
+#[test]
+fn relative_and_once() {
+  let files = [
+    ("src/main.su", "include \"lib/a.su\"\ninclude \"lib/b.su\"\nfunc main\n  puts 65\n  call b\n  ret\nend"),
+    ("src/lib/a.su", "include \"puts.su\""),
+    // Only the copy next to the includer is seen, and only once
+    ("src/lib/b.su", "include \"puts.su\"\ninclude \"a.su\"\nfunc b\n  puts 66\n  ret\nend"),
+    ("src/lib/puts.su", PUTS),
+    ("src/puts.su", "this is not assembly")
+  ];
+  let exe = build_with(&files, &options_for("src/main.su")).unwrap();
+  assert_eq!(run(&exe.bytes), "AB");
+}
+
+#[test]
+fn search_paths() {
+  let files = [("main.su", "include \"puts.su\"\nfunc main\n  puts 65\n  ret\nend"), ("inc/puts.su", PUTS)];
+  let mut options = options();
+  let errs = build_with(&files, &options).err().unwrap();
+  assert_eq!(errs[0], "Could not find include file puts.su");
+  options.search.push("inc".into());
+  assert_eq!(run(&build_with(&files, &options).unwrap().bytes), "A");
+}
+
+#[test]
+fn cycles() {
+  let files = [
+    ("main.su", "include \"a.su\"\nfunc main\n  ret\nend"),
+    ("a.su", "include \"b.su\""),
+    ("b.su", "include \"a.su\"")
+  ];
+  let errs = build_with(&files, &bare()).err().unwrap();
+  assert_eq!(errs, ["Include cycle a.su -> b.su -> a.su"]);
+}
+
+// The inputs are assembled as if each included the next
+#[test]
+fn several_inputs() {
+  let files = [("one.su", "func main\n  call two\n  ret\nend"), ("two.su", "include \"lib.su\"\nfunc two\n  putc 65\n  ret\nend")];
+  let mut options = options_for("one.su");
+  options.inputs.push("two.su".to_owned());
+  assert_eq!(run(&build_with(&files, &options).unwrap().bytes), "A");
+}
+
+#[test]
+fn firmware() {
+  // Without the firmware main is started directly
+  let exe = build("func main\n  mov r0, 1\n  mov r1, 65\n  syscall 2\n  ret\nend", &bare()).unwrap();
+  assert_eq!(run(&exe.bytes), "A");
+  assert!(exe.image.as_ref().unwrap().symbols.iter().all(|s| s.name != "_start"));
+  let files = [
+    ("main.su", "func main\n  ret\nend"),
+    ("boot.su", "func boot\n.firmware\n  mov r0, 1\n  mov r1, 66\n  syscall 2\n  call main\n  ret\nend")
+  ];
+  let mut options = options();
+  options.firmware = Some("boot.su".to_owned());
+  options.entry = "boot".to_owned();
+  assert_eq!(run(&build_with(&files, &options).unwrap().bytes), "B");
+  options.entry = "nowhere".to_owned();
+  let errs = build_with(&files, &options).err().unwrap();
+  assert!(errs.contains(&"Entry point nowhere is not defined".to_owned()), "{errs:?}");
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::*;
+
+// Functions can be called before they are defined
+#[test]
+fn forward_references() {
+  let text = "include \"lib.su\"\nfunc main\n  call later\n  ret\nend\nfunc later\n  putc 65\n  ret\nend";
+  assert_eq!(run(&build(text, &options()).unwrap().bytes), "A");
+}
+
+// Jumps go both ways within a function
+#[test]
+fn forward_and_backward_jumps() {
+  let text = "include \"lib.su\"
+func main
+  mov r2, 64
+again:
+  inc r2
+  cmp r2, 66
+  jge done
+  jmp again
+done:
+  putc r2
+  ret
+end";
+  assert_eq!(run(&build(text, &options()).unwrap().bytes), "B");
+}
+
+#[test]
+fn scoped_to_function() {
+  let text = "include \"lib.su\"
+func main
+  call other
+  jmp done
+  putc 66
+done:
+  putc 65
+  ret
+end
+
+func other
+  jmp done
+  putc 66
+done:
+  ret
+end";
+  let exe = build(text, &options()).unwrap();
+  assert_eq!(run(&exe.bytes), "A");
+  let names: Vec<&str> = exe.image.as_ref().unwrap().symbols.iter().map(|s| s.name.as_str()).collect();
+  assert!(names.contains(&"main.done") && names.contains(&"other.done"));
+}
+
+#[test]
+fn numeric() {
+  let text = "include \"lib.su\"
+func main
+  mov r2, 0
+1:
+  inc r2
+  cmp r2, 3
+  jne 1b
+  jmp 1f
+  putc 66
+1:
+  add r2, r2, 62
+  putc r2
+  ret
+end";
+  assert_eq!(run(&build(text, &options()).unwrap().bytes), "A");
+}
+
+#[test]
+fn errors_in_labels() {
+  let errs = errors("func main\nx:\nx:\n  ret\nend", &bare());
+  assert_eq!(errs, ["Label is already defined in this function"]);
+  let errs = errors("func main\n  jmp done\nend\nfunc other\ndone:\n  ret\nend", &bare());
+  assert_eq!(errs, ["Function or label done not found"]);
+  let errs = errors("func main\n  jmp 1f\n  ret\nend", &bare());
+  assert_eq!(errs, ["Function or label 1f not found"]);
+}
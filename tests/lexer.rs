@@ -0,0 +1,160 @@
+mod common;
+
+use blitz::compiler::expr;
+use blitz::compiler::r#proc::{Lexeme, Lexer, Token};
+use common::*;
+
+// Value of a constant expression
+fn eval(text: &str) -> Result<i64, String> {
+  let toks: Vec<Lexeme> = Lexer::new(0, text)
+    .map(|l| l.unwrap())
+    .filter(|l| l.tok != Token::NEWLINE)
+    .collect();
+  expr::parse(&toks, &[]).and_then(|e| e.constant()).map_err(|d| d.msg)
+}
+
+#[test]
+fn statements() {
+  let toks = tokens("start: mov r0, [sp+8] // comment\nsyscall 2; ret");
+  let expected = [
+    Token::LABEL("start".to_owned()),
+    Token::IDENT("mov".to_owned()),
+    Token::IDENT("r0".to_owned()),
+    Token::COMMA,
+    Token::LBRACKET,
+    Token::IDENT("sp".to_owned()),
+    Token::PLUS,
+    Token::INT(8),
+    Token::RBRACKET,
+    Token::NEWLINE,
+    Token::IDENT("syscall".to_owned()),
+    Token::INT(2),
+    Token::NEWLINE,
+    Token::IDENT("ret".to_owned()),
+    Token::NEWLINE
+  ];
+  assert_eq!(toks, expected.map(Ok));
+}
+
+#[test]
+fn numbers() {
+  let toks = tokens("a<b<=c<<d>=e>>f==g!=h&&i&j||k|l>m");
+  let ops: Vec<Token> = toks.into_iter().map(|t| t.unwrap()).filter(|t| !matches!(t, Token::IDENT(..) | Token::NEWLINE)).collect();
+  let expected = [
+    Token::LT, Token::LE, Token::SHL, Token::GE, Token::SHR, Token::EQ, Token::NE,
+    Token::LAND, Token::AMP, Token::LOR, Token::PIPE, Token::GT
+  ];
+  assert_eq!(ops, expected);
+  assert_eq!(tokens("a = b")[1], Err("Unexpected character '='".to_owned()));
+  let toks = tokens("0x1F 0b101 -3 1.5 1f 2b");
+  assert_eq!(toks[0], Ok(Token::INT(31)));
+  assert_eq!(toks[1], Ok(Token::INT(5)));
+  assert_eq!(toks[2..4], [Ok(Token::MINUS), Ok(Token::INT(3))]);
+  assert_eq!(toks[4], Ok(Token::DECIMAL(1.5)));
+  assert_eq!(toks[5], Ok(Token::IDENT("1f".to_owned())));
+  assert_eq!(toks[6], Ok(Token::IDENT("2b".to_owned())));
+  assert_eq!(tokens("0xZZ")[0], Err("Malformed hexadecimal integer".to_owned()));
+  assert_eq!(tokens("12ab")[0], Err("Malformed number".to_owned()));
+  assert_eq!(tokens("2e3")[0], Ok(Token::DECIMAL(2000.0)));
+  assert_eq!(tokens("99999999999999999999")[0], Err("Integer does not fit in 64 bits".to_owned()));
+  // Words f64 would read as numbers
+  for word in ["inf", "nan", "infinity", "NaN"] {
+    assert_eq!(tokens(word)[0], Ok(Token::IDENT(word.to_owned())));
+  }
+}
+
+#[test]
+fn strings() {
+  assert_eq!(tokens(r#""a\tb\x41\"""#)[0], Ok(Token::STRING("a\tbA\"".to_owned())));
+  assert_eq!(tokens("'x'")[0], Ok(Token::STRING("x".to_owned())));
+  assert_eq!(tokens("\"abc")[0], Err("Unclosed string literal".to_owned()));
+  // Lexing carries on after a bad escape
+  let toks = tokens(r#""\q" 1"#);
+  assert_eq!(toks[0], Err("Unknown escape sequence".to_owned()));
+  assert_eq!(toks[1], Ok(Token::INT(1)));
+}
+
+#[test]
+fn continuation_and_comments() {
+  let toks = tokens("mov r0, \\ // more\n 1 /* a\n b */ ret");
+  assert!(!toks[..5].contains(&Ok(Token::NEWLINE)));
+  assert_eq!(toks[3], Ok(Token::INT(1)));
+  assert_eq!(toks[4], Ok(Token::IDENT("ret".to_owned())));
+  assert_eq!(tokens("/* open")[0], Err("Unclosed comment".to_owned()));
+  assert_eq!(tokens("\\ x")[0], Err("Expected end of line after '\\'".to_owned()));
+}
+
+#[test]
+fn locations() {
+  let lexemes: Vec<Lexeme> = Lexer::new(3, "func main\n  mov r0, 1").map(|l| l.unwrap()).collect();
+  let main = &lexemes[1];
+  assert_eq!((main.loc.file, main.loc.line, main.loc.col, main.loc.len), (3, 1, 6, 4));
+  let one = lexemes.iter().find(|l| l.tok == Token::INT(1)).unwrap();
+  assert_eq!((one.loc.line, one.loc.col), (2, 11));
+}
+
+#[test]
+fn precedence() {
+  assert_eq!(eval("1 + 2 * 3"), Ok(7));
+  assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+  assert_eq!(eval("1 << 4 + 1"), Ok(32));
+  assert_eq!(eval("6 & 3 | 8"), Ok(10));
+  assert_eq!(eval("-7 / 2"), Ok(-3));
+  assert_eq!(eval("-7 % 2"), Ok(-1));
+  assert_eq!(eval("~0"), Ok(-1));
+  assert_eq!(eval("10 - 4 - 3"), Ok(3));
+  assert_eq!(eval("1 + 1 == 2"), Ok(1));
+  assert_eq!(eval("3 < 2 || 2 <= 2 && 5 > 4"), Ok(1));
+  assert_eq!(eval("1 == 2 | 1"), Ok(1));
+  assert_eq!(eval("(4 >= 5) + (4 != 5) * 2"), Ok(2));
+}
+
+#[test]
+fn expression_errors() {
+  assert_eq!(eval("1 / 0"), Err("Division by zero in constant expression".to_owned()));
+  assert!(eval("0x7FFFFFFFFFFFFFFF + 1").is_err());
+  assert!(eval("1 +").is_err());
+  assert!(eval("(1").is_err());
+  assert_eq!(eval("1 2"), Err("Expected operator here".to_owned()));
+  assert_eq!(eval("1.5 + 1"), Err("Floating point numbers cannot be used in expressions".to_owned()));
+}
+
+#[test]
+fn defines_in_expressions() {
+  let exe = build("define SIZE 4 * 2\nvalue: .long SIZE + 1\nfunc main\n  mov r0, value\n  ret\nend", &bare()).unwrap();
+  let sym = symbol(&exe, "value");
+  let bytes = exe.image.as_ref().unwrap().at(sym.address, 4, exe.unit.data_begin);
+  assert_eq!(bytes, 9u32.to_le_bytes());
+}
+
+#[test]
+fn operands() {
+  let text = "define SIZE 2
+table: .long 1, 2, 3, 4
+func main
+  mov r1, table + SIZE * 4
+  mov r0, [r1 - (SIZE << 2)]
+  mov r2, [r1 + 8]
+  add r1, r0, r2
+  mov r0, 1
+  syscall 2
+  ret
+end";
+  let exe = build(&format!("include \"lib.su\"\n{text}"), &options()).unwrap();
+  // 1 + 3
+  assert_eq!(run(&exe.bytes), "\u{4}");
+  let errs = errors("func main\n  mov r0, [r1 + r2]\n  ret\nend", &bare());
+  assert_eq!(errs, ["Registers cannot be used in expressions"]);
+  let errs = errors("define A B\ndefine B A\nfunc main\n  mov r0, A\n  ret\nend", &bare());
+  assert_eq!(errs, ["Define A refers to itself"]);
+}
+
+// A lexer error is reported once and the rest of the statement is still parsed
+#[test]
+fn recovery() {
+  let errs = errors("func ma$in\n  mov r0, 1\n  ret\nend", &bare());
+  assert_eq!(errs, ["Unexpected character '$'"]);
+  let errs = errors("func main\n  mov r0, 1 $\n  bogus r0\n  ret\nend", &bare());
+  assert_eq!(errs.len(), 2);
+  assert_eq!(errs[0], "Unexpected character '$'");
+}
@@ -0,0 +1,209 @@
+mod common;
+
+use blitz::archive::{self, Archive};
+use blitz::link::{self, DATA_BEGIN};
+use blitz::object::Object;
+use common::*;
+
+const MAIN: &str = "func main
+  call greet
+  ret
+end";
+
+// Writes the character in r2, then A
+const GREET: &str = "func greet
+  mov r0, 1
+  mov r1, letter
+  mov r1, [r1]
+  syscall 2
+  call shout
+  ret
+end
+
+func shout
+  mov r0, 1
+  mov r1, 65
+  syscall 2
+  ret
+end
+
+letter: .byte 66";
+
+const UNUSED: &str = "func unused
+  ret
+end";
+
+fn library() -> Archive {
+  let members = vec![object("greet.su", GREET), object("unused.su", UNUSED)];
+  Archive::new("libgreet.a", members).unwrap()
+}
+
+fn names(objects: &[Object]) -> Vec<&str> {
+  objects.iter().map(|o| o.name.as_str()).collect()
+}
+
+#[test]
+fn objects() {
+  let objects = [object("firmware.su", FIRMWARE), object("main.su", MAIN), object("greet.su", GREET)];
+  let image = link::link(&objects, "_start", DATA_BEGIN, false).unwrap();
+  assert_eq!(run(&image.bytes), "BA");
+}
+
+#[test]
+fn round_trip() {
+  let obj = object("greet.su", GREET);
+  let mut bytes: Vec<u8> = Vec::new();
+  obj.write(&mut bytes).unwrap();
+  let read = Object::read("greet.su", &mut bytes.as_slice()).unwrap();
+  assert_eq!((&read.code, &read.data), (&obj.code, &obj.data));
+  assert_eq!(read.symbols.len(), obj.symbols.len());
+  assert_eq!(read.relocs.len(), obj.relocs.len());
+  assert!(Object::read("greet.su", &mut &bytes[..bytes.len() - 1]).is_err());
+}
+
+// Lengths in the file are not trusted
+#[test]
+fn malformed_objects() {
+  let mut bytes: Vec<u8> = Vec::new();
+  bytes.extend(blitz::object::MAGIC.to_le_bytes());
+  bytes.extend(blitz::object::VERSION.to_le_bytes());
+  bytes.extend((u64::MAX >> 1).to_le_bytes());
+  bytes.extend([0u8; 16]);
+  let err = Object::read("huge.o", &mut bytes.as_slice()).err().unwrap();
+  assert_eq!(err.to_string(), "Malformed object file");
+  let mut good: Vec<u8> = Vec::new();
+  object("greet.su", GREET).write(&mut good).unwrap();
+  for len in 0..good.len() {
+    assert!(Object::read("greet.su", &mut &good[..len]).is_err());
+  }
+}
+
+fn read_back(obj: &Object) -> String {
+  let mut bytes: Vec<u8> = Vec::new();
+  obj.write(&mut bytes).unwrap();
+  Object::read("bad.o", &mut bytes.as_slice()).expect_err("a malformed object").to_string()
+}
+
+// Alignment, offsets and nesting in the file are not trusted either
+#[test]
+fn malformed_contents() {
+  let mut obj = object("greet.su", GREET);
+  obj.data_align = 1 << 63;
+  assert_eq!(read_back(&obj), "Malformed object file");
+  obj.data_align = 3;
+  assert_eq!(read_back(&obj), "Malformed object file");
+  let mut obj = object("greet.su", GREET);
+  obj.symbols[0].offset = usize::MAX - 4;
+  assert_eq!(read_back(&obj), "Malformed object file");
+  let mut obj = object("greet.su", GREET);
+  obj.relocs[0].offset = usize::MAX - 2;
+  assert_eq!(read_back(&obj), "Malformed object file");
+  obj.relocs[0].offset = obj.code.len() - 2;
+  assert_eq!(read_back(&obj), "Malformed object file");
+  // 8 bytes of code patched by a million nested negations
+  let mut bytes: Vec<u8> = Vec::new();
+  bytes.extend(blitz::object::MAGIC.to_le_bytes());
+  bytes.extend(blitz::object::VERSION.to_le_bytes());
+  bytes.extend(8u64.to_le_bytes());
+  bytes.extend([0u8; 8]);
+  bytes.extend(0u64.to_le_bytes());
+  bytes.extend(1u64.to_le_bytes());
+  bytes.extend(0u32.to_le_bytes());
+  bytes.extend(1u32.to_le_bytes());
+  bytes.extend([0u8; 10]);
+  bytes.extend(std::iter::repeat_n(2u8, 1_000_000));
+  bytes.push(0);
+  bytes.extend([0u8; 16]);
+  let err = Object::read("deep.o", &mut bytes.as_slice()).err().unwrap();
+  assert_eq!(err.to_string(), "Malformed object file");
+}
+
+#[test]
+fn link_errors() {
+  let objects = [object("main.su", MAIN), object("greet.su", GREET), object("again.su", GREET)];
+  let errs = link::link(&objects, "main", DATA_BEGIN, false).err().unwrap();
+  assert!(errs.contains(&"Symbol greet is defined in both greet.su and again.su".to_owned()));
+  let objects = [object("main.su", MAIN)];
+  assert_eq!(archive::undefined(&objects, "_start"), ["_start", "greet"]);
+  assert!(link::link(&objects, "main", DATA_BEGIN, false).is_err());
+  // Execution only starts at global functions
+  let objects = [object("loop.su", "func main\nagain:\n  jmp again\nend\nvalue: .long 1")];
+  assert!(link::link(&objects, "main", DATA_BEGIN, false).is_ok());
+  for entry in ["value", "main.again"] {
+    let errs = link::link(&objects, entry, DATA_BEGIN, false).err().unwrap();
+    assert_eq!(errs, [format!("Entry point {entry} is not defined")]);
+  }
+}
+
+#[test]
+fn selection() {
+  let lib = library();
+  assert_eq!(lib.index.iter().map(|i| i.0.as_str()).collect::<Vec<_>>(), ["letter", "greet", "shout", "unused"]);
+  let mut objects = vec![object("main.su", MAIN)];
+  archive::select(&mut objects, &[lib], "main");
+  assert_eq!(names(&objects), ["main.su", "greet.su"]);
+  assert!(archive::undefined(&objects, "main").is_empty());
+  // Nothing is taken when nothing is missing
+  let mut objects = vec![object("main.su", MAIN), object("greet.su", GREET)];
+  archive::select(&mut objects, &[library()], "main");
+  assert_eq!(names(&objects), ["main.su", "greet.su"]);
+}
+
+// Members are searched for what other members need too
+#[test]
+fn selection_follows_members() {
+  let first = Archive::new("a.a", vec![object("greet.su", "func greet\n  call shout\n  ret\nend")]).unwrap();
+  let second = Archive::new("b.a", vec![object("shout.su", "func shout\n  ret\nend")]).unwrap();
+  let mut objects = vec![object("main.su", MAIN)];
+  archive::select(&mut objects, &[first, second], "main");
+  assert_eq!(names(&objects), ["main.su", "greet.su", "shout.su"]);
+}
+
+// What the members taken for a name refer to, in the program or not
+#[test]
+fn referenced() {
+  let mut refs = archive::referenced(&[library()], vec!["greet".to_owned()]);
+  refs.sort();
+  assert_eq!(refs, ["letter", "shout"]);
+  let lib = Archive::new("libfw.a", vec![object("firmware.su", FIRMWARE)]).unwrap();
+  assert!(archive::referenced(&[lib], vec!["_start".to_owned()]).contains(&"main".to_owned()));
+  assert!(archive::referenced(&[library()], vec!["missing".to_owned()]).is_empty());
+}
+
+#[test]
+fn archives() {
+  let errs = Archive::new("bad.a", vec![object("greet.su", GREET), object("again.su", GREET)]).err().unwrap();
+  assert!(errs.contains(&"Symbol greet is defined in both greet.su and again.su".to_owned()));
+  let mut bytes: Vec<u8> = Vec::new();
+  library().write(&mut bytes).unwrap();
+  let read = Archive::read("libgreet.a", &mut bytes.as_slice()).unwrap();
+  assert_eq!(read.index, library().index);
+  assert_eq!(names(&read.members), ["libgreet.a(greet.su)", "libgreet.a(unused.su)"]);
+  assert!(Archive::read("libgreet.a", &mut &bytes[..bytes.len() / 2]).is_err());
+}
+
+#[test]
+fn malformed_archives() {
+  let mut bytes: Vec<u8> = Vec::new();
+  bytes.extend(archive::MAGIC.to_le_bytes());
+  bytes.extend(archive::VERSION.to_le_bytes());
+  // No index and one member named a of nearly 2^63 bytes
+  bytes.extend(0u32.to_le_bytes());
+  bytes.extend(1u32.to_le_bytes());
+  bytes.extend(1u32.to_le_bytes());
+  bytes.push(b'a');
+  bytes.extend((u64::MAX >> 1).to_le_bytes());
+  let err = Archive::read("huge.a", &mut bytes.as_slice()).err().unwrap();
+  assert_eq!(err.to_string(), "Malformed archive");
+}
+
+#[test]
+fn assemble_with_library() {
+  let mut options = options();
+  options.libs = vec![library()];
+  let exe = build(MAIN, &options).unwrap();
+  assert_eq!(run(&exe.bytes), "BA");
+  assert!(exe.image.unwrap().symbols.iter().all(|s| s.name != "unused"));
+  let errs = errors("func main\n  call missing\n  ret\nend", &options);
+  assert_eq!(errs, ["Function or label missing not found"]);
+}
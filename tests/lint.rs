@@ -0,0 +1,90 @@
+mod common;
+
+use blitz::archive::Archive;
+use common::*;
+
+#[test]
+fn unused() {
+  let warns = warnings("value: .long 1\nfunc main\n  jmp 1f\nskip:\n1:\n  ret\nend\nfunc other\n  ret\nend", &bare());
+  assert_eq!(warns, ["Data value is never used", "Label skip is never used", "Function other is never used"]);
+}
+
+#[test]
+fn unreachable_and_registers() {
+  let warns = warnings("func main\n  ret\n  nop\nend", &bare());
+  assert_eq!(warns, ["Instruction can never be reached"]);
+  let warns = warnings("func main\n  mov r8, 1\n  ret\nend", &bare());
+  assert_eq!(warns, ["r8 is changed without being pushed first, functions have to preserve r7 to r14"]);
+  assert!(warnings("func main\n  push r8\n  mov r8, 1\n  pop r8\n  ret\nend", &bare()).is_empty());
+}
+
+#[test]
+fn privileged_and_read_only() {
+  let warns = warnings("func main\n  setflags\n  ret\nend", &bare());
+  assert_eq!(warns, ["setflags is privileged and only runs in .firmware functions"]);
+  let warns = warnings("table: .long 0\nfunc main\n  mov r1, table\n  mov [r1], 5\n  ret\nend", &bare());
+  assert_eq!(warns, ["Write to data table, which the firmware maps read-only"]);
+}
+
+#[test]
+fn options() {
+  let text = "func main\n  ret\n  nop\nend";
+  let mut options = bare();
+  options.warnings.set("no-unreachable").unwrap();
+  assert!(warnings(text, &options).is_empty());
+  let mut options = bare();
+  options.warnings.set("error=unreachable").unwrap();
+  assert_eq!(errors(text, &options), ["Instruction can never be reached"]);
+  assert!(options.warnings.set("no-bogus").is_err());
+}
+
+// Included files are libraries, their unused labels are not reported
+#[test]
+fn labels_in_includes() {
+  let lib = "func helper\nskip:\n  ret\nend";
+  let text = "include \"lib2.su\"\nfunc main\n  call helper\n  ret\nend";
+  let warns = match build_with(&[("main.su", text), ("lib2.su", lib)], &bare()) {
+    Ok(exe) => exe.warnings.iter().map(|d| d.msg.clone()).collect::<Vec<_>>(),
+    Err(e) => panic!("{e:?}")
+  };
+  assert!(warns.is_empty(), "{warns:?}");
+}
+
+// A label in a macro body is reported once, where the body defines it
+#[test]
+fn labels_in_macros() {
+  let text = "macro twice\nagain:\n  nop\nendm\nfunc main\n  twice\n  twice\n  ret\nend";
+  let exe = build(text, &bare()).unwrap();
+  assert_eq!(exe.warnings.iter().map(|d| d.msg.as_str()).collect::<Vec<_>>(), ["Label again is never used"]);
+  assert_eq!(exe.warnings[0].loc.map(|l| l.line), Some(2));
+  // Used by one expansion is used
+  let text = "macro loop\nagain:\n  jmp again\nendm\nfunc main\n  loop\n  loop\n  ret\nend";
+  assert!(warnings(text, &bare()).iter().all(|w| !w.starts_with("Label")));
+}
+
+// gc keeps main when only the firmware from the archive calls it
+#[test]
+fn kept_for_library() {
+  let lib = Archive::new("libstd.a", vec![object("firmware.su", FIRMWARE)]).unwrap();
+  let mut options = bare();
+  options.entry = "_start".to_owned();
+  options.libs = vec![lib];
+  options.warnings.set("no-unused").unwrap();
+  let exe = build("include \"lib.su\"\nfunc main\n  putc 66\n  ret\nend\nfunc other\n  ret\nend", &options).unwrap();
+  let removed: Vec<&str> = exe.removed.iter().map(|r| r.name.as_str()).collect();
+  assert!(removed.contains(&"other") && !removed.contains(&"main"), "{removed:?}");
+  assert_eq!(run(&exe.bytes), "B");
+}
+
+// A firmware linked from an archive calls main, so main is used
+#[test]
+fn used_by_library() {
+  let lib = Archive::new("libstd.a", vec![object("firmware.su", FIRMWARE)]).unwrap();
+  let mut options = bare();
+  options.entry = "_start".to_owned();
+  options.libs = vec![lib];
+  options.gc = false;
+  let exe = build("include \"lib.su\"\nfunc main\n  putc 65\n  ret\nend", &options).unwrap();
+  assert!(exe.warnings.is_empty(), "{:?}", exe.warnings.iter().map(|d| &d.msg).collect::<Vec<_>>());
+  assert_eq!(run(&exe.bytes), "A");
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Frames the messages the way an editor does
+fn messages(bodies: &[String]) -> Vec<u8> {
+  let mut out: Vec<u8> = Vec::new();
+  for body in bodies {
+    write!(out, "Content-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+  }
+  out
+}
+
+// Runs blsp over a whole session and returns everything it wrote
+fn session(bodies: &[String]) -> (bool, String) {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_blsp"))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+  child.stdin.take().unwrap().write_all(&messages(bodies)).unwrap();
+  let out = child.wait_with_output().unwrap();
+  (out.status.success(), String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+#[test]
+fn diagnostics_and_definition() {
+  let dir = std::env::temp_dir().join(format!("blitz-lsp-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let path = dir.join("main.su");
+  let text = "func main\n  call helper\n  mov 5, r0\n  ret\nend\nfunc helper\n  ret\nend";
+  std::fs::write(&path, text).unwrap();
+  let uri = format!("file://{}", path.display());
+  let doc = format!("{{\"uri\":\"{uri}\",\"languageId\":\"su\",\"version\":1,\"text\":{text:?}}}");
+  let (ok, out) = session(&[
+    "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{}}".to_owned(),
+    format!("{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didOpen\",\"params\":{{\"textDocument\":{doc}}}}}"),
+    format!("{{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"textDocument/definition\",\"params\":{{\"textDocument\":{{\"uri\":\"{uri}\"}},\"position\":{{\"line\":1,\"character\":8}}}}}}"),
+    "{\"jsonrpc\":\"2.0\",\"id\":3,\"method\":\"shutdown\"}".to_owned(),
+    "{\"jsonrpc\":\"2.0\",\"method\":\"exit\"}".to_owned()
+  ]);
+  let _ = std::fs::remove_dir_all(&dir);
+  assert!(ok, "{out}");
+  assert!(out.contains("\"name\":\"blsp\""), "{out}");
+  // The bad mov is reported on its own line
+  assert!(out.contains("textDocument/publishDiagnostics") && out.contains("\"severity\":1"), "{out}");
+  assert!(out.contains("\"range\":{\"start\":{\"line\":2"), "{out}");
+  // call helper goes to line 6
+  assert!(out.contains("\"id\":2,\"result\":[{\"uri\"") && out.contains("\"range\":{\"start\":{\"line\":5"), "{out}");
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn expansion() {
+  let text = "include \"lib.su\"
+macro twice value
+  putc value
+  putc value
+endm
+
+// Labels in the body are new for every use
+macro skip first, second
+  jmp over
+  putc first
+over:
+  putc second
+endm
+
+func main
+  twice 65
+  skip 66, 67
+  skip 66, 68
+  ret
+end";
+  assert_eq!(run(&build(text, &options()).unwrap().bytes), "AACD");
+}
+
+#[test]
+fn macro_errors() {
+  let errs = errors("macro m a, a\nendm\nfunc main\n  ret\nend", &bare());
+  assert_eq!(errs, ["Duplicate macro parameter"]);
+  let errs = errors("macro m a\nendm\nfunc main\n  m 1, 2\n  ret\nend", &bare());
+  assert_eq!(errs, ["Macro m expects 1 argument(s) but was given 2"]);
+  let errs = errors("macro m a, b\nendm\nfunc main\n  m 1,\n  ret\nend", &bare());
+  assert_eq!(errs, ["Expected macro argument between ','"]);
+  let errs = errors("macro m\nendm\nmacro m\nendm\nfunc main\n  ret\nend", &bare());
+  assert_eq!(errs, ["Macro is already defined"]);
+  let errs = errors("macro m\n  m\nendm\nfunc main\n  m\n  ret\nend", &bare());
+  assert_eq!(errs, ["Macro expansion is nested too deeply"]);
+  let errs = errors("endm\nfunc main\n  ret\nend", &bare());
+  assert_eq!(errs, ["'endm' without a matching 'macro'"]);
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use common::*;
+
+fn check(ins: &str) -> Vec<String> {
+  errors(&format!("func main\n  {ins}\n  ret\nend"), &bare())
+}
+
+#[test]
+fn operand_kinds() {
+  assert_eq!(check("fadd r0, r1, r2"), ["Operand 1 of fadd must be a floating point register"]);
+  assert_eq!(check("mov 5, r0"), ["Operand 1 of mov must be an integer register or a memory operand like [r0 + 8]"]);
+  assert_eq!(check("add r0, [r1], 1"), ["Operand 2 of add must be an integer register or an integer"]);
+  assert_eq!(check("fmov f0, 1"), ["Operand 2 of fmov must be a floating point number"]);
+  assert_eq!(check("lea r0, r1"), ["Operand 2 of lea must be a memory operand like [r0 + 8]"]);
+  assert_eq!(check("add r0, r1"), ["Instruction has been given more or less arguments than needed"]);
+  assert_eq!(check("mov r0, \"a\""), ["Strings cannot be used as operands"]);
+  assert_eq!(check("mov r0, [5]"), ["First argument to offset must be register"]);
+  assert_eq!(check("mov r0, [r1"), ["Unclosed offset"]);
+  assert_eq!(check("mov r0 r1"), ["Expected ',' between operands"]);
+  assert_eq!(check("mov r0, r20"), ["Invalid register number"]);
+}
+
+// Integers and addresses can stand for each other
+#[test]
+fn accepted() {
+  let text = "func main
+  mov r0, main
+  call 0x20
+  push 1
+  fadd f0, f1, 1.5
+  mov [sp + 8], r1
+  jmp done
+done:
+  ret
+end";
+  let mut options = bare();
+  options.warnings.set("no-all").unwrap();
+  build(text, &options).unwrap();
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use blitz::compiler::assemble::assemble;
+use blitz::compiler::emit;
+use blitz::debug::Debug;
+use blitz::link::Header;
+use common::*;
+
+const TEXT: &str = "macro two
+  nop
+  nop
+endm
+value: .long 7
+func main
+  mov r0, value
+  two
+  ret
+end";
+
+#[test]
+fn listing() {
+  let mut sources = sources(&[("main.su", TEXT)]);
+  let exe = assemble(&mut sources, &bare()).unwrap();
+  let listing = emit::listing(&exe.unit, exe.image.as_ref().unwrap(), &sources);
+  let lines: Vec<&str> = listing.lines().collect();
+  assert_eq!(lines[0], "main.su:");
+  let main = symbol(&exe, "main").address;
+  let mov = lines.iter().find(|l| l.ends_with("mov r0, value")).unwrap();
+  assert!(mov.starts_with(&format!("    7  {main:#08x}  ")), "{mov}");
+  // Both nops of the macro call are listed, the second under the first
+  let call = lines.iter().position(|l| l.ends_with("  two")).unwrap();
+  assert!(lines[call].contains(&format!("{:#08x}  00000000", main + 8)), "{}", lines[call]);
+  assert_eq!(lines[call + 1].trim(), format!("{:#08x}  00000000", main + 12));
+  let data = lines.iter().position(|l| *l == "Data section:").unwrap();
+  let value = symbol(&exe, "value").address;
+  assert_eq!(lines[data + 1], format!("  {value:#08x}      8  value"));
+}
+
+#[test]
+fn map() {
+  let exe = build(&format!("{TEXT}\nfunc other\n  ret\nend"), &bare()).unwrap();
+  let map = blitz::link::map(exe.image.as_ref().unwrap());
+  let lines: Vec<&str> = map.lines().collect();
+  assert!(lines[0].starts_with("address") && lines[0].ends_with("defined at"));
+  let main = symbol(&exe, "main");
+  assert_eq!(lines[1], format!("{:#010x}  {:6}  func   main   main.su:6", main.address, main.size));
+  let value = lines.iter().find(|l| l.contains("value")).unwrap();
+  assert!(value.contains("  8  data ") && value.ends_with("main.su:5"), "{value}");
+  // Unused functions are left out
+  assert!(!map.contains("other"));
+}
+
+// The debug section is laid out as in the spec and read back from the header
+#[test]
+fn debug_section() {
+  let exe = build(TEXT, &bare()).unwrap();
+  let header = Header::read(&exe.bytes).unwrap();
+  let offset = header.debug_offset.unwrap();
+  assert_eq!(u64::from_le_bytes(exe.bytes[24..32].try_into().unwrap()) as usize, offset);
+  let debug = header.debug(&exe.bytes).unwrap();
+  let main = symbol(&exe, "main");
+  assert_eq!(debug.function(main.address), Some("main"));
+  assert_eq!(debug.line(main.address), Some(("main.su", 7)));
+  // u32 function count, u64 address, u64 size and then the name
+  let section = &exe.bytes[offset..];
+  let count = u32::from_le_bytes(section[..4].try_into().unwrap()) as usize;
+  assert_eq!(count, debug.funcs.len());
+  // A name longer than the section is malformed, not read
+  let mut bad = section.to_vec();
+  bad[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+  assert!(Debug::read(&mut bad.as_slice()).is_err());
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use blitz::compiler::assemble::Executable;
+use common::*;
+
+fn optimized(text: &str) -> Executable {
+  let mut options = options();
+  options.optimize = true;
+  build(text, &options).unwrap()
+}
+
+#[test]
+fn rewrites() {
+  let exe = optimized("func main
+  nop
+  mov r1, r1
+  add r2, r2, 1
+  add r2, 1, r2
+  sub r3, r3, 1
+  push r4
+  pop r4
+  ret
+end");
+  let report = exe.optimized.as_ref().unwrap();
+  assert_eq!((report.nops, report.moves, report.increments, report.pairs), (1, 1, 3, 1));
+  // inc r2, inc r2, dec r3 and ret
+  assert_eq!(code(&exe, "main").len(), 4 * 4);
+}
+
+#[test]
+fn kept() {
+  let exe = optimized("func main
+  mov r1, r2
+  add r2, r3, 1
+  sub r3, r3, 2
+  push r4
+1:
+  pop r4
+  jmp 1b
+end");
+  let report = exe.optimized.as_ref().unwrap();
+  assert!(report.lines().is_empty());
+}
+
+#[test]
+fn jumps() {
+  let text = "func main
+  jmp first
+first:
+  jmp second
+second:
+  jmp 1f
+1:
+  ret
+end";
+  let exe = optimized(text);
+  let report = exe.optimized.as_ref().unwrap();
+  assert_eq!(report.jumps, 2);
+  assert_eq!(report.lines(), ["jumps to jumps shortened: 2"]);
+}
+
+// -O never changes what the program does
+#[test]
+fn same_output() {
+  let text = "include \"lib.su\"
+func main
+  mov r2, 64
+  jmp 2f
+1:
+  jmp 3f
+2:
+  add r2, r2, 1
+  push r2
+  pop r2
+  nop
+  jmp 1b
+3:
+  putc r2
+  ret
+end";
+  let plain = build(text, &options()).unwrap();
+  let exe = optimized(text);
+  assert_eq!(run(&plain.bytes), "A");
+  assert_eq!(run(&exe.bytes), "A");
+  assert!(exe.bytes.len() < plain.bytes.len());
+}
+
+// Jumps to a numeric label share one generated label, which stays out of
+// the symbols
+#[test]
+fn generated_labels() {
+  let exe = optimized("func main
+  jmp 1f
+  jmp 1f
+1:
+  jmp 2f
+2:
+  ret
+end");
+  assert_eq!(exe.optimized.as_ref().unwrap().jumps, 2);
+  // Both jumps are encoded the same, to the ret
+  let code = code(&exe, "main");
+  let size = (code.len() - 4) / 3;
+  assert_eq!(code[..size], code[size..2 * size]);
+  let symbols = &exe.image.as_ref().unwrap().symbols;
+  assert!(symbols.iter().all(|s| !s.name.contains('@')));
+}